@@ -0,0 +1 @@
+ALTER TABLE photos DROP COLUMN xmp_mtime;
@@ -0,0 +1,3 @@
+-- When the xmp data of a photo was last read, as the newest
+-- modification time of the image and its sidecar.
+ALTER TABLE photos ADD COLUMN xmp_mtime TIMESTAMPTZ;
@@ -0,0 +1 @@
+ALTER TABLE positions ADD COLUMN inferred BOOLEAN NOT NULL DEFAULT false;
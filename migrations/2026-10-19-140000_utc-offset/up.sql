@@ -0,0 +1,2 @@
+-- Utc offset in minutes of the local photo date, when known.
+ALTER TABLE photos ADD COLUMN utc_offset SMALLINT;
@@ -0,0 +1,6 @@
+-- The instant the photo was taken, when the utc offset of the local
+-- date is known.
+ALTER TABLE photos ADD COLUMN date_utc TIMESTAMPTZ
+ GENERATED ALWAYS AS (timezone(make_interval(mins => utc_offset), date))
+ STORED;
+CREATE INDEX photos_date_utc_idx ON photos (date_utc);
@@ -0,0 +1,2 @@
+-- Seconds the date is shifted from the exif date, to correct camera clocks.
+ALTER TABLE photos ADD COLUMN date_shift INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- How exact the date is: 0 exact, 1 day, 2 month, 3 year, 4 decade.
+ALTER TABLE photos ADD COLUMN date_precision SMALLINT NOT NULL DEFAULT 0;
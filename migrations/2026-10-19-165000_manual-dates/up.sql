@@ -0,0 +1,3 @@
+-- Where the date came from, 'manual' if set by an admin.
+ALTER TABLE photos ADD COLUMN date_source VARCHAR;
+UPDATE photos SET date_source = 'manual' WHERE date_precision > 0;
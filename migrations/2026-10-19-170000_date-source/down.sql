@@ -0,0 +1 @@
+UPDATE photos SET date_source = NULL WHERE date_source <> 'manual';
@@ -0,0 +1,3 @@
+-- Dates set before sources were tracked came from exif.
+UPDATE photos SET date_source = 'exif'
+ WHERE date IS NOT NULL AND date_source IS NULL;
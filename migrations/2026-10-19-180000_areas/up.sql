@@ -0,0 +1,13 @@
+-- Areas imported from an OpenStreetMap extract, for finding places
+-- without the overpass API.
+CREATE TABLE areas (
+  osm_id BIGINT PRIMARY KEY,
+  tags JSONB NOT NULL,
+  min_lat INTEGER NOT NULL,
+  max_lat INTEGER NOT NULL,
+  min_lng INTEGER NOT NULL,
+  max_lng INTEGER NOT NULL,
+  geometry JSONB NOT NULL
+);
+
+CREATE INDEX areas_bbox ON areas (min_lat, max_lat, min_lng, max_lng);
@@ -0,0 +1,9 @@
+-- Photos waiting for places to be fetched, with retry state.
+CREATE TABLE place_queue (
+  photo_id INTEGER PRIMARY KEY REFERENCES photos (id) ON DELETE CASCADE,
+  attempts SMALLINT NOT NULL DEFAULT 0,
+  next_try TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_error VARCHAR
+);
+
+CREATE INDEX place_queue_next_try ON place_queue (next_try);
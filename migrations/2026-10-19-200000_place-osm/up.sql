@@ -0,0 +1,6 @@
+-- The osm tags of places, for reclassifying them.
+CREATE TABLE place_osm (
+  place_id INTEGER PRIMARY KEY REFERENCES places (id) ON DELETE CASCADE,
+  country VARCHAR,
+  tags JSONB NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Geofences of custom places, that osm does not know about.
+-- The geometry is a GeoJSON Point (with a radius in meters) or
+-- MultiPolygon.  Coordinates of the bounding box are in microdegrees.
+CREATE TABLE place_fences (
+  place_id INTEGER PRIMARY KEY REFERENCES places (id) ON DELETE CASCADE,
+  geometry JSONB NOT NULL,
+  radius DOUBLE PRECISION,
+  min_lat INTEGER NOT NULL,
+  max_lat INTEGER NOT NULL,
+  min_lng INTEGER NOT NULL,
+  max_lng INTEGER NOT NULL
+);
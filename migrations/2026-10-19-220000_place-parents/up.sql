@@ -0,0 +1,3 @@
+-- The smallest place that contains this place, if known.
+ALTER TABLE places
+  ADD COLUMN parent_id INTEGER REFERENCES places (id) ON DELETE SET NULL;
@@ -0,0 +1,4 @@
+-- Index for searching positions inside a box, used as
+-- point(longitude, latitude) <@ box(...)
+CREATE INDEX positions_point_idx ON positions
+  USING gist (point(longitude, latitude));
@@ -0,0 +1,4 @@
+ALTER TABLE positions
+  DROP COLUMN altitude,
+  DROP COLUMN accuracy,
+  DROP COLUMN direction;
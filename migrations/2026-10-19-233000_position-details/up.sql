@@ -0,0 +1,5 @@
+-- Details of gps positions, from exif.
+ALTER TABLE positions
+  ADD COLUMN altitude REAL,
+  ADD COLUMN accuracy REAL,
+  ADD COLUMN direction REAL;
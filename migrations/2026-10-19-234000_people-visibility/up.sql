@@ -0,0 +1,3 @@
+-- Who may see a person: 0 public, 1 anonymous, 2 hidden.
+ALTER TABLE people
+  ADD COLUMN visibility SMALLINT NOT NULL DEFAULT 0;
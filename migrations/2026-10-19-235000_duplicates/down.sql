@@ -0,0 +1,2 @@
+ALTER TABLE photos DROP COLUMN duplicate_of;
+DROP TABLE photo_hashes;
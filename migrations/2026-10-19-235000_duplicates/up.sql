@@ -0,0 +1,10 @@
+-- A perceptual hash of each photo, for finding duplicates.
+CREATE TABLE photo_hashes (
+  photo_id INTEGER PRIMARY KEY REFERENCES photos (id) ON DELETE CASCADE,
+  phash BIGINT NOT NULL
+);
+
+-- The primary photo of a photo that is a hidden duplicate.
+ALTER TABLE photos
+  ADD COLUMN duplicate_of INTEGER REFERENCES photos (id) ON DELETE SET NULL;
+CREATE INDEX photos_duplicate_of_idx ON photos (duplicate_of);
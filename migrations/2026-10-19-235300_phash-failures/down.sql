@@ -0,0 +1,2 @@
+DELETE FROM photo_hashes WHERE phash IS NULL;
+ALTER TABLE photo_hashes ALTER COLUMN phash SET NOT NULL;
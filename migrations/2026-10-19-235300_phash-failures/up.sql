@@ -0,0 +1,3 @@
+-- A NULL hash marks a photo that could not be hashed, so it is not
+-- tried again by every findphotos run.
+ALTER TABLE photo_hashes ALTER COLUMN phash DROP NOT NULL;
@@ -0,0 +1,2 @@
+DROP TABLE photo_unstacked;
+DROP TABLE photo_stacks;
@@ -0,0 +1,12 @@
+-- Stacks of similar photos taken close in time, shown as one photo.
+CREATE TABLE photo_stacks (
+  photo_id INTEGER PRIMARY KEY REFERENCES photos (id) ON DELETE CASCADE,
+  top_id INTEGER NOT NULL REFERENCES photos (id) ON DELETE CASCADE,
+  manual BOOLEAN NOT NULL DEFAULT false
+);
+CREATE INDEX photo_stacks_top_idx ON photo_stacks (top_id);
+
+-- Photos taken out of their stack, that are not stacked again.
+CREATE TABLE photo_unstacked (
+  photo_id INTEGER PRIMARY KEY REFERENCES photos (id) ON DELETE CASCADE
+);
@@ -53,6 +53,20 @@
             b.disabled = (b === one);
         })
     }
+    // Remove form f, re-enabling the button of the event creating it.
+    function close_form(f, event) {
+        f.remove();
+        event.target.disabled = false;
+        event.target.focus();
+    }
+    function add_close(f, event) {
+        let c = d.createElement("button");
+        c.innerHTML = "&#x1f5d9;";
+        c.className = 'close';
+        c.title = 'close';
+        c.onclick = e => close_form(f, event);
+        f.appendChild(c);
+    }
     function tag_form(event, category) {
         disable_one(event.target);
         var f = makeform(category);
@@ -108,9 +122,7 @@
                         i.value = '';
                         i.focus();
                     } else { // close form
-                        e.target.closest('form').remove();
-                        event.target.disabled = false;
-                        event.target.focus();
+                        close_form(f, event);
                     }
                     e.preventDefault();
                     e.stopPropagation();
@@ -141,16 +153,7 @@
         s.innerHTML = "Ok";
         s.type = "submit";
         f.appendChild(s);
-        let c = d.createElement("button");
-        c.innerHTML = "&#x1f5d9;";
-        c.className = 'close';
-        c.title = 'close';
-        c.onclick = e => {
-            e.target.closest('form').remove();
-            event.target.disabled = false; // The old event creating this form
-            event.target.focus();
-        };
-        f.appendChild(c);
+        add_close(f, event);
         p.append(f);
         i.focus();
     }
@@ -175,22 +178,11 @@
         s.innerHTML = "Ok";
         s.type = "submit";
         f.appendChild(s);
-        let c = d.createElement("button");
-        c.innerHTML = "&#x1f5d9;";
-        c.className = 'close';
-        c.title = 'close';
-        c.onclick = e => {
-            e.target.closest('form').remove();
-            event.target.disabled = false; // The old event creating this form
-            event.target.focus();
-        };
-        f.appendChild(c);
+        add_close(f, event);
         f.addEventListener('keydown', e => {
             switch(e.code) {
             case 'Escape':
-                e.target.closest('form').remove();
-                event.target.disabled = false;
-                event.target.focus();
+                close_form(f, event);
                 break;
             case 'Enter':
                 f.submit();
@@ -206,6 +198,39 @@
         i.focus();
     }
 
+    function interpolate_form(event) {
+        disable_one(event.target);
+        var f = makeform("interpolate");
+        var l = d.createElement("label");
+        l.innerHTML = "Max minutes from other photos";
+        f.appendChild(l);
+        var i = d.createElement("input");
+        i.type = "number";
+        i.name = "minutes";
+        i.id = "interpolateminutes";
+        i.value = 10;
+        i.min = 1;
+        i.max = 1440;
+        l.htmlFor = i.id;
+        f.appendChild(i);
+        let s = d.createElement("button");
+        s.innerHTML = "Ok";
+        s.type = "submit";
+        f.appendChild(s);
+        add_close(f, event);
+        f.addEventListener('keydown', e => {
+            if (e.code == 'Escape') {
+                close_form(f, event);
+                e.preventDefault();
+                e.stopPropagation();
+                return false;
+            }
+            return true;
+        });
+        p.append(f);
+        i.focus();
+    }
+
     function location_form(event) {
         disable_one(event.target);
         var position = details.dataset.position || localStorage.getItem('lastpos');
@@ -233,8 +258,8 @@
         var marker;
         slink.onload = () => {
             var map = L.map('amap');
-            L.tileLayer('//{s}.tile.openstreetmap.org/{z}/{x}/{y}.png', {
-		attribution: '© <a href="http://osm.org/copyright">OpenStreetMap</a> contributors',
+            L.tileLayer(d.body.dataset.tiles, {
+		attribution: d.body.dataset.tileAttribution,
 		maxZoom: 19
             }).addTo(map);
             if (position) {
@@ -270,9 +295,7 @@
             console.log("In keyhandler", e);
             switch(e.code) {
             case 'Escape':
-                e.target.closest('form').remove();
-                event.target.disabled = false;
-                event.target.focus();
+                close_form(f, event);
                 break;
             case 'Enter':
                 presubmit();
@@ -286,16 +309,7 @@
             return false;
         }
 
-        let c = d.createElement("button");
-        c.innerHTML = "&#x1f5d9;";
-        c.className = 'close';
-        c.title = 'close';
-        c.onclick = e => {
-            e.target.closest('form').remove();
-            event.target.disabled = false; // The old event creating this form
-            event.target.focus();
-        };
-        f.appendChild(c);
+        add_close(f, event);
         f.addEventListener('keydown', keyHandler);
         p.append(f);
     }
@@ -340,6 +354,15 @@
     r.accessKey = "l";
     p.appendChild(r);
 
+    if (!details.dataset.position) {
+        p.appendChild(d.createTextNode(" "));
+        r = d.createElement("button");
+        r.onclick = e => interpolate_form(e);
+        r.innerHTML = "\u{1f9ed}";
+        r.title = "Interpolate location from photos near in time";
+        p.appendChild(r);
+    }
+
     p.appendChild(d.createTextNode(" "));
     r = d.createElement("button");
     r.onclick = e => grade_form(e);
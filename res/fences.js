@@ -0,0 +1,62 @@
+// Drawing geofences for custom places in rphotos
+(function (d) {
+    let f = d.querySelector('form.fence');
+    if (!f) {
+        return;
+    }
+    let me = d.getElementById('fmap');
+    let map = L.map(me);
+    L.tileLayer(d.body.dataset.tiles, {
+        attribution: d.body.dataset.tileAttribution,
+        maxZoom: 19
+    }).addTo(map);
+
+    let known = L.featureGroup().addTo(map);
+    JSON.parse(me.dataset.fences).forEach(x => {
+        let c = x.geometry.coordinates;
+        let shape = x.radius ?
+            L.circle([c[1], c[0]], {radius: x.radius}) :
+            L.geoJSON(x.geometry);
+        let a = d.createElement('a');
+        a.href = '/place/' + x.slug;
+        a.innerText = x.name;
+        shape.bindPopup(a);
+        known.addLayer(shape);
+    });
+    let position = localStorage.getItem('lastpos');
+    if (known.getLayers().length) {
+        map.fitBounds(known.getBounds());
+    } else if (position) {
+        map.setView(JSON.parse(position), 14);
+    } else {
+        map.fitWorld();
+    }
+
+    let points = [], shape;
+    let radius = f.querySelector('[name=radius]');
+    function redraw() {
+        if (shape) {
+            shape.remove();
+            shape = null;
+        }
+        let r = parseFloat(radius.value);
+        if (points.length == 1 && r > 0) {
+            shape = L.circle(points[0], {radius: r, color: 'red'});
+        } else if (points.length > 0) {
+            shape = L.polygon(points, {color: 'red'});
+        }
+        if (shape) {
+            shape.addTo(map);
+        }
+        f.querySelector('[name=points]').value = JSON.stringify(points);
+    }
+    map.on('click', e => {
+        points.push([e.latlng.lat, e.latlng.lng]);
+        redraw();
+    });
+    radius.addEventListener('input', redraw);
+    f.querySelector('button.clear').addEventListener('click', e => {
+        points = [];
+        redraw();
+    });
+})(document)
@@ -0,0 +1,74 @@
+// Full-screen map of photos in rphotos, with clusters from the server
+(function (d) {
+    let me = d.getElementById('bigmap');
+    if (!me) {
+        return;
+    }
+    let map = L.map(me);
+    L.tileLayer(d.body.dataset.tiles, {
+        attribution: d.body.dataset.tileAttribution,
+        maxZoom: 19
+    }).addTo(map);
+    let markers = L.layerGroup().addTo(map);
+    let url = me.dataset.markers;
+    url += url.includes('?') ? '&' : '?';
+    let pending;
+
+    function marker(c) {
+        if (c.count == 1) {
+            let m = L.marker([c.lat, c.lng], {title: c.photo});
+            m.bindPopup(`<a href="/img/${c.photo}"><img src="/img/${c.photo}-s.jpg"></a>`);
+            return m;
+        }
+        let size = 30 + 6 * Math.floor(Math.log10(c.count));
+        let m = L.marker([c.lat, c.lng], {
+            icon: L.divIcon({
+                className: 'cluster',
+                html: `<span>${c.count}</span>`,
+                iconSize: [size, size]
+            }),
+            title: c.count + ' photos'
+        });
+        m.on('click', () => map.setView([c.lat, c.lng], map.getZoom() + 2));
+        return m;
+    }
+    function load() {
+        if (pending) {
+            pending.abort();
+        }
+        let r = pending = new XMLHttpRequest();
+        r.onload = function() {
+            if (this.status != 200) {
+                return;
+            }
+            markers.clearLayers();
+            JSON.parse(this.responseText).forEach(c => markers.addLayer(marker(c)));
+        };
+        r.open('GET', url + 'z=' + map.getZoom() +
+               '&view=' + map.getBounds().toBBoxString());
+        r.send(null);
+        let c = map.getCenter();
+        localStorage.setItem('mapview', JSON.stringify([c.lat, c.lng, map.getZoom()]));
+    }
+    let search = me.dataset.search;
+    search += search.includes('?') ? '&' : '?';
+    let area = L.control({position: 'topright'});
+    area.onAdd = () => {
+        let a = d.createElement('a');
+        a.className = 'mapsearch';
+        a.innerText = 'Search in this area';
+        a.addEventListener('click', e => {
+            a.href = search + 'bbox=' + map.getBounds().toBBoxString();
+        });
+        return a;
+    };
+    area.addTo(map);
+    map.on('moveend', load);
+    let view = localStorage.getItem('mapview');
+    if (view) {
+        let v = JSON.parse(view);
+        map.setView([v[0], v[1]], v[2]);
+    } else {
+        map.fitWorld();
+    }
+})(document)
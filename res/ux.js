@@ -30,6 +30,13 @@
     }
   }
   window.addEventListener('resize', resize_map);
+  d.querySelectorAll('.group button.stack').forEach(b => {
+    b.addEventListener('click', e => {
+      let open = b.classList.toggle('open');
+      d.querySelectorAll('.item[data-stack="' + b.dataset.stack + '"]')
+	.forEach(i => i.hidden = !open);
+    });
+  });
   let i = d.querySelector('.details .item');
   if (i) {
     i.addEventListener('click', e => {
@@ -59,8 +66,8 @@
     slink.async = 'async';
     slink.onload = () => {
       map = L.map('map', {'scrollWheelZoom': false, 'trackResize': false});
-      L.tileLayer('//{s}.tile.openstreetmap.org/{z}/{x}/{y}.png', {
-	attribution: '© <a href="http://osm.org/copyright">OpenStreetMap</a> contributors'
+      L.tileLayer(d.body.dataset.tiles, {
+	attribution: d.body.dataset.tileAttribution
       }).addTo(map);
       resize_map();
       cb(map);
@@ -74,6 +81,21 @@
       let p = JSON.parse(pos);
       map.setView(p, 16);
       L.marker(p).addTo(map);
+      let acc = details.dataset.accuracy;
+      if (acc) {
+	L.circle(p, {radius: +acc, weight: 1}).addTo(map);
+      }
+      let dir = details.dataset.direction;
+      if (dir) {
+	// A cone of 60 degrees in the direction the camera was pointing.
+	let r = 0.0006, c = Math.cos(p[0] * Math.PI / 180);
+	let cone = [p];
+	for (let a = +dir - 30; a <= +dir + 30; a += 10) {
+	  let rad = a * Math.PI / 180;
+	  cone.push([p[0] + r * Math.cos(rad), p[1] + r * Math.sin(rad) / c]);
+	}
+	L.polygon(cone, {weight: 1, fillOpacity: 0.3}).addTo(map);
+      }
     })
   }
   let group = d.querySelector('.group');
@@ -106,6 +128,9 @@
   }
 
   (function(form) {
+    if (!form) {
+      return; // No search in static export
+    }
     function prepareQtag(s) {
       const i = s.querySelector('input');
       i.addEventListener('change', function(e) {
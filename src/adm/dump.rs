@@ -0,0 +1,645 @@
+//! Dump and restore all curated metadata.
+//!
+//! The dump format is JSON lines.  The first line is a header with
+//! the format version, followed by one line per photo, one line per
+//! person that is not public and one line per user.  Photos are
+//! identified by path, so a dump can be restored to a new database
+//! for the same photo directory.
+use super::result::Error;
+use crate::DbOpt;
+use crate::models::{Camera, Person, Photo, PosDetails, Tag, Visibility};
+use crate::schema::attributions::dsl as a;
+use crate::schema::cameras::dsl as c;
+use crate::schema::people::dsl as h;
+use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos::dsl as p;
+use crate::schema::places::dsl as l;
+use crate::schema::positions::dsl as ps;
+use crate::schema::tags::dsl as t;
+use crate::schema::users::dsl as u;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::{insert_into, update};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+const FORMAT: &str = "rphotos-dump";
+/// The dump format version.
+///
+/// Increased when data is added to the format, so an older rphotos
+/// refuses a dump rather than restoring only part of it.  Version 2
+/// added position details, person visibility and duplicates.
+const VERSION: u32 = 2;
+
+#[derive(clap::Parser)]
+pub struct Dump {
+    #[clap(flatten)]
+    db: DbOpt,
+    /// File to write the dump to.  Default is stdout.
+    file: Option<PathBuf>,
+}
+
+#[derive(clap::Parser)]
+pub struct Restore {
+    #[clap(flatten)]
+    db: DbOpt,
+    /// File to read the dump from.  Default is stdin.
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Photo(Box<PhotoRecord>),
+    Person(PersonRecord),
+    User(UserRecord),
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct PhotoRecord {
+    path: String,
+    date: Option<NaiveDateTime>,
+    grade: Option<i16>,
+    rotation: i16,
+    is_public: bool,
+    width: i32,
+    height: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// Utc offset of date, in minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    utc_offset: Option<i16>,
+    /// Correction of the camera clock, in seconds.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    date_shift: i32,
+    /// How exact the date is, 0 is exact, 4 is decade.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    date_precision: i16,
+    /// Where the date came from: exif, path or manual.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    date_source: Option<String>,
+    /// Path of the primary photo, if this is a hidden duplicate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    camera: Option<CameraRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attribution: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<PositionRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    people: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    places: Vec<PlaceRecord>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct CameraRecord {
+    make: String,
+    model: String,
+}
+
+/// A position in microdegrees, as stored in the database.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct PositionRecord {
+    lat: i32,
+    lng: i32,
+    /// True if the position is interpolated from other photos.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    inferred: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    altitude: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accuracy: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    direction: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct PlaceRecord {
+    slug: String,
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    osm_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    osm_level: Option<i16>,
+}
+
+/// The visibility of a person, who is also named in photo records.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct PersonRecord {
+    name: String,
+    /// Public, anonymous or hidden.
+    visibility: String,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct UserRecord {
+    username: String,
+    /// The password hash.
+    password: String,
+}
+
+impl Dump {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let out: Box<dyn Write> = match &self.file {
+            Some(file) => Box::new(File::create(file)?),
+            None => Box::new(io::stdout().lock()),
+        };
+        let mut out = BufWriter::new(out);
+        write_line(
+            &mut out,
+            &Header {
+                format: FORMAT.into(),
+                version: VERSION,
+            },
+        )?;
+        let n = dump_photos(&mut db, &mut out).await?;
+        let people = h::people
+            .select((h::person_name, h::visibility))
+            .filter(h::visibility.ne(Visibility::Public.as_i16()))
+            .order(h::person_name)
+            .load::<(String, i16)>(&mut db)
+            .await?;
+        for (name, visibility) in people {
+            let person = PersonRecord {
+                name,
+                visibility: Visibility::from_i16(visibility).name().into(),
+            };
+            write_line(&mut out, &Record::Person(person))?;
+        }
+        let users = u::users
+            .select((u::username, u::password))
+            .order(u::username)
+            .load::<(String, String)>(&mut db)
+            .await?;
+        for (username, password) in &users {
+            let user = UserRecord {
+                username: username.clone(),
+                password: password.clone(),
+            };
+            write_line(&mut out, &Record::User(user))?;
+        }
+        out.flush()?;
+        info!("Dumped {} photos and {} users.", n, users.len());
+        Ok(())
+    }
+}
+
+async fn dump_photos(
+    db: &mut AsyncPgConnection,
+    out: &mut impl Write,
+) -> Result<usize, Error> {
+    let cameras = c::cameras
+        .load::<Camera>(db)
+        .await?
+        .into_iter()
+        .map(|c| (c.id, c))
+        .collect::<HashMap<_, _>>();
+    let attributions = a::attributions
+        .select((a::id, a::name))
+        .load::<(i32, String)>(db)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let positions = ps::positions
+        .select((
+            ps::photo_id,
+            ps::latitude,
+            ps::longitude,
+            ps::inferred,
+            PosDetails::as_select(),
+        ))
+        .load::<(i32, i32, i32, bool, PosDetails)>(db)
+        .await?
+        .into_iter()
+        .map(|(id, lat, lng, inferred, details)| {
+            let PosDetails {
+                altitude,
+                accuracy,
+                direction,
+            } = details;
+            let pos = PositionRecord {
+                lat,
+                lng,
+                inferred,
+                altitude,
+                accuracy,
+                direction,
+            };
+            (id, pos)
+        })
+        .collect::<HashMap<_, _>>();
+    let tags = grouped(
+        pt::photo_tags
+            .inner_join(t::tags)
+            .select((pt::photo_id, t::tag_name))
+            .order(t::tag_name)
+            .load::<(i32, String)>(db)
+            .await?,
+    );
+    let people = grouped(
+        pp::photo_people
+            .inner_join(h::people)
+            .select((pp::photo_id, h::person_name))
+            .order(h::person_name)
+            .load::<(i32, String)>(db)
+            .await?,
+    );
+    let places = grouped(
+        pl::photo_places
+            .inner_join(l::places)
+            .select((
+                pl::photo_id,
+                (l::slug, l::place_name, l::osm_id, l::osm_level),
+            ))
+            .order(l::osm_level.desc().nulls_first())
+            .load::<(i32, (String, String, Option<i64>, Option<i16>))>(db)
+            .await?,
+    );
+    let photos = p::photos.order(p::path).load::<Photo>(db).await?;
+    let paths = photos
+        .iter()
+        .map(|photo| (photo.id, &photo.path))
+        .collect::<HashMap<_, _>>();
+    for photo in &photos {
+        let record = PhotoRecord {
+            path: photo.path.clone(),
+            date: photo.date,
+            grade: photo.grade,
+            rotation: photo.rotation,
+            is_public: photo.is_public,
+            width: photo.width,
+            height: photo.height,
+            description: photo.description.clone(),
+            utc_offset: photo.utc_offset,
+            date_shift: photo.date_shift,
+            date_precision: photo.date_precision,
+            date_source: photo.date_source.clone(),
+            duplicate_of: photo
+                .duplicate_of
+                .and_then(|id| paths.get(&id))
+                .map(|path| path.to_string()),
+            camera: photo.camera_id.and_then(|id| cameras.get(&id)).map(|c| {
+                CameraRecord {
+                    make: c.manufacturer.clone(),
+                    model: c.model.clone(),
+                }
+            }),
+            attribution: photo
+                .attribution_id
+                .and_then(|id| attributions.get(&id))
+                .cloned(),
+            position: positions.get(&photo.id).cloned(),
+            tags: tags.get(&photo.id).cloned().unwrap_or_default(),
+            people: people.get(&photo.id).cloned().unwrap_or_default(),
+            places: places
+                .get(&photo.id)
+                .into_iter()
+                .flatten()
+                .map(|(slug, name, osm_id, osm_level)| PlaceRecord {
+                    slug: slug.clone(),
+                    name: name.clone(),
+                    osm_id: *osm_id,
+                    osm_level: *osm_level,
+                })
+                .collect(),
+        };
+        write_line(out, &Record::Photo(Box::new(record)))?;
+    }
+    Ok(photos.len())
+}
+
+fn grouped<T>(rows: Vec<(i32, T)>) -> HashMap<i32, Vec<T>> {
+    let mut result = HashMap::<_, Vec<_>>::new();
+    for (id, value) in rows {
+        result.entry(id).or_default().push(value);
+    }
+    result
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn write_line(out: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
+
+impl Restore {
+    /// Restore a dump.
+    ///
+    /// Photos and users that exist are updated, others are created.
+    /// Tags, people and places are added to photos, but existing
+    /// ones are not removed, so restoring the same dump again
+    /// changes nothing.  The whole dump is read before anything is
+    /// restored, and it is restored in one transaction, so a bad
+    /// dump leaves the database unchanged.
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let input: Box<dyn BufRead> = match &self.file {
+            Some(file) => Box::new(BufReader::new(File::open(file)?)),
+            None => Box::new(io::stdin().lock()),
+        };
+        let mut lines = input.lines().enumerate();
+        let header = match lines.next() {
+            Some((_, line)) => serde_json::from_str::<Header>(&line?)
+                .map_err(|e| Error::Other(format!("Bad dump header: {e}")))?,
+            None => return Err(Error::Other("Empty dump".into())),
+        };
+        if header.format != FORMAT || header.version > VERSION {
+            return Err(Error::Other(format!(
+                "Unsupported dump format {:?} version {}",
+                header.format, header.version,
+            )));
+        }
+        let mut records = Vec::new();
+        for (n, line) in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str::<Record>(&line).map_err(
+                |e| Error::Other(format!("Bad record on line {}: {e}", n + 1)),
+            )?);
+        }
+        let (n_photos, n_users) = db
+            .transaction(|db| {
+                async move { restore_all(db, records).await }.scope_boxed()
+            })
+            .await?;
+        info!("Restored {} photos and {} users.", n_photos, n_users);
+        Ok(())
+    }
+}
+
+/// Restore all records, returning the number of photos and users.
+async fn restore_all(
+    db: &mut AsyncPgConnection,
+    records: Vec<Record>,
+) -> Result<(usize, usize), Error> {
+    let (mut n_photos, mut n_users) = (0, 0);
+    let mut duplicates = Vec::new();
+    for record in records {
+        match record {
+            Record::Photo(mut photo) => {
+                if let Some(primary) = photo.duplicate_of.take() {
+                    duplicates.push((photo.path.clone(), primary));
+                }
+                restore_photo(db, *photo).await?;
+                n_photos += 1;
+            }
+            Record::Person(person) => {
+                restore_person(db, person).await?;
+            }
+            Record::User(user) => {
+                restore_user(db, user).await?;
+                n_users += 1;
+            }
+        }
+    }
+    // The primary photo may come after its duplicates in the dump.
+    for (path, primary) in &duplicates {
+        restore_duplicate(db, path, primary).await?;
+    }
+    Ok((n_photos, n_users))
+}
+
+async fn restore_duplicate(
+    db: &mut AsyncPgConnection,
+    path: &str,
+    primary: &str,
+) -> Result<(), Error> {
+    let primary_id = p::photos
+        .select(p::id)
+        .filter(p::path.eq(primary))
+        .first::<i32>(db)
+        .await
+        .optional()?;
+    if let Some(primary_id) = primary_id {
+        update(p::photos.filter(p::path.eq(path)))
+            .set(p::duplicate_of.eq(primary_id))
+            .execute(db)
+            .await?;
+    } else {
+        warn!("Primary {} of duplicate {} not found", primary, path);
+    }
+    Ok(())
+}
+
+async fn restore_photo(
+    db: &mut AsyncPgConnection,
+    record: PhotoRecord,
+) -> Result<(), Error> {
+    let camera_id = match &record.camera {
+        Some(cam) => {
+            Some(Camera::get_or_create(db, &cam.make, &cam.model).await?.id)
+        }
+        None => None,
+    };
+    let attribution_id = match &record.attribution {
+        Some(name) => Some(get_or_create_attribution(db, name).await?),
+        None => None,
+    };
+    let values = (
+        p::date.eq(record.date),
+        p::grade.eq(record.grade),
+        p::rotation.eq(record.rotation),
+        p::is_public.eq(record.is_public),
+        p::width.eq(record.width),
+        p::height.eq(record.height),
+        p::description.eq(&record.description),
+        p::utc_offset.eq(record.utc_offset),
+        p::date_shift.eq(record.date_shift),
+        p::date_precision.eq(record.date_precision),
+        p::date_source.eq(&record.date_source),
+        p::camera_id.eq(camera_id),
+        p::attribution_id.eq(attribution_id),
+    );
+    let photo_id = insert_into(p::photos)
+        .values((p::path.eq(&record.path), values))
+        .on_conflict(p::path)
+        .do_update()
+        .set(values)
+        .returning(p::id)
+        .get_result::<i32>(db)
+        .await?;
+    debug!("Restoring #{} {}", photo_id, record.path);
+
+    if let Some(pos) = record.position {
+        let values = (
+            ps::latitude.eq(pos.lat),
+            ps::longitude.eq(pos.lng),
+            ps::inferred.eq(pos.inferred),
+            ps::altitude.eq(pos.altitude),
+            ps::accuracy.eq(pos.accuracy),
+            ps::direction.eq(pos.direction),
+        );
+        insert_into(ps::positions)
+            .values((ps::photo_id.eq(photo_id), values))
+            .on_conflict(ps::photo_id)
+            .do_update()
+            .set(values)
+            .execute(db)
+            .await?;
+    }
+    for name in &record.tags {
+        let tag = Tag::get_or_create_name(db, name).await?;
+        insert_into(pt::photo_tags)
+            .values((pt::photo_id.eq(photo_id), pt::tag_id.eq(tag.id)))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await?;
+    }
+    for name in &record.people {
+        let person = Person::get_or_create_name(db, name).await?;
+        insert_into(pp::photo_people)
+            .values((pp::photo_id.eq(photo_id), pp::person_id.eq(person.id)))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await?;
+    }
+    for place in &record.places {
+        let place_id = get_or_create_place(db, place).await?;
+        insert_into(pl::photo_places)
+            .values((pl::photo_id.eq(photo_id), pl::place_id.eq(place_id)))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn get_or_create_attribution(
+    db: &mut AsyncPgConnection,
+    name: &str,
+) -> Result<i32, Error> {
+    if let Some(id) = a::attributions
+        .select(a::id)
+        .filter(a::name.eq(name))
+        .first(db)
+        .await
+        .optional()?
+    {
+        Ok(id)
+    } else {
+        Ok(insert_into(a::attributions)
+            .values(a::name.eq(name))
+            .returning(a::id)
+            .get_result(db)
+            .await?)
+    }
+}
+
+/// Find a place by osm id (if any) or slug, or create it.
+async fn get_or_create_place(
+    db: &mut AsyncPgConnection,
+    place: &PlaceRecord,
+) -> Result<i32, Error> {
+    let existing = match place.osm_id {
+        Some(osm_id) => l::places
+            .select(l::id)
+            .filter(l::osm_id.eq(osm_id))
+            .first(db)
+            .await
+            .optional()?,
+        None => None,
+    };
+    let existing = match existing {
+        Some(id) => Some(id),
+        None => l::places
+            .select(l::id)
+            .filter(l::slug.eq(&place.slug))
+            .first(db)
+            .await
+            .optional()?,
+    };
+    if let Some(id) = existing {
+        Ok(id)
+    } else {
+        Ok(insert_into(l::places)
+            .values((
+                l::slug.eq(&place.slug),
+                l::place_name.eq(&place.name),
+                l::osm_id.eq(place.osm_id),
+                l::osm_level.eq(place.osm_level),
+            ))
+            .returning(l::id)
+            .get_result(db)
+            .await?)
+    }
+}
+
+async fn restore_person(
+    db: &mut AsyncPgConnection,
+    record: PersonRecord,
+) -> Result<(), Error> {
+    let visibility = record
+        .visibility
+        .parse::<Visibility>()
+        .map_err(|e| Error::Other(format!("{e} for {}", record.name)))?;
+    let person = Person::get_or_create_name(db, &record.name).await?;
+    diesel::update(h::people.find(person.id))
+        .set(h::visibility.eq(visibility.as_i16()))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+async fn restore_user(
+    db: &mut AsyncPgConnection,
+    user: UserRecord,
+) -> Result<(), Error> {
+    insert_into(u::users)
+        .values((
+            u::username.eq(&user.username),
+            u::password.eq(&user.password),
+        ))
+        .on_conflict(u::username)
+        .do_update()
+        .set(u::password.eq(&user.password))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[test]
+fn photo_record_roundtrip() {
+    let json = r#"{"type":"photo","path":"2019/05/IMG_17.jpg","date":"2019-05-03T14:17:03","grade":60,"rotation":90,"is_public":true,"width":4000,"height":3000,"duplicate_of":"2019/05/IMG_16.jpg","camera":{"make":"Canon","model":"EOS 5D"},"position":{"lat":59318500,"lng":18062300},"tags":["lake"],"places":[{"slug":"stockholm","name":"Stockholm","osm_id":398021,"osm_level":7}]}"#;
+    let record = serde_json::from_str::<Record>(json).unwrap();
+    let Record::Photo(ref photo) = record else {
+        panic!("Expected a photo, got {record:?}");
+    };
+    assert_eq!(photo.people, Vec::<String>::new());
+    assert_eq!(photo.places[0].osm_level, Some(7));
+    assert_eq!(serde_json::to_string(&record).unwrap(), json);
+}
+
+#[test]
+fn person_record_roundtrip() {
+    let json = r#"{"type":"person","name":"Anna","visibility":"anonymous"}"#;
+    let record = serde_json::from_str::<Record>(json).unwrap();
+    let Record::Person(ref person) = record else {
+        panic!("Expected a person, got {record:?}");
+    };
+    assert_eq!(person.visibility.parse(), Ok(Visibility::Anonymous));
+    assert_eq!(serde_json::to_string(&record).unwrap(), json);
+}
@@ -0,0 +1,74 @@
+//! Export positions of selected photos to other mapping tools.
+use super::result::Error;
+use crate::DbOpt;
+use crate::geoexport::{Format, GeoPhoto, write};
+use crate::models::Photo;
+use crate::server::search::SearchOpt;
+use crate::server::{BaseUrl, PrivacyOpt, PrivacyZones};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(clap::Parser)]
+pub struct ExportPositions {
+    #[clap(flatten)]
+    db: DbOpt,
+
+    #[clap(flatten)]
+    search: SearchOpt,
+    /// Only public photos, with privacy zones applied.
+    #[clap(long)]
+    public: bool,
+    #[clap(flatten)]
+    privacy: PrivacyOpt,
+
+    /// Public base url of the site, for absolute links to the photos.
+    #[clap(long, env = "RPHOTOS_BASE_URL")]
+    base_url: Option<BaseUrl>,
+    /// Format to write.  Decided by the output file name if not given.
+    #[clap(long, value_enum)]
+    format: Option<Format>,
+    /// File to write to, default is standard output (as GeoJSON).
+    #[clap(long, short)]
+    output: Option<PathBuf>,
+}
+
+impl ExportPositions {
+    pub async fn run(&self) -> Result<(), Error> {
+        let format = match (self.format, &self.output) {
+            (Some(format), _) => format,
+            (None, Some(path)) => path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(Format::from_extension)
+                .ok_or_else(|| {
+                    Error::Other(format!("Unknown format of {path:?}"))
+                })?,
+            (None, None) => Format::Geojson,
+        };
+        let mut db = self.db.connect().await?;
+        let zones = if self.public {
+            self.privacy.zones()
+        } else {
+            PrivacyZones::default()
+        };
+        let query = self.search.query(&mut db).await.map_err(|_| {
+            Error::Other("Bad or unknown photo selection".into())
+        })?;
+        let photos = query.filter(Photo::query(!self.public));
+        let url = |path: &str| match &self.base_url {
+            Some(base) => base.join(path),
+            None => path.to_string(),
+        };
+        let photos = GeoPhoto::load(photos, url, &zones, &mut db).await?;
+        info!("Exporting {} positions as {:?}.", photos.len(), format);
+        let mut out: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout().lock()),
+        };
+        write(format, &photos, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,494 @@
+//! Export the public part of the library as a static site.
+use super::result::Error;
+use super::storestatics;
+use crate::models::{
+    Coord, DatePrecision, Person, Photo, PhotoDetails, Place, SizeTag, Tag,
+    Visibility,
+};
+use crate::photosdir::{PhotosDir, get_scaled_jpeg};
+use crate::schema::people::dsl as h;
+use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos::dsl as p;
+use crate::schema::places::dsl as l;
+use crate::schema::positions::dsl as ps;
+use crate::schema::tags::dsl as t;
+use crate::server::{
+    BaseUrl, Context, GlobalContext, Link, PhotoLink, PlaceTree, PrivacyOpt,
+    TileOpt, monthname,
+};
+use crate::templates;
+use crate::{DbOpt, DirOpt};
+use chrono::Datelike;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    db: DbOpt,
+    #[clap(flatten)]
+    photos: DirOpt,
+
+    /// Public base url of the exported site.
+    #[clap(long, env = "RPHOTOS_BASE_URL")]
+    base_url: Option<BaseUrl>,
+    #[clap(flatten)]
+    tiles: TileOpt,
+    #[clap(flatten)]
+    privacy: PrivacyOpt,
+    /// Directory to write the site to.
+    dir: PathBuf,
+}
+
+impl Args {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let global = Arc::new(GlobalContext::for_static_export(
+            self.db.create_pool()?,
+            PhotosDir::new(&self.photos.photos_dir),
+            self.base_url.clone(),
+            self.tiles.tiles(),
+            self.privacy.zones(),
+        ));
+        let zones = global.privacy_zones();
+        let photos = Photo::query(false)
+            .order((p::date.desc().nulls_last(), p::id.desc()))
+            .load::<Photo>(&mut db)
+            .await?;
+        info!("Exporting {} public photos.", photos.len());
+        let positions = Photo::query(false)
+            .inner_join(ps::positions)
+            .select((ps::photo_id, (ps::latitude, ps::longitude)))
+            .load::<(i32, Coord)>(&mut db)
+            .await?
+            .into_iter()
+            .filter_map(|(id, pos)| Some((id, zones.apply(pos)?)))
+            .collect();
+        let site = Site {
+            dir: &self.dir,
+            global,
+            photosdir: PhotosDir::new(&self.photos.photos_dir),
+            positions,
+        };
+        site.date_pages(&photos)?;
+        site.category_pages(&mut db, &photos).await?;
+        site.details_pages(&mut db, &photos).await?;
+        site.images(&photos).await?;
+        storestatics::to_dir(&self.dir.join("static"))?;
+        Ok(())
+    }
+}
+
+struct Site<'a> {
+    dir: &'a Path,
+    global: Arc<GlobalContext>,
+    photosdir: PhotosDir,
+    positions: HashMap<i32, Coord>,
+}
+
+impl Site<'_> {
+    /// Write the page for the site-relative `path`.
+    ///
+    /// Each page is written as an `index.html` in a directory named
+    /// as the path, so any normal static file server can serve it.
+    fn page<F>(&self, path: &str, render: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Vec<u8>, &Context) -> io::Result<()>,
+    {
+        let context = Context::for_static_export(&self.global, path);
+        let mut buf = Vec::new();
+        render(&mut buf, &context)?;
+        let dir = self.dir.join(path.trim_matches('/'));
+        create_dir_all(&dir)?;
+        debug!("Writing {path:?}");
+        write(dir.join("index.html"), buf)?;
+        Ok(())
+    }
+
+    fn coords(&self, photos: &[&Photo]) -> Vec<(Coord, i32)> {
+        photos
+            .iter()
+            .filter_map(|p| Some((self.positions.get(&p.id)?.clone(), p.id)))
+            .collect()
+    }
+
+    fn date_pages(&self, photos: &[Photo]) -> Result<(), Error> {
+        let mut years = BTreeMap::<Reverse<i32>, Vec<&Photo>>::new();
+        let mut no_date = Vec::new();
+        for photo in photos {
+            match photo.date {
+                Some(date) => {
+                    years.entry(Reverse(date.year())).or_default().push(photo)
+                }
+                None => no_date.push(photo),
+            }
+        }
+        let mut groups = years
+            .iter()
+            .map(|(Reverse(year), photos)| {
+                group_link(
+                    year.to_string(),
+                    format!("/{year}/"),
+                    format!("{} images", photos.len()),
+                    photos,
+                )
+            })
+            .collect::<Vec<_>>();
+        if !no_date.is_empty() {
+            groups.push(group_link(
+                "-".into(),
+                "/0/".into(),
+                format!("{} images", no_date.len()),
+                &no_date,
+            ));
+        }
+        self.page("/", |o, c| {
+            templates::index_html(o, c, "All photos", &[], &groups, &[])
+        })?;
+
+        let links = no_date.iter().copied().map(PhotoLink::no_title);
+        let links = links.collect::<Vec<_>>();
+        self.page("/0/", |o, c| {
+            let title = "Photos without a date";
+            templates::index_html(o, c, title, &[], &links, &[])
+        })?;
+
+        for (Reverse(year), photos) in &years {
+            self.year_pages(*year, photos)?;
+        }
+        Ok(())
+    }
+
+    fn year_pages(&self, year: i32, photos: &[&Photo]) -> Result<(), Error> {
+        // Month 0 is for photos with only a known year (or decade).
+        let mut months = BTreeMap::<Reverse<u32>, Vec<&Photo>>::new();
+        for photo in photos {
+            if let Some(date) = photo.date {
+                let month = if photo.precision() >= DatePrecision::Year {
+                    0
+                } else {
+                    date.month()
+                };
+                months.entry(Reverse(month)).or_default().push(photo);
+            }
+        }
+        let groups = months
+            .iter()
+            .map(|(Reverse(month), photos)| {
+                group_link(
+                    match month {
+                        0 => "Unknown month".into(),
+                        month => monthname(*month).into(),
+                    },
+                    format!("/{year}/{month}/"),
+                    format!("{} pictures", photos.len()),
+                    photos,
+                )
+            })
+            .collect::<Vec<_>>();
+        let coords = self.coords(photos);
+        self.page(&format!("/{year}/"), |o, c| {
+            let title = format!("Photos from {year}");
+            templates::index_html(o, c, &title, &[], &groups, &coords)
+        })?;
+
+        for (Reverse(month), photos) in &months {
+            if *month == 0 {
+                let links = photos.iter().copied().map(PhotoLink::no_title);
+                let links = links.collect::<Vec<_>>();
+                self.page(&format!("/{year}/0/"), |o, c| {
+                    let title = format!("Photos from some time in {year}");
+                    let lpath = [Link::year(year)];
+                    templates::index_html(o, c, &title, &lpath, &links, &[])
+                })?;
+            } else {
+                self.month_pages(year, *month, photos)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn month_pages(
+        &self,
+        year: i32,
+        month: u32,
+        photos: &[&Photo],
+    ) -> Result<(), Error> {
+        // Day 0 is for photos with only a known month.
+        let mut days = BTreeMap::<Reverse<u32>, Vec<&Photo>>::new();
+        for photo in photos {
+            if let Some(date) = photo.date {
+                let day = if photo.precision() == DatePrecision::Month {
+                    0
+                } else {
+                    date.day()
+                };
+                days.entry(Reverse(day)).or_default().push(photo);
+            }
+        }
+        let groups = days
+            .iter()
+            .map(|(Reverse(day), photos)| {
+                group_link(
+                    match day {
+                        0 => "Unknown day".into(),
+                        day => day.to_string(),
+                    },
+                    format!("/{year}/{month}/{day}"),
+                    format!("{} pictures", photos.len()),
+                    photos,
+                )
+            })
+            .collect::<Vec<_>>();
+        let coords = self.coords(photos);
+        self.page(&format!("/{year}/{month}/"), |o, c| {
+            let title = format!("Photos from {} {year}", monthname(month));
+            let lpath = [Link::year(year)];
+            templates::index_html(o, c, &title, &lpath, &groups, &coords)
+        })?;
+
+        for (Reverse(day), photos) in &days {
+            let links = photos.iter().copied().map(PhotoLink::no_title);
+            let links = links.collect::<Vec<_>>();
+            let coords = self.coords(photos);
+            self.page(&format!("/{year}/{month}/{day}"), |o, c| {
+                let title = match day {
+                    0 => format!(
+                        "Photos from some time in {} {year}",
+                        monthname(month)
+                    ),
+                    day => format!(
+                        "Photos from {day} {} {year}",
+                        monthname(month)
+                    ),
+                };
+                let lpath = [Link::year(year), Link::month(year, month)];
+                templates::index_html(o, c, &title, &lpath, &links, &coords)
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn category_pages(
+        &self,
+        db: &mut AsyncPgConnection,
+        photos: &[Photo],
+    ) -> Result<(), Error> {
+        let public = || p::photos.select(p::id).filter(p::is_public);
+        let tags = pt::photo_tags
+            .inner_join(t::tags)
+            .select(((t::id, t::slug, t::tag_name), pt::photo_id))
+            .filter(pt::photo_id.eq_any(public()))
+            .order(t::tag_name)
+            .load::<(Tag, i32)>(db)
+            .await?;
+        let tags = by_category(tags, |t| t.id, photos);
+        let all = tags.iter().map(|(t, _)| t.clone()).collect::<Vec<_>>();
+        self.page("/tag/", |o, c| templates::tags_html(o, c, &all))?;
+        for (tag, photos) in &tags {
+            let (links, coords) = self.dated_links(photos);
+            self.page(&format!("/tag/{}", tag.slug), |o, c| {
+                templates::tag_html(o, c, &links, &coords, tag)
+            })?;
+        }
+
+        let people = pp::photo_people
+            .inner_join(h::people)
+            .select((
+                (h::id, h::slug, h::person_name, h::visibility),
+                pp::photo_id,
+            ))
+            .filter(pp::photo_id.eq_any(public()))
+            .filter(h::visibility.eq(Visibility::Public.as_i16()))
+            .order(h::person_name)
+            .load::<(Person, i32)>(db)
+            .await?;
+        let people = by_category(people, |p| p.id, photos);
+        let all = people.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+        self.page("/person/", |o, c| templates::people_html(o, c, &all))?;
+        for (person, photos) in &people {
+            let (links, coords) = self.dated_links(photos);
+            self.page(&format!("/person/{}", person.slug), |o, c| {
+                templates::person_html(o, c, &links, &coords, person)
+            })?;
+        }
+
+        let places = pl::photo_places
+            .inner_join(l::places)
+            .select((
+                (
+                    l::id,
+                    l::slug,
+                    l::place_name,
+                    l::osm_id,
+                    l::osm_level,
+                    l::parent_id,
+                ),
+                pl::photo_id,
+            ))
+            .filter(pl::photo_id.eq_any(public()))
+            .order(l::place_name)
+            .load::<(Place, i32)>(db)
+            .await?;
+        let places = by_category(places, |p| p.id, photos);
+        let all = places.iter().map(|(p, _)| p.clone()).collect();
+        let tree = PlaceTree::new(all);
+        self.page("/place/", |o, c| templates::places_html(o, c, &tree))?;
+        for (place, photos) in &places {
+            let (links, coords) = self.dated_links(photos);
+            let lpath = tree
+                .ancestors(place)
+                .into_iter()
+                .map(Link::place)
+                .collect::<Vec<_>>();
+            self.page(&format!("/place/{}", place.slug), |o, c| {
+                templates::place_html(o, c, &lpath, &links, &coords, place)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn dated_links(
+        &self,
+        photos: &[&Photo],
+    ) -> (Vec<PhotoLink>, Vec<(Coord, i32)>) {
+        let links = photos.iter().copied().map(PhotoLink::date_title);
+        (links.collect(), self.coords(photos))
+    }
+
+    async fn details_pages(
+        &self,
+        db: &mut AsyncPgConnection,
+        photos: &[Photo],
+    ) -> Result<(), Error> {
+        for photo in photos {
+            let mut photo = PhotoDetails::load(photo.id, db).await?;
+            self.global.privacy_zones().apply_details(&mut photo);
+            photo.hide_people();
+            let lpath = photo
+                .date
+                .map(|d| {
+                    vec![
+                        Link::year(d.year()),
+                        Link::month(d.year(), d.month()),
+                        Link::day(d.year(), d.month(), d.day()),
+                    ]
+                })
+                .unwrap_or_default();
+            self.page(&format!("/img/{}", photo.id), |o, c| {
+                templates::details_html(o, c, &lpath, &photo)
+            })?;
+        }
+        info!("Wrote {} details pages.", photos.len());
+        Ok(())
+    }
+
+    /// Write scaled images that don't already exist in the export.
+    async fn images(&self, photos: &[Photo]) -> Result<(), Error> {
+        let dir = self.dir.join("img");
+        create_dir_all(&dir)?;
+        let mut n_stored = 0;
+        for photo in photos {
+            for size in [SizeTag::Small, SizeTag::Medium] {
+                let file =
+                    dir.join(format!("{}-{}.jpg", photo.id, size.tag()));
+                if file.exists() {
+                    continue;
+                }
+                let path = self.photosdir.get_raw_path(photo);
+                let data = get_scaled_jpeg(path, photo.rotation, size.px())
+                    .await
+                    .map_err(|e| {
+                        Error::Other(format!(
+                            "Failed to scale #{} ({}): {:?}",
+                            photo.id, photo.path, e,
+                        ))
+                    })?;
+                write(file, data)?;
+                n_stored += 1;
+            }
+        }
+        info!("Stored {} new scaled images.", n_stored);
+        Ok(())
+    }
+}
+
+/// Group photo ids by category, keeping the order of `photos`.
+fn by_category<T, F>(
+    data: Vec<(T, i32)>,
+    id: F,
+    photos: &[Photo],
+) -> Vec<(T, Vec<&Photo>)>
+where
+    F: Fn(&T) -> i32,
+{
+    let index = photos
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.id, i))
+        .collect::<HashMap<_, _>>();
+    let mut result: Vec<(T, Vec<usize>)> = Vec::new();
+    for (item, photo_id) in data {
+        let Some(i) = index.get(&photo_id) else {
+            continue; // Public, but a raw file.
+        };
+        match result.last_mut() {
+            Some((last, ids)) if id(last) == id(&item) => ids.push(*i),
+            _ => result.push((item, vec![*i])),
+        }
+    }
+    result
+        .into_iter()
+        .map(|(item, mut ids)| {
+            ids.sort_unstable();
+            (item, ids.into_iter().map(|i| &photos[i]).collect())
+        })
+        .collect()
+}
+
+/// Create a link for a group of photos, showing the best photo.
+///
+/// The best photo is the one with the highest grade, or the first
+/// one by date if grades are equal (like in the server views).
+fn group_link(
+    title: String,
+    href: String,
+    lable: String,
+    photos: &[&Photo],
+) -> PhotoLink {
+    let photo = photos
+        .iter()
+        .min_by_key(|p| (Reverse(p.grade), p.date))
+        .expect("Groups are not empty");
+    PhotoLink {
+        title: Some(title),
+        href,
+        lable: Some(lable),
+        id: photo.id,
+        size: photo.get_size(SizeTag::Small),
+        stack: None,
+    }
+}
+
+#[test]
+fn best_in_group() {
+    let mut photos = [
+        Photo::mock(2018, 8, 31, 21, 45, 48),
+        Photo::mock(2018, 8, 31, 21, 45, 12),
+        Photo::mock(2018, 8, 31, 21, 45, 10),
+    ];
+    photos[0].grade = Some(40);
+    photos[1].grade = Some(40);
+    let group = photos.iter().collect::<Vec<_>>();
+    let link = group_link("t".into(), "/".into(), "l".into(), &group);
+    assert_eq!(link.id, photos[1].id);
+}
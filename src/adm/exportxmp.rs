@@ -0,0 +1,44 @@
+use super::result::Error;
+use crate::models::PhotoDetails;
+use crate::schema::photos::dsl as p;
+use crate::xmp::XmpOpt;
+use crate::{DbOpt, DirOpt};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use tracing::{debug, info};
+
+#[derive(clap::Parser)]
+pub struct Args {
+    #[clap(flatten)]
+    db: DbOpt,
+    #[clap(flatten)]
+    photos: DirOpt,
+    #[clap(flatten)]
+    xmp: XmpOpt,
+
+    /// Only export photos with a path starting with this.
+    #[clap(long)]
+    base: Option<String>,
+}
+
+impl Args {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let writer = self.xmp.writer(&self.photos.photos_dir);
+        let mut query = p::photos.select(p::id).order(p::path).into_boxed();
+        if let Some(base) = &self.base {
+            query = query.filter(p::path.like(format!("{base}%")));
+        }
+        let ids = query.load::<i32>(&mut db).await?;
+        let mut n_written = 0;
+        for id in &ids {
+            let photo = PhotoDetails::load(*id, &mut db).await?;
+            if writer.write(&photo)? {
+                debug!("Wrote {:?}", writer.path_for(&photo));
+                n_written += 1;
+            }
+        }
+        info!("Wrote {} of {} sidecars.", n_written, ids.len());
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::duplicates::{MAX_DISTANCE, load_clusters, merge};
+
+#[derive(clap::Parser)]
+pub struct Duplicates {
+    #[clap(flatten)]
+    db: DbOpt,
+    /// Max number of differing bits in the hashes of near duplicates.
+    ///
+    /// Zero finds only photos with identical hashes.
+    #[clap(
+        long,
+        default_value_t = MAX_DISTANCE,
+        value_parser = clap::value_parser!(u32).range(0..=63)
+    )]
+    max_distance: u32,
+    /// Hide the photos of each cluster that are within max distance
+    /// of the first.
+    ///
+    /// The tags, people and places of the hidden photos are added to
+    /// the first, which is the largest.
+    #[clap(long)]
+    merge: bool,
+}
+
+impl Duplicates {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let clusters = load_clusters(&mut db, self.max_distance).await?;
+        let mut n_hidden = 0;
+        for cluster in &clusters {
+            println!();
+            for (i, photo) in cluster.iter().enumerate() {
+                println!(
+                    "{} #{} {} ({}x{}, {})",
+                    if i == 0 { '*' } else { ' ' },
+                    photo.id,
+                    photo.path,
+                    photo.width,
+                    photo.height,
+                    photo
+                        .date
+                        .map(|d| d.to_string())
+                        .unwrap_or_else(|| "no date".into()),
+                );
+            }
+            if self.merge {
+                // Each cluster is merged in a transaction of its own.
+                let ids = cluster.iter().map(|p| p.id).collect::<Vec<_>>();
+                n_hidden +=
+                    merge(&mut db, ids[0], &ids[1..], self.max_distance)
+                        .await?;
+            }
+        }
+        println!();
+        if self.merge {
+            println!(
+                "Hid {} duplicates in {} clusters.",
+                n_hidden,
+                clusters.len()
+            );
+        } else {
+            println!(
+                "Found {} clusters, the suggested primary is marked by *.",
+                clusters.len()
+            );
+        }
+        Ok(())
+    }
+}
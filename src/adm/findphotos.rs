@@ -1,13 +1,26 @@
 use super::result::Error;
-use crate::models::{Camera, Modification, Photo};
+use crate::duplicates;
+use crate::fences;
+use crate::models::{
+    Camera, DatePrecision, DateSource, Modification, Person, Photo, PhotoDate,
+    Tag,
+};
 use crate::myexif::ExifData;
+use crate::pathdate::DatePatterns;
 use crate::photosdir::{PhotosDir, load_meta};
+use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos::dsl as p;
 use crate::schema::positions::dsl as ps;
+use crate::stacks::{update_stacks, update_stacks_near};
+use crate::xmp::{XmpData, sidecars};
 use crate::{DbOpt, DirOpt};
+use chrono::{DateTime, NaiveDateTime, SubsecRound, Utc};
+use diesel::dsl::exists;
 use diesel::insert_into;
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tracing::{debug, info, instrument, warn};
 
 #[derive(clap::Parser)]
@@ -17,25 +30,76 @@ pub struct Findphotos {
     #[clap(flatten)]
     photos: DirOpt,
 
+    /// How to use keywords, rating, people and caption from xmp
+    /// sidecars and embedded xmp / iptc data.
+    ///
+    /// With "fill", grade and description is only set if missing,
+    /// and keywords and people are only added to photos that have no
+    /// tags or people yet, so tags removed by an admin stay removed.
+    /// With "overwrite", grade and description are replaced by the
+    /// xmp data and keywords and people are always added (but never
+    /// removed).  Except with "overwrite", xmp data is only read if
+    /// the image or sidecar is modified since the last time.
+    #[clap(long, value_enum, default_value = "fill")]
+    xmp: XmpPolicy,
+
+    /// File with patterns for dates in paths, one regex per line.
+    ///
+    /// Used for photos without an exif date, before the built-in
+    /// patterns for names like `IMG-20190704-WA0012.jpg` and
+    /// directories like `1998/12 Julafton`.  A pattern must have a
+    /// named group `year`, and may have `month`, `day`, `hour`,
+    /// `minute` and `second` groups.
+    #[clap(long, env = "RPHOTOS_DATE_PATTERNS")]
+    date_patterns: Option<PathBuf>,
+
+    /// Find stacks of similar photos among all photos, not only near
+    /// photos that are new or hashed again.
+    #[clap(long)]
+    all_stacks: bool,
+
     /// Base directory to search in (relative to the image root).
     base: Vec<String>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum XmpPolicy {
+    Ignore,
+    Fill,
+    Overwrite,
+}
+
 impl Findphotos {
     pub async fn run(&self) -> Result<(), Error> {
         let pd = PhotosDir::new(&self.photos.photos_dir);
+        let patterns = DatePatterns::load(self.date_patterns.as_deref())
+            .map_err(|e| match &self.date_patterns {
+                Some(file) => Error::in_file(&e, file),
+                None => Error::Other(e.to_string()),
+            })?;
         let mut db = self.db.connect().await?;
+        let mut hashed = Vec::new();
         if !self.base.is_empty() {
             for base in &self.base {
-                crawl(&mut db, &pd, Path::new(base)).await.map_err(|e| {
-                    Error::Other(format!("Failed to crawl {base}: {e}"))
-                })?;
+                let path = Path::new(base);
+                crawl(&mut db, &pd, path, &patterns, self.xmp, &mut hashed)
+                    .await
+                    .map_err(|e| {
+                        Error::Other(format!("Failed to crawl {base}: {e}"))
+                    })?;
             }
         } else {
-            crawl(&mut db, &pd, Path::new(""))
+            let path = Path::new("");
+            crawl(&mut db, &pd, path, &patterns, self.xmp, &mut hashed)
                 .await
                 .map_err(|e| Error::Other(format!("Failed to crawl: {e}")))?;
         }
+        let n = if self.all_stacks {
+            update_stacks(&mut db).await?
+        } else {
+            update_stacks_near(&mut db, hashed).await?
+        };
+        info!("Found {} stacks of similar photos.", n);
         Ok(())
     }
 }
@@ -44,6 +108,9 @@ async fn crawl(
     db: &mut AsyncPgConnection,
     photos: &PhotosDir,
     only_in: &Path,
+    patterns: &DatePatterns,
+    xmp: XmpPolicy,
+    hashed: &mut Vec<NaiveDateTime>,
 ) -> Result<(), Error> {
     use futures_lite::stream::StreamExt as _;
     let mut entries = photos.walk_dir(only_in);
@@ -52,7 +119,33 @@ async fn crawl(
             let path = entry.path();
             if let Some(exif) = load_meta(&path) {
                 let sp = photos.subpath(&path)?;
-                save_photo(db, sp, &exif).await?;
+                let date = photo_date(&exif, sp, patterns);
+                let photo = save_photo(db, sp, &exif, date).await?;
+                if !photo.is_raw()
+                    && !duplicates::has_phash(db, &photo).await?
+                    && duplicates::update_phash(db, photos, &photo)
+                        .await?
+                        .is_some()
+                    && let Some(date) = photo.date
+                {
+                    hashed.push(date);
+                }
+                let mtime = xmp_mtime(&path);
+                if xmp == XmpPolicy::Overwrite
+                    || (xmp == XmpPolicy::Fill && mtime != photo.xmp_mtime)
+                {
+                    match XmpData::read_for(&path) {
+                        Ok(data) if !data.is_empty() => {
+                            save_xmp(db, &photo, data, xmp).await?;
+                        }
+                        Ok(_) => (),
+                        Err(e) => warn!("Failed to read xmp: {e}"),
+                    }
+                    diesel::update(p::photos.find(photo.id))
+                        .set(p::xmp_mtime.eq(mtime))
+                        .execute(db)
+                        .await?;
+                }
             } else {
                 debug!("Not an image: {path:?}");
             }
@@ -61,12 +154,36 @@ async fn crawl(
     Ok(())
 }
 
+/// Get the date of a photo from exif, or else from its path.
+fn photo_date(
+    exif: &ExifData,
+    path: &str,
+    patterns: &DatePatterns,
+) -> Option<PhotoDate> {
+    if let Some(date) = exif.date() {
+        Some(PhotoDate {
+            date,
+            precision: DatePrecision::Exact,
+            source: DateSource::Exif,
+        })
+    } else {
+        let (date, precision) = patterns.date_for(path)?;
+        debug!("Date for {} from path is {} ({:?})", path, date, precision);
+        Some(PhotoDate {
+            date,
+            precision,
+            source: DateSource::Path,
+        })
+    }
+}
+
 #[instrument(skip(db, exif))]
 async fn save_photo(
     db: &mut AsyncPgConnection,
     file_path: &str,
     exif: &ExifData,
-) -> Result<(), Error> {
+    date: Option<PhotoDate>,
+) -> Result<Photo, Error> {
     let width = exif.width.ok_or(Error::MissingWidth)?;
     let height = exif.height.ok_or(Error::MissingHeight)?;
     let rot = exif.rotation()?;
@@ -76,7 +193,7 @@ async fn save_photo(
         file_path,
         width as i32,
         height as i32,
-        exif.date(),
+        date,
         rot,
         cam,
     )
@@ -97,10 +214,11 @@ async fn save_photo(
     };
     if let Some((lat, long)) = exif.position() {
         debug!("Position for {} is {} {}", file_path, lat, long);
-        if let Ok((clat, clong)) = ps::positions
+        let details = exif.position_details();
+        if let Ok((clat, clong, false)) = ps::positions
             .filter(ps::photo_id.eq(photo.id))
-            .select((ps::latitude, ps::longitude))
-            .first::<(i32, i32)>(db)
+            .select((ps::latitude, ps::longitude, ps::inferred))
+            .first::<(i32, i32, bool)>(db)
             .await
         {
             let lat = (lat * 1e6) as i32;
@@ -111,23 +229,158 @@ async fn save_photo(
                      Exif position {}, {} differs from saved {}, {}",
                     photo.id, lat, long, clat, clong,
                 );
+            } else if !details.is_empty() {
+                diesel::update(ps::positions)
+                    .filter(ps::photo_id.eq(photo.id))
+                    .set(details)
+                    .execute(db)
+                    .await?;
             }
         } else {
             info!("Position for {} is {} {}", file_path, lat, long);
+            // Exif positions replaces inferred positions.
+            let (lat, long) = ((lat * 1e6) as i32, (long * 1e6) as i32);
             insert_into(ps::positions)
                 .values((
                     ps::photo_id.eq(photo.id),
-                    ps::latitude.eq((lat * 1e6) as i32),
-                    ps::longitude.eq((long * 1e6) as i32),
+                    ps::latitude.eq(lat),
+                    ps::longitude.eq(long),
+                    details,
+                ))
+                .on_conflict(ps::photo_id)
+                .do_update()
+                .set((
+                    ps::latitude.eq(lat),
+                    ps::longitude.eq(long),
+                    ps::inferred.eq(false),
+                    details,
                 ))
                 .execute(db)
                 .await
                 .expect("Insert image position");
+            fences::link_photo(db, photo.id).await?;
+        }
+    }
+    save_utc_offset(db, photo, exif).await
+}
+
+/// Store the utc offset of the photo date, if exif data has it.
+///
+/// A known offset of a photo with a corrected date is kept, since the
+/// exif data is probably as wrong as the camera clock.  A date set
+/// manually has no offset, since it may be anywhere in time.
+async fn save_utc_offset(
+    db: &mut AsyncPgConnection,
+    photo: Photo,
+    exif: &ExifData,
+) -> Result<Photo, Error> {
+    if photo.date_shift != 0 && photo.utc_offset.is_some() {
+        return Ok(photo);
+    }
+    if photo.date_source.as_deref() == Some(DateSource::Manual.as_str()) {
+        return Ok(photo);
+    }
+    let offset = exif.utc_offset().map(|o| (o.seconds() / 60) as i16);
+    if offset.is_none() || offset == photo.utc_offset {
+        return Ok(photo);
+    }
+    debug!("Utc offset of #{} is {:?} minutes", photo.id, offset);
+    Ok(diesel::update(p::photos.find(photo.id))
+        .set(p::utc_offset.eq(offset))
+        .get_result(db)
+        .await?)
+}
+
+/// Store data from xmp / iptc for a photo, according to `policy`.
+#[instrument(skip(db, photo), fields(photo = photo.id))]
+async fn save_xmp(
+    db: &mut AsyncPgConnection,
+    photo: &Photo,
+    xmp: XmpData,
+    policy: XmpPolicy,
+) -> Result<(), Error> {
+    let overwrite = policy == XmpPolicy::Overwrite;
+    if let Some(grade) = xmp.grade()
+        && (photo.grade.is_none() || overwrite)
+        && photo.grade != Some(grade)
+    {
+        info!("Set grade of #{} to {}", photo.id, grade);
+        diesel::update(p::photos.find(photo.id))
+            .set(p::grade.eq(grade))
+            .execute(db)
+            .await?;
+    }
+    if let Some(description) = &xmp.description
+        && (photo.description.is_none() || overwrite)
+        && photo.description.as_ref() != Some(description)
+    {
+        info!("Set description of #{} to {:?}", photo.id, description);
+        diesel::update(p::photos.find(photo.id))
+            .set(p::description.eq(description))
+            .execute(db)
+            .await?;
+    }
+    let (has_tags, has_people) = has_tags_and_people(db, photo).await?;
+    let keywords = if overwrite || !has_tags {
+        &xmp.keywords[..]
+    } else {
+        &[]
+    };
+    for keyword in keywords {
+        let tag = Tag::get_or_create_name(db, keyword).await?;
+        let n = insert_into(pt::photo_tags)
+            .values((pt::photo_id.eq(photo.id), pt::tag_id.eq(tag.id)))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await?;
+        if n > 0 {
+            info!("Tagged #{} with {:?}", photo.id, tag.tag_name);
+        }
+    }
+    let people = if overwrite || !has_people {
+        &xmp.people[..]
+    } else {
+        &[]
+    };
+    for name in people {
+        let person = Person::get_or_create_name(db, name).await?;
+        let n = insert_into(pp::photo_people)
+            .values((pp::photo_id.eq(photo.id), pp::person_id.eq(person.id)))
+            .on_conflict_do_nothing()
+            .execute(db)
+            .await?;
+        if n > 0 {
+            info!("Added {:?} to #{}", person.person_name, photo.id);
         }
     }
     Ok(())
 }
 
+/// Check if a photo has any tags, and if it has any people.
+async fn has_tags_and_people(
+    db: &mut AsyncPgConnection,
+    photo: &Photo,
+) -> Result<(bool, bool), Error> {
+    Ok(diesel::select((
+        exists(pt::photo_tags.filter(pt::photo_id.eq(photo.id))),
+        exists(pp::photo_people.filter(pp::photo_id.eq(photo.id))),
+    ))
+    .get_result(db)
+    .await?)
+}
+
+/// The newest modification time of an image and its sidecars.
+///
+/// Embedded xmp data is part of the image, so the image itself
+/// counts.  The time is truncated to what the database stores.
+fn xmp_mtime(path: &Path) -> Option<DateTime<Utc>> {
+    std::iter::once(path.to_owned())
+        .chain(sidecars(path))
+        .filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+        .max()
+        .map(|t| DateTime::<Utc>::from(t).trunc_subsecs(6))
+}
+
 async fn find_camera(
     db: &mut AsyncPgConnection,
     exif: &ExifData,
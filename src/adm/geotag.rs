@@ -0,0 +1,177 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::clockoffset::ClockOffset;
+use crate::fences;
+use crate::fetch_places::PlacesOpt;
+use crate::models::Photo;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as ps;
+use crate::tracks::Track;
+use crate::xmp::WriteXmpOpt;
+use chrono::{DateTime, Local, TimeDelta, TimeZone, Utc};
+use diesel::insert_into;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+#[derive(clap::Parser)]
+pub struct Geotag {
+    #[clap(flatten)]
+    db: DbOpt,
+    /// How to find places, for --fetch-places.
+    #[clap(flatten)]
+    places: PlacesOpt,
+
+    /// How much the camera clock is ahead of UTC.
+    ///
+    /// E.g. "+2h" for a camera set to central european summer time,
+    /// or "+1h59m30s" if it is also 30 seconds slow.  By default,
+    /// the camera is assumed to use the local time zone of this
+    /// computer, unless the utc offset of the photo is known.
+    #[clap(long, allow_hyphen_values = true)]
+    offset: Option<ClockOffset>,
+    /// Max time (in seconds) between a photo and a track point.
+    #[clap(long, default_value = "300")]
+    max_gap: u32,
+    /// Only geotag photos with a path starting with this.
+    #[clap(long)]
+    base: Option<String>,
+    /// Replace existing positions, not only add missing ones.
+    #[clap(long)]
+    overwrite: bool,
+    /// Fetch places for geotagged photos.
+    #[clap(long)]
+    fetch_places: bool,
+    #[clap(flatten)]
+    xmp: WriteXmpOpt,
+    /// Show what would be done, without changing anything.
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    /// Track files (gpx, kml, or geojson).
+    #[clap(required = true)]
+    tracks: Vec<PathBuf>,
+}
+
+impl Geotag {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut track = Track::default();
+        for file in &self.tracks {
+            track
+                .read_file(file)
+                .map_err(|e| Error::in_file(&e, file))?;
+        }
+        let Some((first, last)) = track.span() else {
+            warn!("No timestamped points in tracks.");
+            return Ok(());
+        };
+        info!(
+            "Read {} track points, {} - {}.",
+            track.n_points(),
+            first,
+            last
+        );
+        let max_gap = TimeDelta::seconds(self.max_gap.into());
+
+        let mut db = self.db.connect().await?;
+        // Load with some margin, since the camera time is not utc.
+        let margin = TimeDelta::days(1) + max_gap;
+        let mut query = p::photos
+            .filter(p::date.ge((first - margin).naive_utc()))
+            .filter(p::date.le((last + margin).naive_utc()))
+            .order(p::date)
+            .into_boxed();
+        if !self.overwrite {
+            query =
+                query.filter(p::id.ne_all(ps::positions.select(ps::photo_id)));
+        }
+        if let Some(base) = &self.base {
+            query = query.filter(p::path.like(format!("{base}%")));
+        }
+        let photos = query.load::<Photo>(&mut db).await?;
+        debug!("Found {} candidate photos.", photos.len());
+
+        let places = if self.fetch_places {
+            Some(self.places.required()?)
+        } else {
+            None
+        };
+        let xmp = self.xmp.writer()?;
+        let mut n_tagged = 0;
+        for photo in &photos {
+            let Some(time) = self.utc(photo) else {
+                continue;
+            };
+            let Some((lat, lng)) = track.position_at(time, max_gap) else {
+                continue;
+            };
+            n_tagged += 1;
+            if self.dry_run {
+                println!("#{} {}: {lat:.6} {lng:.6}", photo.id, photo.path);
+                continue;
+            }
+            info!(
+                "Position for #{} {} is {} {}",
+                photo.id, photo.path, lat, lng
+            );
+            save_position(&mut db, photo.id, lat, lng).await?;
+            fences::link_photo(&mut db, photo.id).await?;
+            if let Some(places) = &places {
+                places.update_image_places(&mut db, photo.id).await?;
+            }
+            if let Some(xmp) = &xmp {
+                xmp.update(&mut db, photo.id).await;
+            }
+        }
+        info!(
+            "Geotagged {} of {} photos{}.",
+            n_tagged,
+            photos.len(),
+            if self.dry_run { " (dry run)" } else { "" },
+        );
+        Ok(())
+    }
+
+    /// The actual utc time for when a photo was taken.
+    fn utc(&self, photo: &Photo) -> Option<DateTime<Utc>> {
+        let date = photo.date?;
+        match self.offset {
+            Some(offset) => Some((date - offset.0).and_utc()),
+            None => photo.date_utc.or_else(|| {
+                Local
+                    .from_local_datetime(&date)
+                    .earliest()
+                    .map(|d| d.with_timezone(&Utc))
+            }),
+        }
+    }
+}
+
+async fn save_position(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+    lat: f64,
+    lng: f64,
+) -> Result<(), Error> {
+    let (lat, lng) = ((lat * 1e6) as i32, (lng * 1e6) as i32);
+    insert_into(ps::positions)
+        .values((
+            ps::photo_id.eq(photo_id),
+            ps::latitude.eq(lat),
+            ps::longitude.eq(lng),
+        ))
+        .on_conflict(ps::photo_id)
+        .do_update()
+        .set((
+            ps::latitude.eq(lat),
+            ps::longitude.eq(lng),
+            ps::inferred.eq(false),
+            // The gps altitude and accuracy are not for this position.
+            ps::altitude.eq(None::<f32>),
+            ps::accuracy.eq(None::<f32>),
+        ))
+        .execute(db)
+        .await?;
+    Ok(())
+}
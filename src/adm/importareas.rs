@@ -0,0 +1,187 @@
+//! Import areas for finding places without the overpass API.
+use super::result::Error;
+use crate::DbOpt;
+use crate::geo::MultiPolygon;
+use crate::place_class::{ClassOpt, Classification};
+use crate::schema::areas::dsl as ar;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+#[derive(clap::Parser)]
+pub struct ImportAreas {
+    #[clap(flatten)]
+    db: DbOpt,
+    #[clap(flatten)]
+    classes: ClassOpt,
+    /// Remove all previously imported areas first.
+    #[clap(long)]
+    replace: bool,
+    /// GeoJSON files to import.
+    ///
+    /// Each file should be a FeatureCollection of OSM relations and
+    /// closed ways, e.g. as exported by `osmium export -a type,id` or
+    /// `osmtogeojson`.
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+}
+
+impl ImportAreas {
+    pub async fn run(&self) -> Result<(), Error> {
+        let classes = self
+            .classes
+            .classification()
+            .map_err(|e| Error::Other(e.to_string()))?;
+        let mut db = self.db.connect().await?;
+        if self.replace {
+            let n = diesel::delete(ar::areas).execute(&mut db).await?;
+            info!("Removed {} old areas.", n);
+        }
+        for file in &self.files {
+            import_file(&mut db, &classes, file).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn import_file(
+    db: &mut AsyncPgConnection,
+    classes: &Classification,
+    file: &Path,
+) -> Result<(), Error> {
+    let reader = BufReader::new(File::open(file)?);
+    let data: Value = serde_json::from_reader(reader)
+        .map_err(|e| Error::in_file(&e, file))?;
+    let features = data
+        .get("features")
+        .and_then(Value::as_array)
+        .ok_or_else(|| Error::Other(format!("No features in {file:?}")))?;
+    let (mut n_stored, mut n_skipped) = (0, 0);
+    for feature in features {
+        let Some(area) = Area::from_feature(feature, classes) else {
+            n_skipped += 1;
+            continue;
+        };
+        debug!("Storing area {}", area.osm_id);
+        diesel::insert_into(ar::areas)
+            .values((
+                ar::osm_id.eq(area.osm_id),
+                ar::tags.eq(&area.tags),
+                ar::min_lat.eq(area.min.0),
+                ar::max_lat.eq(area.max.0),
+                ar::min_lng.eq(area.min.1),
+                ar::max_lng.eq(area.max.1),
+                ar::geometry.eq(&area.geometry),
+            ))
+            .on_conflict(ar::osm_id)
+            .do_update()
+            .set((
+                ar::tags.eq(excluded(ar::tags)),
+                ar::min_lat.eq(excluded(ar::min_lat)),
+                ar::max_lat.eq(excluded(ar::max_lat)),
+                ar::min_lng.eq(excluded(ar::min_lng)),
+                ar::max_lng.eq(excluded(ar::max_lng)),
+                ar::geometry.eq(excluded(ar::geometry)),
+            ))
+            .execute(db)
+            .await?;
+        n_stored += 1;
+    }
+    info!(
+        "Stored {} areas from {:?}, skipped {} other features.",
+        n_stored, file, n_skipped,
+    );
+    Ok(())
+}
+
+struct Area {
+    osm_id: i64,
+    tags: Value,
+    min: (i32, i32),
+    max: (i32, i32),
+    geometry: Value,
+}
+
+impl Area {
+    /// Get an area from a GeoJSON feature, if it is a (multi)polygon
+    /// with tags that can be used as a place.
+    fn from_feature(
+        feature: &Value,
+        classes: &Classification,
+    ) -> Option<Area> {
+        let props = feature.get("properties")?.as_object()?;
+        let osm_id = area_id(feature.get("id"), props)?;
+        let tags = props
+            .iter()
+            .filter(|(k, _)| !k.starts_with('@'))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Map<_, _>>();
+        let tags = Value::Object(tags);
+        if !classes.may_be_place(&tags) {
+            return None;
+        }
+        let geometry = feature.get("geometry")?;
+        let (min, max) = MultiPolygon::from_geojson(geometry)?.bbox()?;
+        let micro = |(lng, lat): (f64, f64)| {
+            ((lat * 1e6).round() as i32, (lng * 1e6).round() as i32)
+        };
+        Some(Area {
+            osm_id,
+            tags,
+            min: micro(min),
+            max: micro(max),
+            geometry: geometry.clone(),
+        })
+    }
+}
+
+/// Get the overpass area id of an OSM way or relation.
+///
+/// The id may be given as a feature id like `"r123"` or
+/// `"relation/123"`, or as `@type` and `@id` properties.
+fn area_id(id: Option<&Value>, props: &Map<String, Value>) -> Option<i64> {
+    let id = match (props.get("@type").and_then(Value::as_str), id) {
+        (Some(kind), _) => {
+            let id = props.get("@id")?;
+            let id = id.as_i64().or_else(|| id.as_str()?.parse().ok())?;
+            (kind.to_string(), id)
+        }
+        (None, Some(Value::String(id))) => split_id(id)?,
+        (None, _) => split_id(props.get("@id")?.as_str()?)?,
+    };
+    match id {
+        (kind, id) if kind == "relation" || kind == "r" => {
+            Some(3_600_000_000 + id)
+        }
+        (kind, id) if kind == "way" || kind == "w" => Some(2_400_000_000 + id),
+        _ => None,
+    }
+}
+
+fn split_id(id: &str) -> Option<(String, i64)> {
+    let (kind, num) = match id.split_once('/') {
+        Some(parts) => parts,
+        None => id.split_at(id.find(|c: char| c.is_ascii_digit())?),
+    };
+    Some((kind.to_string(), num.parse().ok()?))
+}
+
+#[test]
+fn osm_area_ids() {
+    let none = Map::new();
+    let id = |s: &str| area_id(Some(&serde_json::json!(s)), &none);
+    assert_eq!(id("r62422"), Some(3_600_062_422));
+    assert_eq!(id("relation/62422"), Some(3_600_062_422));
+    assert_eq!(id("way/4711"), Some(2_400_004_711));
+    assert_eq!(id("n17"), None);
+    let props = serde_json::json!({"@type": "relation", "@id": 62422, "name": "Berlin"});
+    assert_eq!(
+        area_id(Some(&serde_json::json!(1)), props.as_object().unwrap()),
+        Some(3_600_062_422),
+    );
+}
@@ -0,0 +1,102 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::fences;
+use crate::fetch_places::PlacesOpt;
+use crate::models::Photo;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as ps;
+use crate::xmp::WriteXmpOpt;
+use chrono::TimeDelta;
+use diesel::insert_into;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use tracing::info;
+
+#[derive(clap::Parser)]
+pub struct Interpolate {
+    #[clap(flatten)]
+    db: DbOpt,
+
+    /// Max time (in seconds) between a photo and the photos its
+    /// position is interpolated from.
+    #[clap(long, default_value = "600")]
+    window: u32,
+    /// Only handle photos with a path starting with this.
+    #[clap(long)]
+    base: Option<String>,
+    /// How to find places, for --fetch-places.
+    #[clap(flatten)]
+    places: PlacesOpt,
+    /// Fetch places for photos that get a position.
+    #[clap(long)]
+    fetch_places: bool,
+    #[clap(flatten)]
+    xmp: WriteXmpOpt,
+    /// List the positions that would be set, without changing anything.
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+}
+
+impl Interpolate {
+    /// Set inferred positions for photos that has a date but no
+    /// position.
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let window = TimeDelta::seconds(self.window.into());
+        let places = if self.fetch_places {
+            Some(self.places.required()?)
+        } else {
+            None
+        };
+        let xmp = self.xmp.writer()?;
+        let mut query = p::photos
+            .filter(p::date.is_not_null())
+            .filter(p::id.ne_all(ps::positions.select(ps::photo_id)))
+            .order(p::date)
+            .into_boxed();
+        if let Some(base) = &self.base {
+            query = query.filter(p::path.like(format!("{base}%")));
+        }
+        let photos = query.load::<Photo>(&mut db).await?;
+        let mut n_found = 0;
+        for photo in &photos {
+            let Some(pos) =
+                photo.interpolate_position(&mut db, window).await?
+            else {
+                continue;
+            };
+            n_found += 1;
+            if self.dry_run {
+                println!(
+                    "#{} {}: {:.6} {:.6}",
+                    photo.id, photo.path, pos.x, pos.y
+                );
+                continue;
+            }
+            info!("Inferred position for #{} is {:?}", photo.id, pos);
+            insert_into(ps::positions)
+                .values((
+                    ps::photo_id.eq(photo.id),
+                    ps::latitude.eq((pos.x * 1e6) as i32),
+                    ps::longitude.eq((pos.y * 1e6) as i32),
+                    ps::inferred.eq(true),
+                ))
+                .execute(&mut db)
+                .await?;
+            fences::link_photo(&mut db, photo.id).await?;
+            if let Some(places) = &places {
+                places.update_image_places(&mut db, photo.id).await?;
+            }
+            if let Some(xmp) = &xmp {
+                xmp.update(&mut db, photo.id).await;
+            }
+        }
+        info!(
+            "Found positions for {} of {} photos{}.",
+            n_found,
+            photos.len(),
+            if self.dry_run { " (dry run)" } else { "" },
+        );
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
+pub mod dump;
+pub mod exportpositions;
+pub mod exportstatic;
+pub mod exportxmp;
+pub mod findduplicates;
 pub mod findphotos;
+pub mod geotag;
+pub mod importareas;
+pub mod interpolate;
 pub mod makepublic;
+pub mod placeparents;
 pub mod precache;
+pub mod reclassify;
 pub mod result;
 pub mod stats;
 pub mod storestatics;
+pub mod timeshift;
 pub mod users;
@@ -0,0 +1,113 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::places::dsl as l;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::BTreeMap;
+use tracing::info;
+
+#[derive(clap::Parser)]
+pub struct PlaceParents {
+    #[clap(flatten)]
+    db: DbOpt,
+}
+
+impl PlaceParents {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let n = update_parents(&mut db).await?;
+        info!("Changed the parent of {} places.", n);
+        Ok(())
+    }
+}
+
+/// Set the parent of each place, from the places of its photos.
+///
+/// Returns the number of places that got a new parent.
+pub async fn update_parents(
+    db: &mut AsyncPgConnection,
+) -> Result<usize, diesel::result::Error> {
+    let places = l::places
+        .select((l::id, l::osm_level, l::parent_id))
+        .load::<(i32, Option<i16>, Option<i32>)>(db)
+        .await?;
+    let links = pl::photo_places
+        .select((pl::photo_id, pl::place_id))
+        .order(pl::photo_id)
+        .load::<(i32, i32)>(db)
+        .await?;
+    let levels = places
+        .iter()
+        .filter_map(|&(id, level, _)| Some((id, level?)))
+        .collect();
+    let parents = find_parents(&links, &levels);
+    let mut n = 0;
+    for (id, _, old) in places {
+        let new = parents.get(&id).copied();
+        if new != old {
+            diesel::update(l::places.find(id))
+                .set(l::parent_id.eq(new))
+                .execute(db)
+                .await?;
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Find the parent of places, by which places occur together.
+///
+/// The `links` are (photo, place) pairs, ordered by photo.  The
+/// parent of a place is the most specific place of a lower level that
+/// is on most of the photos of the place.
+fn find_parents(
+    links: &[(i32, i32)],
+    levels: &BTreeMap<i32, i16>,
+) -> BTreeMap<i32, i32> {
+    let mut totals = BTreeMap::<i32, usize>::new();
+    let mut together = BTreeMap::<(i32, i32), usize>::new();
+    for photo in links.chunk_by(|a, b| a.0 == b.0) {
+        for &(_, place) in photo {
+            *totals.entry(place).or_default() += 1;
+            let Some(level) = levels.get(&place) else {
+                continue;
+            };
+            for &(_, other) in photo {
+                if levels.get(&other).is_some_and(|l| l < level) {
+                    *together.entry((place, other)).or_default() += 1;
+                }
+            }
+        }
+    }
+    let mut parents = BTreeMap::<i32, (i32, (i16, usize))>::new();
+    for ((place, other), n) in together {
+        if 2 * n <= totals[&place] {
+            continue;
+        }
+        let rank = (levels[&other], n);
+        match parents.get(&place) {
+            Some((_, best)) if *best >= rank => (),
+            _ => {
+                parents.insert(place, (other, rank));
+            }
+        }
+    }
+    parents.into_iter().map(|(k, (v, _))| (k, v)).collect()
+}
+
+#[test]
+fn parents_by_level_and_occurrence() {
+    // 1: country, 2: municipality, 3: park, 4: other municipality,
+    // 5: another park in the first municipality.
+    let levels = [(1, 2), (2, 7), (3, 14), (4, 7), (5, 14)].into();
+    #[rustfmt::skip]
+    let links = [
+        (10, 1), (10, 2), (10, 3),
+        (11, 1), (11, 2), (11, 3),
+        (12, 1), (12, 4), (12, 3), // A bad position near the border
+        (13, 1), (13, 2), (13, 5),
+    ];
+    let parents = find_parents(&links, &levels);
+    assert_eq!(parents, [(2, 1), (3, 2), (4, 1), (5, 2)].into(),);
+}
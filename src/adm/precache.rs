@@ -1,7 +1,10 @@
 use super::result::Error;
+use crate::duplicates::update_phash;
 use crate::models::{Photo, SizeTag};
 use crate::photosdir::{PhotosDir, get_scaled_jpeg};
+use crate::schema::photo_hashes::dsl as ph;
 use crate::schema::photos::dsl::{date, is_public};
+use crate::stacks::update_stacks_near;
 use crate::{CacheOpt, DbOpt, DirOpt};
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
@@ -24,7 +27,8 @@ pub struct Args {
 }
 
 impl Args {
-    /// Make sure all photos are stored in the cache.
+    /// Make sure all photos are stored in the cache, and have a
+    /// perceptual hash for finding duplicates and stacks.
     ///
     /// The work are intentionally handled sequentially, to not
     /// overwhelm the host while precaching.
@@ -35,15 +39,26 @@ impl Args {
         let timer = Instant::now();
         let cache = Client::connect(self.cache.memcached_url.as_ref())?;
         let size = SizeTag::Small;
-        let (mut n, mut n_stored) = (0, 0);
+        let (mut n, mut n_stored, mut n_hashed) = (0, 0, 0);
+        let mut hashed_dates = Vec::new();
+        let mut db = self.db.connect().await?;
         let photos = Photo::query(true)
+            .left_join(ph::photo_hashes)
+            .select((Photo::as_select(), ph::photo_id.nullable()))
             .order((is_public.desc(), date.desc().nulls_last()))
-            .load::<Photo>(&mut self.db.connect().await?)
+            .load::<(Photo, Option<i32>)>(&mut db)
             .await?;
         let no_expire = 0;
         let pd = PhotosDir::new(&self.photos.photos_dir);
-        for photo in photos {
+        for (photo, hashed) in photos {
             n += 1;
+            let done = n_stored + n_hashed;
+            if hashed.is_none()
+                && update_phash(&mut db, &pd, &photo).await?.is_some()
+            {
+                n_hashed += 1;
+                hashed_dates.extend(photo.date);
+            }
             let key = &photo.cache_key(size);
             if cache.get::<Vec<u8>>(key)?.is_none() {
                 let path = pd.get_raw_path(&photo);
@@ -59,9 +74,6 @@ impl Args {
                 cache.set(key, &data[..], no_expire)?;
                 debug!("Cache: stored {} for {}", key, photo.path);
                 n_stored += 1;
-                if timer.elapsed() > max_time {
-                    break;
-                }
                 if n_stored % 64 == 0 {
                     info!(
                         "Checked {} images in cache, added {}, in {:.1?}.",
@@ -71,13 +83,21 @@ impl Args {
                     );
                 }
             }
+            if n_stored + n_hashed > done && timer.elapsed() > max_time {
+                break;
+            }
         }
         info!(
-            "Checked {} images in cache, added {}, in {:.1?}.",
+            "Checked {} images in cache, added {} and hashed {}, in {:.1?}.",
             n,
             n_stored,
+            n_hashed,
             timer.elapsed()
         );
+        if n_hashed > 0 {
+            let n = update_stacks_near(&mut db, hashed_dates).await?;
+            info!("Found {} stacks of similar photos.", n);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,93 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::fetch_places::is_duplicate;
+use crate::place_class::ClassOpt;
+use crate::schema::place_osm::dsl as po;
+use crate::schema::places::dsl as l;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde_json::Value;
+use tracing::{info, warn};
+
+#[derive(clap::Parser)]
+pub struct Reclassify {
+    #[clap(flatten)]
+    db: DbOpt,
+    #[clap(flatten)]
+    classes: ClassOpt,
+    /// List the changes, without changing anything.
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+}
+
+impl Reclassify {
+    pub async fn run(&self) -> Result<(), Error> {
+        let classes = self
+            .classes
+            .classification()
+            .map_err(|e| Error::Other(e.to_string()))?;
+        let mut db = self.db.connect().await?;
+        let places = l::places
+            .inner_join(po::place_osm)
+            .select((
+                l::id,
+                l::place_name,
+                l::osm_level,
+                po::country,
+                po::tags,
+            ))
+            .order(l::id)
+            .load::<(i32, String, Option<i16>, Option<String>, Value)>(&mut db)
+            .await?;
+        let mut n_changed = 0;
+        for (id, old_name, old_level, country, tags) in &places {
+            let Some((name, level)) =
+                classes.name_and_level(tags, country.as_deref())
+            else {
+                println!("#{id} {old_name}: no longer a place, kept as is.");
+                continue;
+            };
+            if Some(level) == *old_level && name == old_name {
+                continue;
+            }
+            n_changed += 1;
+            println!(
+                "#{id} {old_name} (level {}) -> {name} (level {level})",
+                old_level.map(|l| l.to_string()).as_deref().unwrap_or("-"),
+            );
+            if self.dry_run {
+                continue;
+            }
+            diesel::update(l::places.find(id))
+                .set(l::osm_level.eq(level))
+                .execute(&mut db)
+                .await?;
+            if name != old_name {
+                let renamed = diesel::update(l::places.find(id))
+                    .set(l::place_name.eq(name))
+                    .execute(&mut db)
+                    .await;
+                if is_duplicate(&renamed) {
+                    warn!("Can't rename #{id} to {name:?}, name is taken.");
+                } else {
+                    renamed?;
+                }
+            }
+        }
+        info!("Changed {} of {} places.", n_changed, places.len());
+        let n_without = l::places
+            .filter(l::osm_id.is_not_null())
+            .filter(l::id.ne_all(po::place_osm.select(po::place_id)))
+            .count()
+            .get_result::<i64>(&mut db)
+            .await?;
+        if n_without > 0 {
+            info!(
+                "{} places lack stored osm tags.  Fetch places for their \
+                 photos again to reclassify them.",
+                n_without,
+            );
+        }
+        Ok(())
+    }
+}
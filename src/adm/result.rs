@@ -46,7 +46,7 @@ impl fmt::Display for Error {
             Error::MissingHeight => write!(f, "Missing height property"),
             Error::MissingWidth => write!(f, "Missing width property"),
             Error::PlacesFailed(ref e) => {
-                write!(f, "Failed to get places: {e:?}")
+                write!(f, "Failed to get places: {e}")
             }
             Error::Other(ref s) => write!(f, "Error: {s}"),
         }
@@ -7,8 +7,7 @@ use std::fs::{File, create_dir_all};
 use std::io::prelude::*;
 use std::path::Path;
 
-pub fn to_dir(dir: &str) -> Result<(), Error> {
-    let dir: &Path = dir.as_ref();
+pub fn to_dir(dir: &Path) -> Result<(), Error> {
     for s in STATICS {
         // s.name may contain directory components.
         if let Some(parent) = dir.join(s.name).parent() {
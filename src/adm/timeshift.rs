@@ -0,0 +1,92 @@
+use super::result::Error;
+use crate::DbOpt;
+use crate::clockoffset::ClockOffset;
+use crate::models::Photo;
+use crate::schema::cameras::dsl as c;
+use crate::schema::photos::dsl as p;
+use crate::server::search::SearchOpt;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use tracing::info;
+
+#[derive(clap::Parser)]
+#[clap(group(
+    clap::ArgGroup::new("selection")
+        .required(true)
+        .multiple(true)
+        .args(["camera", "base", "tag", "person", "place", "since", "until"])
+))]
+pub struct Timeshift {
+    #[clap(flatten)]
+    db: DbOpt,
+
+    /// Only shift photos from a camera with this model name.
+    #[clap(long)]
+    camera: Option<String>,
+    /// Only shift photos with a path starting with this.
+    #[clap(long)]
+    base: Option<String>,
+    #[clap(flatten)]
+    search: SearchOpt,
+    /// List the photos and their new dates, without changing anything.
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    /// How much to shift the dates, e.g. "+1h" or "-0:02:30".
+    ///
+    /// The shift is stored, so it is applied again when `findphotos`
+    /// reads the date from exif.
+    #[clap(allow_hyphen_values = true)]
+    shift: ClockOffset,
+}
+
+impl Timeshift {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut db = self.db.connect().await?;
+        let search = self.search.query(&mut db).await.map_err(|_| {
+            Error::Other("Bad or unknown photo selection".into())
+        })?;
+        // Include raw files and hidden duplicates, they have the same
+        // camera clock as the photos found by the search.
+        let mut query =
+            Photo::exif_dated(search.filter(p::photos.into_boxed()))
+                .order((p::date, p::id));
+        if let Some(camera) = &self.camera {
+            query = query.filter(
+                p::camera_id.eq_any(
+                    c::cameras
+                        .select(c::id.nullable())
+                        .filter(c::model.eq(camera)),
+                ),
+            );
+        }
+        if let Some(base) = &self.base {
+            query = query.filter(p::path.like(format!("{base}%")));
+        }
+        let photos = query.load::<Photo>(&mut db).await?;
+        let shift = self.shift.0;
+        if self.dry_run {
+            for photo in &photos {
+                if let Some(date) = photo.date {
+                    println!(
+                        "#{} {}: {} -> {}",
+                        photo.id,
+                        photo.path,
+                        date,
+                        date + shift,
+                    );
+                }
+            }
+            info!(
+                "Would shift {} photos by {} (dry run).",
+                photos.len(),
+                self.shift,
+            );
+            return Ok(());
+        }
+        let ids = photos.iter().map(|p| p.id).collect::<Vec<_>>();
+        let n = Photo::shift_dates(&mut db, &ids, shift).await?;
+        info!("Shifted {} photos by {}.", n, self.shift);
+        Ok(())
+    }
+}
@@ -6,6 +6,8 @@ fn main() -> Result<(), RucteError> {
     statics.add_sass_file("res/photos.scss")?;
     statics.add_file("res/rphotos.svg")?;
     statics.add_file("res/admin.js")?;
+    statics.add_file("res/fences.js")?;
+    statics.add_file("res/map.js")?;
     statics.add_file("res/ux.js")?;
     statics.add_files_as("res/leaflet-1.4.0", "l140")?;
     statics.add_files_as("res/leaflet-cluster-1.4.1", "lm141")?;
@@ -0,0 +1,133 @@
+//! Parse camera clock offsets, like `+2h`, `-1h30m15s` or `+02:00`.
+use chrono::TimeDelta;
+use std::fmt;
+use std::str::FromStr;
+
+/// How much a camera clock differs from some reference time.
+///
+/// A positive offset means the camera clock is ahead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClockOffset(pub TimeDelta);
+
+impl ClockOffset {
+    pub fn seconds(self) -> i64 {
+        self.0.num_seconds()
+    }
+}
+
+impl FromStr for ClockOffset {
+    type Err = BadOffset;
+
+    /// Parse an offset.
+    ///
+    /// Either as units (`h`, `m`, `s` and `d`), as in `-1h30m`, or as
+    /// `[+-]H:MM[:SS]`, as in `+02:00`.  A bare number is seconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'-') => (-1, &s[1..]),
+            Some(b'+') => (1, &s[1..]),
+            _ => (1, s),
+        };
+        if rest.is_empty() {
+            return Err(BadOffset);
+        }
+        let seconds = if rest.contains(':') {
+            let mut parts = rest.split(':').map(|p| {
+                p.parse::<u32>().map(i64::from).map_err(|_| BadOffset)
+            });
+            let h = parts.next().ok_or(BadOffset)??;
+            let m = parts.next().ok_or(BadOffset)??;
+            let s = parts.next().transpose()?.unwrap_or(0);
+            if parts.next().is_some() || m > 59 || s > 59 {
+                return Err(BadOffset);
+            }
+            (h * 60 + m) * 60 + s
+        } else if let Ok(n) = rest.parse::<u32>() {
+            i64::from(n)
+        } else {
+            let mut total = 0;
+            let mut num = String::new();
+            for c in rest.chars() {
+                if c.is_ascii_digit() {
+                    num.push(c);
+                } else {
+                    let n = num.parse::<i64>().map_err(|_| BadOffset)?;
+                    num.clear();
+                    total += n * match c {
+                        'd' => 86400,
+                        'h' => 3600,
+                        'm' => 60,
+                        's' => 1,
+                        _ => return Err(BadOffset),
+                    };
+                }
+            }
+            if !num.is_empty() {
+                return Err(BadOffset);
+            }
+            total
+        };
+        Ok(ClockOffset(TimeDelta::seconds(sign * seconds)))
+    }
+}
+
+impl fmt::Display for ClockOffset {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        let s = self.seconds();
+        let sign = if s < 0 { '-' } else { '+' };
+        let s = s.abs();
+        write!(out, "{sign}{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+    }
+}
+
+#[derive(Debug)]
+pub struct BadOffset;
+
+impl fmt::Display for BadOffset {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        out.write_str("bad offset, expected e.g. \"+2h\" or \"-01:30\"")
+    }
+}
+
+impl std::error::Error for BadOffset {}
+
+#[cfg(test)]
+mod tests {
+    use super::ClockOffset;
+
+    fn secs(s: &str) -> Option<i64> {
+        s.parse::<ClockOffset>().ok().map(ClockOffset::seconds)
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(secs("+2h"), Some(7200));
+        assert_eq!(secs("-1h30m15s"), Some(-5415));
+        assert_eq!(secs("1d"), Some(86400));
+        assert_eq!(secs("90"), Some(90));
+    }
+
+    #[test]
+    fn colons() {
+        assert_eq!(secs("+02:00"), Some(7200));
+        assert_eq!(secs("-0:05:30"), Some(-330));
+    }
+
+    #[test]
+    fn bad() {
+        assert_eq!(secs(""), None);
+        assert_eq!(secs("-"), None);
+        assert_eq!(secs("2x"), None);
+        assert_eq!(secs("1h30"), None);
+        assert_eq!(secs("1:75"), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            ClockOffset(chrono::TimeDelta::seconds(-5415)).to_string(),
+            "-1:30:15"
+        );
+    }
+}
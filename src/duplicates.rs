@@ -0,0 +1,351 @@
+//! Find photos that are duplicates or near duplicates of each other.
+//!
+//! Photos are compared by a perceptual hash, so the same photo found
+//! under different paths, or scaled or recompressed, is found.  When
+//! a cluster of duplicates is merged, one photo is kept as primary and
+//! the others near it are hidden, with their tags, people and places
+//! added to the primary.
+use crate::models::Photo;
+use crate::photosdir::{PhotosDir, get_phash};
+use crate::schema::photo_hashes::dsl as ph;
+use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as ps;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::{Bool, Integer};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::collections::HashMap;
+use tracing::{debug, warn};
+
+define_sql_function! {
+    #[aggregate]
+    fn bool_or(value: Bool) -> Nullable<Bool>
+}
+
+/// The default number of differing hash bits for near duplicates.
+pub const MAX_DISTANCE: u32 = 4;
+
+/// Compute and store the perceptual hash of a photo.
+///
+/// Failure to read the image is logged, but not an error.  A missing
+/// hash is stored for the photo, so it is not tried again.
+pub async fn update_phash(
+    db: &mut AsyncPgConnection,
+    dir: &PhotosDir,
+    photo: &Photo,
+) -> Result<Option<i64>, Error> {
+    let hash = match get_phash(dir.get_raw_path(photo)).await {
+        Ok(hash) => {
+            debug!("Hash of #{} is {:016x}", photo.id, hash);
+            Some(hash)
+        }
+        Err(e) => {
+            warn!("Failed to hash #{} ({}): {}", photo.id, photo.path, e);
+            None
+        }
+    };
+    diesel::insert_into(ph::photo_hashes)
+        .values((ph::photo_id.eq(photo.id), ph::phash.eq(hash)))
+        .on_conflict(ph::photo_id)
+        .do_update()
+        .set(ph::phash.eq(hash))
+        .execute(db)
+        .await?;
+    Ok(hash)
+}
+
+/// True if the photo has a stored perceptual hash, or has failed to
+/// get one.
+pub async fn has_phash(
+    db: &mut AsyncPgConnection,
+    photo: &Photo,
+) -> Result<bool, Error> {
+    Ok(ph::photo_hashes
+        .find(photo.id)
+        .select(ph::photo_id)
+        .first::<i32>(db)
+        .await
+        .optional()?
+        .is_some())
+}
+
+/// Load clusters of duplicate photos.
+///
+/// Only photos that are not already hidden as duplicates are
+/// considered.  In each cluster, the suggested primary photo (the
+/// largest one, and the oldest one in the database of those) is
+/// first.
+pub async fn load_clusters(
+    db: &mut AsyncPgConnection,
+    max_distance: u32,
+) -> Result<Vec<Vec<Photo>>, Error> {
+    let photos = Photo::query(true)
+        .inner_join(ph::photo_hashes)
+        .filter(ph::phash.is_not_null())
+        .select((Photo::as_select(), ph::phash.assume_not_null()))
+        .load::<(Photo, i64)>(db)
+        .await?;
+    let hashes = photos
+        .iter()
+        .map(|(photo, hash)| (photo.id, *hash))
+        .collect::<Vec<_>>();
+    let mut photos = photos
+        .into_iter()
+        .map(|(photo, _)| (photo.id, photo))
+        .collect::<HashMap<_, _>>();
+    Ok(clusters(&hashes, max_distance)
+        .into_iter()
+        .map(|ids| {
+            let mut cluster = ids
+                .iter()
+                .filter_map(|id| photos.remove(id))
+                .collect::<Vec<_>>();
+            cluster.sort_by_key(|photo| {
+                (-i64::from(photo.width) * i64::from(photo.height), photo.id)
+            });
+            cluster
+        })
+        .collect())
+}
+
+/// Hide `others` as duplicates of `primary`.
+///
+/// Only the others with a hash within `max_distance` of the primary
+/// are hidden, since a cluster may chain photos that are not near
+/// duplicates of each other.  The tags, people and places of the
+/// hidden photos are added to the primary photo, as is the highest
+/// grade, a position if the primary has none, and the public flag if
+/// any of them is public.
+/// Returns the number of photos hidden.  All changes are done in one
+/// transaction, so a failure never leaves a half merged photo.
+pub async fn merge(
+    db: &mut AsyncPgConnection,
+    primary: i32,
+    others: &[i32],
+    max_distance: u32,
+) -> Result<usize, Error> {
+    let others = others.to_vec();
+    db.transaction(move |db| {
+        async move {
+            let others = near(db, primary, &others, max_distance).await?;
+            merge_into(db, primary, &others).await
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// The ids of `others` with a hash within `max_distance` of `primary`.
+async fn near(
+    db: &mut AsyncPgConnection,
+    primary: i32,
+    others: &[i32],
+    max_distance: u32,
+) -> Result<Vec<i32>, Error> {
+    let hashes = ph::photo_hashes
+        .filter(ph::photo_id.eq(primary).or(ph::photo_id.eq_any(others)))
+        .filter(ph::phash.is_not_null())
+        .select((ph::photo_id, ph::phash.assume_not_null()))
+        .load::<(i32, i64)>(db)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let Some(&hash) = hashes.get(&primary) else {
+        warn!("Primary #{} has no hash, not merging", primary);
+        return Ok(Vec::new());
+    };
+    Ok(others
+        .iter()
+        .copied()
+        .filter(|id| *id != primary)
+        .filter(|id| {
+            let near = hashes
+                .get(id)
+                .is_some_and(|h| distance(hash, *h) <= max_distance);
+            if !near {
+                debug!("#{} is not near primary #{}", id, primary);
+            }
+            near
+        })
+        .collect())
+}
+
+async fn merge_into(
+    db: &mut AsyncPgConnection,
+    primary: i32,
+    others: &[i32],
+) -> Result<usize, Error> {
+    let primary_id = primary.into_sql::<Integer>();
+    diesel::insert_into(pt::photo_tags)
+        .values(
+            pt::photo_tags
+                .select((primary_id, pt::tag_id))
+                .filter(pt::photo_id.eq_any(others)),
+        )
+        .into_columns((pt::photo_id, pt::tag_id))
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    diesel::insert_into(pp::photo_people)
+        .values(
+            pp::photo_people
+                .select((primary_id, pp::person_id))
+                .filter(pp::photo_id.eq_any(others)),
+        )
+        .into_columns((pp::photo_id, pp::person_id))
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    diesel::insert_into(pl::photo_places)
+        .values(
+            pl::photo_places
+                .select((primary_id, pl::place_id))
+                .filter(pl::photo_id.eq_any(others)),
+        )
+        .into_columns((pl::photo_id, pl::place_id))
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    // Note: positions has a unique photo_id, so an existing position
+    // of the primary is kept.
+    diesel::insert_into(ps::positions)
+        .values(
+            ps::positions
+                .select((
+                    primary_id,
+                    ps::latitude,
+                    ps::longitude,
+                    ps::inferred,
+                    ps::altitude,
+                    ps::accuracy,
+                    ps::direction,
+                ))
+                .filter(ps::photo_id.eq_any(others))
+                .order((ps::inferred, ps::photo_id))
+                .limit(1),
+        )
+        .into_columns((
+            ps::photo_id,
+            ps::latitude,
+            ps::longitude,
+            ps::inferred,
+            ps::altitude,
+            ps::accuracy,
+            ps::direction,
+        ))
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    let all = p::id.eq(primary).or(p::id.eq_any(others));
+    let (grade, public) = p::photos
+        .select((diesel::dsl::max(p::grade), bool_or(p::is_public)))
+        .filter(all)
+        .first::<(Option<i16>, Option<bool>)>(db)
+        .await?;
+    diesel::update(p::photos.find(primary))
+        .set((
+            p::grade.eq(grade),
+            p::is_public.eq(public.unwrap_or(false)),
+            p::duplicate_of.eq(None::<i32>),
+        ))
+        .execute(db)
+        .await?;
+    diesel::update(p::photos.filter(p::id.eq_any(others)))
+        .set(p::duplicate_of.eq(primary))
+        .execute(db)
+        .await
+}
+
+/// Show a hidden duplicate again.
+pub async fn unmark(db: &mut AsyncPgConnection, id: i32) -> Result<(), Error> {
+    diesel::update(p::photos.find(id))
+        .set(p::duplicate_of.eq(None::<i32>))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Number of bits that differ between two hashes.
+pub fn distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Group photo ids by hashes, where each photo in a cluster is within
+/// `max_distance` of some other photo in the cluster.
+///
+/// The hashes are split in `max_distance + 1` parts, and two hashes
+/// that are close enough must have at least one part in common, so
+/// only hashes that share a part are compared.
+fn clusters(hashes: &[(i32, i64)], max_distance: u32) -> Vec<Vec<i32>> {
+    let parts = max_distance.min(63) + 1;
+    let mut buckets = HashMap::<(u32, u64), Vec<usize>>::new();
+    for (i, (_, hash)) in hashes.iter().enumerate() {
+        for part in 0..parts {
+            let (from, to) = (64 * part / parts, 64 * (part + 1) / parts);
+            let bits = (*hash as u64) << from >> from >> (64 - to);
+            buckets.entry((part, bits)).or_default().push(i);
+        }
+    }
+    let mut sets = UnionFind::new(hashes.len());
+    for bucket in buckets.values() {
+        for (n, &i) in bucket.iter().enumerate() {
+            for &j in &bucket[n + 1..] {
+                if distance(hashes[i].1, hashes[j].1) <= max_distance {
+                    sets.union(i, j);
+                }
+            }
+        }
+    }
+    let mut result = HashMap::<usize, Vec<i32>>::new();
+    for (i, (id, _)) in hashes.iter().enumerate() {
+        result.entry(sets.find(i)).or_default().push(*id);
+    }
+    let mut result = result
+        .into_values()
+        .filter(|ids| ids.len() > 1)
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
+struct UnionFind(Vec<usize>);
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind((0..len).collect())
+    }
+    fn find(&mut self, i: usize) -> usize {
+        let parent = self.0[i];
+        if parent == i {
+            i
+        } else {
+            let root = self.find(parent);
+            self.0[i] = root;
+            root
+        }
+    }
+    fn union(&mut self, i: usize, j: usize) {
+        let (i, j) = (self.find(i), self.find(j));
+        self.0[i.max(j)] = i.min(j);
+    }
+}
+
+#[test]
+fn cluster_near_hashes() {
+    let hashes = [
+        (1, 0x0f0f_0f0f_0f0f_0f0f),
+        (2, 0x7777_7777_7777_7777),
+        (3, 0x0f0f_0f0f_0f0f_0f0e),
+        (4, 0x0f0f_0f0f_0f0f_0f0f),
+        (5, 0x7777_7777_7777_7700),
+        (6, 0x0f0f_0f0f_0f0f_0f00),
+    ];
+    // 3 and 6 differ from 1 in one and four bits.
+    assert_eq!(clusters(&hashes, 4), [vec![1, 3, 4, 6]]);
+    assert_eq!(clusters(&hashes, 0), [vec![1, 4]]);
+    assert_eq!(clusters(&hashes, 8), [vec![1, 3, 4, 6], vec![2, 5]]);
+}
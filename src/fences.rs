@@ -0,0 +1,281 @@
+//! Custom places, defined by a geofence rather than by OSM.
+//!
+//! A fence is either a circle (a center and a radius) or a polygon.
+//! Photos with a position inside a fence are linked to its place.
+use crate::geo::{MultiPolygon, Point, distance};
+use crate::models::Place;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::place_fences::dsl as f;
+use crate::schema::places::dsl as l;
+use crate::schema::positions::dsl as ps;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde_json::{Value, json};
+use slug::slugify;
+use tracing::debug;
+
+/// Approximate length of a degree of latitude, in meters.
+const DEGREE: f64 = 111_320.;
+
+#[derive(Debug)]
+pub enum Fence {
+    Circle { center: Point, radius: f64 },
+    Area(MultiPolygon),
+}
+
+impl Fence {
+    /// Get a fence from a list of `[lat, lng]` pairs.
+    ///
+    /// A single point makes a circle with the given radius (in
+    /// meters), and three or more points makes a polygon.
+    pub fn from_points(
+        points: &[(f64, f64)],
+        radius: Option<f64>,
+    ) -> Option<Self> {
+        let mut points = points.iter().map(|&(lat, lng)| (lng, lat));
+        match (points.len(), radius) {
+            (1, Some(radius)) if radius > 0. => Some(Fence::Circle {
+                center: points.next()?,
+                radius,
+            }),
+            (3.., _) => {
+                let mut ring = points.collect::<Vec<_>>();
+                ring.push(ring[0]);
+                Some(Fence::Area(MultiPolygon::polygon(ring)))
+            }
+            _ => None,
+        }
+    }
+
+    fn from_db(geometry: &Value, radius: Option<f64>) -> Option<Self> {
+        match radius {
+            Some(radius) => {
+                let coords = geometry.get("coordinates")?;
+                Some(Fence::Circle {
+                    center: (
+                        coords.get(0)?.as_f64()?,
+                        coords.get(1)?.as_f64()?,
+                    ),
+                    radius,
+                })
+            }
+            None => MultiPolygon::from_geojson(geometry).map(Fence::Area),
+        }
+    }
+
+    /// Get the fence as a GeoJSON geometry, and a radius for a circle.
+    pub fn geometry(&self) -> (Value, Option<f64>) {
+        match self {
+            Fence::Circle { center, radius } => (
+                json!({"type": "Point", "coordinates": [center.0, center.1]}),
+                Some(*radius),
+            ),
+            Fence::Area(area) => (area.to_geojson(), None),
+        }
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        match self {
+            Fence::Circle { center, radius } => {
+                distance(*center, point) <= *radius
+            }
+            Fence::Area(area) => area.contains(point),
+        }
+    }
+
+    /// The bounding box, as (min, max) points.
+    pub fn bbox(&self) -> Option<(Point, Point)> {
+        match self {
+            Fence::Circle {
+                center: (lng, lat),
+                radius,
+            } => {
+                let dlat = radius / DEGREE;
+                let dlng = dlat / lat.to_radians().cos().max(0.01);
+                Some(((lng - dlng, lat - dlat), (lng + dlng, lat + dlat)))
+            }
+            Fence::Area(area) => area.bbox(),
+        }
+    }
+}
+
+/// A custom place with its fence.
+pub struct FencedPlace {
+    pub place: Place,
+    pub fence: Fence,
+}
+
+impl FencedPlace {
+    pub async fn load_all(
+        db: &mut AsyncPgConnection,
+    ) -> Result<Vec<FencedPlace>, Error> {
+        Ok(l::places
+            .inner_join(f::place_fences)
+            .select((
+                (
+                    l::id,
+                    l::slug,
+                    l::place_name,
+                    l::osm_id,
+                    l::osm_level,
+                    l::parent_id,
+                ),
+                f::geometry,
+                f::radius,
+            ))
+            .order(l::place_name)
+            .load::<(Place, Value, Option<f64>)>(db)
+            .await?
+            .into_iter()
+            .filter_map(|(place, geometry, radius)| {
+                let fence = Fence::from_db(&geometry, radius)?;
+                Some(FencedPlace { place, fence })
+            })
+            .collect())
+    }
+}
+
+/// Create a custom place, and link all photos inside the fence to it.
+pub async fn create_place(
+    db: &mut AsyncPgConnection,
+    name: &str,
+    level: i16,
+    fence: &Fence,
+) -> Result<Place, Error> {
+    // Note: Only an empty polygon lacks a bounding box.
+    let ((min_lng, min_lat), (max_lng, max_lat)) =
+        fence.bbox().ok_or(Error::NotFound)?;
+    let (min_lat, max_lat) = (micro(min_lat).floor(), micro(max_lat).ceil());
+    let (min_lng, max_lng) = (micro(min_lng).floor(), micro(max_lng).ceil());
+    let (min_lat, max_lat) = (min_lat as i32, max_lat as i32);
+    let (min_lng, max_lng) = (min_lng as i32, max_lng as i32);
+    let (geometry, radius) = fence.geometry();
+    // All or nothing, a place without its fence is not a custom place.
+    db.transaction(|db| {
+        async move {
+            let place = diesel::insert_into(l::places)
+                .values((
+                    l::place_name.eq(name),
+                    l::slug.eq(slugify(name)),
+                    l::osm_level.eq(level),
+                ))
+                .get_result::<Place>(db)
+                .await?;
+            diesel::insert_into(f::place_fences)
+                .values((
+                    f::place_id.eq(place.id),
+                    f::geometry.eq(geometry),
+                    f::radius.eq(radius),
+                    f::min_lat.eq(min_lat),
+                    f::max_lat.eq(max_lat),
+                    f::min_lng.eq(min_lng),
+                    f::max_lng.eq(max_lng),
+                ))
+                .execute(db)
+                .await?;
+            let photos = ps::positions
+                .filter(ps::latitude.between(min_lat, max_lat))
+                .filter(ps::longitude.between(min_lng, max_lng))
+                .select((ps::photo_id, ps::latitude, ps::longitude))
+                .load::<(i32, i32, i32)>(db)
+                .await?
+                .into_iter()
+                .filter(|&(_, lat, lng)| fence.contains(point(lat, lng)))
+                .map(|(photo_id, _, _)| photo_id)
+                .collect::<Vec<_>>();
+            link(db, &photos, place.id).await?;
+            debug!("Linked {} photos to new place {:?}", photos.len(), place);
+            Ok(place)
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// Link a photo to all custom places with a fence around its position.
+///
+/// Links to custom places that no longer contain the photo (or when
+/// it no longer has a position) are removed.
+pub async fn link_photo(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<(), Error> {
+    let position = ps::positions
+        .filter(ps::photo_id.eq(photo_id))
+        .select((ps::latitude, ps::longitude))
+        .first::<(i32, i32)>(db)
+        .await
+        .optional()?;
+    let mut inside = Vec::new();
+    if let Some((lat, lng)) = position {
+        let fences = f::place_fences
+            .filter(f::min_lat.le(lat))
+            .filter(f::max_lat.ge(lat))
+            .filter(f::min_lng.le(lng))
+            .filter(f::max_lng.ge(lng))
+            .select((f::place_id, f::geometry, f::radius))
+            .load::<(i32, Value, Option<f64>)>(db)
+            .await?;
+        for (place_id, geometry, radius) in fences {
+            if Fence::from_db(&geometry, radius)
+                .is_some_and(|fence| fence.contains(point(lat, lng)))
+            {
+                inside.push(place_id);
+            }
+        }
+    }
+    let n = diesel::delete(
+        pl::photo_places
+            .filter(pl::photo_id.eq(photo_id))
+            .filter(pl::place_id.eq_any(f::place_fences.select(f::place_id)))
+            .filter(pl::place_id.ne_all(&inside)),
+    )
+    .execute(db)
+    .await?;
+    if n > 0 {
+        debug!("Photo #{} left {} custom places", photo_id, n);
+    }
+    for place_id in inside {
+        debug!("Photo #{} is in custom place #{}", photo_id, place_id);
+        link(db, &[photo_id], place_id).await?;
+    }
+    Ok(())
+}
+
+async fn link(
+    db: &mut AsyncPgConnection,
+    photos: &[i32],
+    place_id: i32,
+) -> Result<(), Error> {
+    diesel::insert_into(pl::photo_places)
+        .values(
+            photos
+                .iter()
+                .map(|id| (pl::photo_id.eq(id), pl::place_id.eq(place_id)))
+                .collect::<Vec<_>>(),
+        )
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+fn micro(degrees: f64) -> f64 {
+    degrees * 1e6
+}
+
+fn point(lat: i32, lng: i32) -> Point {
+    (f64::from(lng) / 1e6, f64::from(lat) / 1e6)
+}
+
+#[test]
+fn circle_fence() {
+    let fence = Fence::from_points(&[(59.3293, 18.0686)], Some(500.)).unwrap();
+    assert!(fence.contains((18.0700, 59.3300)));
+    assert!(!fence.contains((18.0800, 59.3300)));
+    let ((min_lng, min_lat), (max_lng, max_lat)) = fence.bbox().unwrap();
+    assert!(min_lat < 59.325 && max_lat > 59.333);
+    assert!(min_lng < 18.060 && max_lng > 18.077);
+}
@@ -1,13 +1,25 @@
 use crate::DbOpt;
+use crate::adm::placeparents::update_parents;
+use crate::geo::MultiPolygon;
 use crate::models::{Coord, Place};
+use crate::place_class::{self, ClassOpt, Classification, country_of};
+use crate::place_queue;
+use crate::schema::areas::dsl as ar;
 use crate::schema::photo_places::dsl as pl;
+use crate::schema::place_fences::dsl as pf;
+use crate::schema::place_osm::dsl as po;
 use crate::schema::places::dsl as l;
 use crate::schema::positions::dsl as ps;
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use reqwest::{self, Client, Response};
-use serde_json::Value;
+use serde_json::{Value, json};
 use slug::slugify;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{Instant, sleep_until};
 use tracing::{debug, info, instrument};
 
 #[derive(clap::Parser)]
@@ -15,7 +27,7 @@ pub struct Fetchplaces {
     #[clap(flatten)]
     db: DbOpt,
     #[clap(flatten)]
-    overpass: OverpassOpt,
+    places: PlacesOpt,
 
     /// Max number of photos to use for --auto
     #[clap(long, short, default_value = "5")]
@@ -23,6 +35,10 @@ pub struct Fetchplaces {
     /// Fetch data for photos with position but lacking places.
     #[clap(long, short)]
     auto: bool,
+    /// Add the photos to the place queue, for the server to fetch
+    /// places with retries, instead of fetching them now.
+    #[clap(long, short)]
+    queue: bool,
     /// Image ids to fetch place data for
     photos: Vec<i32>,
 }
@@ -30,42 +46,101 @@ pub struct Fetchplaces {
 impl Fetchplaces {
     pub async fn run(&self) -> Result<(), super::adm::result::Error> {
         let mut db = self.db.connect().await?;
-        if self.auto {
+        let photos = if self.auto {
             println!("Should find {} photos to fetch places for", self.limit);
-            let result =
-                ps::positions
-                    .select((ps::photo_id, (ps::latitude, ps::longitude)))
-                    .filter(ps::photo_id.ne_all(
+            ps::positions
+                .select(ps::photo_id)
+                .filter(
+                    ps::photo_id.ne_all(
                         pl::photo_places.select(pl::photo_id).distinct(),
-                    ))
-                    .order(ps::photo_id.desc())
-                    .limit(self.limit)
-                    .load::<(i32, Coord)>(&mut db)
-                    .await?;
-            for (photo_id, coord) in result {
-                println!("Find places for #{photo_id}, {coord:?}");
-                self.overpass.update_image_places(&mut db, photo_id).await?;
+                    ),
+                )
+                .order(ps::photo_id.desc())
+                .limit(self.limit)
+                .load::<i32>(&mut db)
+                .await?
+        } else {
+            self.photos.clone()
+        };
+        if self.queue {
+            for photo in &photos {
+                place_queue::enqueue(&mut db, *photo).await?;
             }
+            println!("Queued {} photos for places.", photos.len());
         } else {
-            for photo in &self.photos {
-                self.overpass.update_image_places(&mut db, *photo).await?;
+            let lookup = self.places.required()?;
+            for photo in photos {
+                println!("Find places for #{photo}");
+                lookup.update_image_places(&mut db, photo).await?;
             }
+            update_parents(&mut db).await?;
         }
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, clap::Parser)]
-pub struct OverpassOpt {
+/// How to find places for a position.
+#[derive(Clone, Debug, Default, clap::Parser)]
+pub struct PlacesOpt {
     /// How to connect to the overpass API.
     ///
     /// See <https://wiki.openstreetmap.org/wiki/Overpass_API> for
     /// available servers and policies.
     #[clap(long, env = "OVERPASS_URL")]
-    overpass_url: String,
+    overpass_url: Option<String>,
+    /// Minimum time (in seconds) between requests to the overpass API.
+    #[clap(long, env = "OVERPASS_INTERVAL", default_value = "2")]
+    overpass_interval: f64,
+    /// Find places in areas imported by `import-areas`, rather than
+    /// by the overpass API.
+    #[clap(long, env = "RPHOTOS_LOCAL_AREAS")]
+    local_areas: bool,
+    #[clap(flatten)]
+    classes: ClassOpt,
+}
+
+impl PlacesOpt {
+    /// Get the selected lookup, if any.
+    pub fn lookup(&self) -> Result<Option<PlaceLookup>, Error> {
+        let source = if self.local_areas {
+            Source::Local
+        } else if let Some(url) = &self.overpass_url {
+            Source::Overpass(Overpass {
+                url: url.clone(),
+                interval: Duration::from_secs_f64(self.overpass_interval),
+                last: Arc::default(),
+            })
+        } else {
+            return Ok(None);
+        };
+        let classes =
+            Arc::new(self.classes.classification().map_err(Error::Config)?);
+        Ok(Some(PlaceLookup { source, classes }))
+    }
+
+    /// Get the selected lookup, or an error if none is selected.
+    pub fn required(&self) -> Result<PlaceLookup, Error> {
+        self.lookup()?.ok_or(Error::NoLookup)
+    }
+}
+
+/// A way to find places for a position.
+#[derive(Clone, Debug)]
+pub struct PlaceLookup {
+    source: Source,
+    classes: Arc<Classification>,
+}
+
+/// A backend for finding the areas a position is in.
+#[derive(Clone, Debug)]
+enum Source {
+    /// Use an overpass API server.
+    Overpass(Overpass),
+    /// Use areas imported to the database.
+    Local,
 }
 
-impl OverpassOpt {
+impl PlaceLookup {
     #[instrument(skip(self, db))]
     pub async fn update_image_places(
         &self,
@@ -81,209 +156,134 @@ impl OverpassOpt {
             .map_err(|e| Error::Db(image, e))?
             .ok_or(Error::NoPosition(image))?;
         debug!(?coord, "Should get places.");
-        let data = Client::new()
-            .post(&self.overpass_url)
-            .body(format!("[out:json];is_in({},{});out;", coord.x, coord.y))
-            .send()
-            .await
-            .and_then(Response::error_for_status)
-            .map_err(|e| Error::Server(image, e))?
-            .json::<Value>()
-            .await
-            .map_err(|e| Error::Server(image, e))?;
-
-        if let Some(elements) = data
-            .as_object()
-            .and_then(|o| o.get("elements"))
-            .and_then(Value::as_array)
-        {
-            for obj in elements {
-                if let (Some(t_osm_id), Some((name, level))) =
-                    (osm_id(obj), name_and_level(obj))
-                {
-                    debug!("{}: {} (level {})", t_osm_id, name, level);
-                    let place = get_or_create_place(db, t_osm_id, name, level)
+        let elements = match &self.source {
+            Source::Overpass(overpass) => overpass
+                .is_in(&coord)
+                .await
+                .map_err(|e| Error::Server(image, e))?,
+            Source::Local => local_is_in(db, &coord)
+                .await
+                .map_err(|e| Error::Db(image, e))?,
+        };
+        let country = country_of(&elements);
+        for obj in &elements {
+            let tags = obj.get("tags").unwrap_or(&Value::Null);
+            if let (Some(t_osm_id), Some((name, level))) =
+                (osm_id(obj), self.classes.name_and_level(tags, country))
+            {
+                debug!("{}: {} (level {})", t_osm_id, name, level);
+                let place = get_or_create_place(db, t_osm_id, name, level)
+                    .await
+                    .map_err(|e| Error::Db(image, e))?;
+                store_osm_tags(db, place.id, country, tags)
+                    .await
+                    .map_err(|e| Error::Db(image, e))?;
+                let q = pl::photo_places
+                    .select(pl::place_id)
+                    .filter(pl::photo_id.eq(image))
+                    .filter(pl::place_id.eq(place.id));
+                if q.first::<i32>(db).await.is_ok() {
+                    debug!(
+                        "Photo #{} already has {} ({})",
+                        image, place.id, place.place_name
+                    );
+                } else {
+                    diesel::insert_into(pl::photo_places)
+                        .values((
+                            pl::photo_id.eq(image),
+                            pl::place_id.eq(place.id),
+                        ))
+                        .execute(db)
                         .await
                         .map_err(|e| Error::Db(image, e))?;
-                    let q = pl::photo_places
-                        .select(pl::place_id)
-                        .filter(pl::photo_id.eq(image))
-                        .filter(pl::place_id.eq(place.id));
-                    if q.first::<i32>(db).await.is_ok() {
-                        debug!(
-                            "Photo #{} already has {} ({})",
-                            image, place.id, place.place_name
-                        );
-                    } else {
-                        diesel::insert_into(pl::photo_places)
-                            .values((
-                                pl::photo_id.eq(image),
-                                pl::place_id.eq(place.id),
-                            ))
-                            .execute(db)
-                            .await
-                            .map_err(|e| Error::Db(image, e))?;
-                    }
-                } else {
-                    info!("Unused area: {}", obj);
                 }
+            } else {
+                info!("Unused area: {}", obj);
             }
         }
         Ok(())
     }
 }
 
-fn osm_id(obj: &Value) -> Option<i64> {
-    obj.get("id").and_then(Value::as_i64)
+/// An overpass API server, with a limit on the request rate.
+///
+/// Clones share the time of the last request, so the limit applies
+/// to all of them together.
+#[derive(Clone, Debug)]
+pub struct Overpass {
+    url: String,
+    interval: Duration,
+    last: Arc<Mutex<Option<Instant>>>,
 }
 
-#[rustfmt::skip] // This data is written in a more compact style
-static KNOWN: [(&str, &[(&str, i16)]); 19] = [
-    ("ref:KATEGORI", &[
-        ("Tätort", 11),
-    ]),
-    ("leisure", &[
-        ("dog_park", 19),
-        ("fitness_station", 17),
-        ("garden", 18),
-        ("nature_reserve", 12),
-        ("park", 14),
-        ("pitch", 15),
-        ("playground", 16),
-        ("sports_centre", 18),
-        ("water_park", 14),
-    ]),
-    ("building", &[
-        ("exhibition_center", 19),
-        ("sports_hall", 19),
-        ("", 20), // MAGIC: Empty value means default
-    ]),
-    ("tourism", &[
-        ("guest_house", 17),
-        ("attraction", 16),
-        ("museum", 16),
-        ("camp_site", 15),
-        ("theme_park", 14),
-        ("zoo", 14),
-    ]),
-    ("boundary", &[
-        ("national_park", 14),
-        ("historic", 5), // Seems to be mainly "Landskap"
-    ]),
-    ("landuse", &[
-        ("allotments", 14),
-        ("commercial", 12),
-        ("grass", 13),
-        ("farmland", 14),
-        ("farmyard", 16),
-        ("industrial", 11),
-        ("meadow", 16),
-        ("railway", 13),
-        ("residential", 11),
-        ("retail", 13),
-        ("cemetery", 14),
-        ("military", 13),
-        ("quarry", 14),
-    ]),
-    ("highway", &[
-        ("pedestrian", 15),  // torg
-        ("residential", 15), // torg?
-        ("rest_area", 16), // rastplats
-        ("services", 16), // rastplats / vägkrog
-    ]),
-    ("public_transport", &[
-        ("station", 18),
-    ]),
-    ("amenity", &[
-        ("bus_station", 16),
-        ("exhibition_center", 20),
-        ("kindergarten", 15),
-        ("place_of_worship", 15),
-        ("school", 14),
-        ("university", 12),
-        ("hospital", 14),
-    ]),
-    ("aeroway", &[
-        ("aerodrome", 14),
-    ]),
-    ("water", &[
-        ("lake", 15),
-        ("river", 16),
-    ]),
-    ("waterway", &[
-        ("riverbank", 16),
-    ]),
-    ("man_made", &[
-        ("bridge", 17),
-    ]),
-    ("place", &[
-        ("city_block", 17),
-        ("island", 13),
-        ("islet", 17),
-        ("penisula", 13),
-        ("region", 8),
-        ("square", 18),
-        ("suburb", 12),
-        ("town", 11),
-    ]),
-    ("natural", &[
-        ("bay", 14),
-        ("beach", 15),
-        ("scrub", 18),
-        ("wood", 14),
-        ("peninsula", 11),
-        ("wetland", 15),
-        ("strait", 12),
-    ]),
-    ("designation", &[
-        ("historic_county", 9),
-    ]),
-    ("political_division", &[
-        ("canton", 9),
-    ]),
-    ("seamark:type", &[
-        ("harbour_basin", 18),
-    ]),
-    // Not sure about this, check later if someone changes Bruddesta Sjöbodar
-    ("historic", &[
-        ("manor", 16),
-        ("heritage", 14),
-        ("mine", 14),
-    ]),
-];
-
-fn name_and_level(obj: &Value) -> Option<(&str, i16)> {
-    let tags = obj.get("tags")?;
-    let name = tags
-        .get("name:sv")
-        // .or_else(|| tags.get("name:en"))
-        .or_else(|| tags.get("name"))
-        .and_then(Value::as_str)?;
-    let level = tags
-        .get("admin_level")
-        .and_then(Value::as_str)
-        .and_then(|l| l.parse().ok())
-        .or_else(|| {
-            KNOWN
-                .iter()
-                .find_map(|(name, values)| tag_level(tags, name, values))
-        })?;
+impl Overpass {
+    /// Get the areas containing a position.
+    ///
+    /// Waits as long as needed to respect the request interval.
+    async fn is_in(
+        &self,
+        coord: &Coord,
+    ) -> Result<Vec<Value>, reqwest::Error> {
+        let mut last = self.last.lock().await;
+        if let Some(last) = *last {
+            sleep_until(last + self.interval).await;
+        }
+        let result = overpass_is_in(&self.url, coord).await;
+        *last = Some(Instant::now());
+        result
+    }
+}
 
-    Some((name, level))
+async fn overpass_is_in(
+    url: &str,
+    coord: &Coord,
+) -> Result<Vec<Value>, reqwest::Error> {
+    let data = Client::new()
+        .post(url)
+        .body(format!("[out:json];is_in({},{});out;", coord.x, coord.y))
+        .send()
+        .await
+        .and_then(Response::error_for_status)?
+        .json::<Value>()
+        .await?;
+    Ok(match data {
+        Value::Object(mut data) => match data.remove("elements") {
+            Some(Value::Array(elements)) => elements,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    })
 }
 
-fn tag_level(tags: &Value, name: &str, values: &[(&str, i16)]) -> Option<i16> {
-    let value = tag_str(tags, name)?;
-    for (vs, vi) in values {
-        if &value == vs || vs.is_empty() {
-            return Some(*vi);
-        }
-    }
-    None
+/// Get the imported areas containing a position.
+///
+/// The areas are returned in the same form as from overpass.
+async fn local_is_in(
+    db: &mut AsyncPgConnection,
+    coord: &Coord,
+) -> Result<Vec<Value>, diesel::result::Error> {
+    let (lat, lng) = ((coord.x * 1e6) as i32, (coord.y * 1e6) as i32);
+    let candidates = ar::areas
+        .filter(ar::min_lat.le(lat))
+        .filter(ar::max_lat.ge(lat))
+        .filter(ar::min_lng.le(lng))
+        .filter(ar::max_lng.ge(lng))
+        .select((ar::osm_id, ar::tags, ar::geometry))
+        .load::<(i64, Value, Value)>(db)
+        .await?;
+    debug!("Found {} candidate areas", candidates.len());
+    Ok(candidates
+        .into_iter()
+        .filter(|(_, _, geometry)| {
+            MultiPolygon::from_geojson(geometry)
+                .is_some_and(|area| area.contains((coord.y, coord.x)))
+        })
+        .map(|(id, tags, _)| json!({"id": id, "tags": tags, "type": "area"}))
+        .collect())
 }
 
-fn tag_str<'a>(tags: &'a Value, name: &str) -> Option<&'a str> {
-    tags.get(name).and_then(Value::as_str)
+fn osm_id(obj: &Value) -> Option<i64> {
+    obj.get("id").and_then(Value::as_i64)
 }
 
 async fn get_or_create_place(
@@ -298,6 +298,7 @@ async fn get_or_create_place(
                 .eq(Some(t_osm_id))
                 .or(l::place_name.eq(name).and(l::osm_id.is_null())),
         )
+        .filter(l::id.ne_all(pf::place_fences.select(pf::place_id)))
         .first::<Place>(c)
         .await
         .optional()?;
@@ -340,7 +341,28 @@ async fn get_or_create_place(
     }
 }
 
-fn is_duplicate<T>(r: &Result<T, diesel::result::Error>) -> bool {
+/// Store the osm tags of a place, for reclassifying it later.
+async fn store_osm_tags(
+    db: &mut AsyncPgConnection,
+    place_id: i32,
+    country: Option<&str>,
+    tags: &Value,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(po::place_osm)
+        .values((
+            po::place_id.eq(place_id),
+            po::country.eq(country),
+            po::tags.eq(tags),
+        ))
+        .on_conflict(po::place_id)
+        .do_update()
+        .set((po::country.eq(country), po::tags.eq(tags)))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub fn is_duplicate<T>(r: &Result<T, diesel::result::Error>) -> bool {
     use diesel::result::DatabaseErrorKind::UniqueViolation;
     use diesel::result::Error::DatabaseError;
     matches!(r, Err(DatabaseError(UniqueViolation, _)))
@@ -348,37 +370,40 @@ fn is_duplicate<T>(r: &Result<T, diesel::result::Error>) -> bool {
 
 #[derive(Debug)]
 pub enum Error {
+    NoLookup,
+    Config(place_class::Error),
     NoPosition(i32),
     Db(i32, diesel::result::Error),
-    Pool(i32, String),
     Server(i32, reqwest::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoLookup => {
+                write!(out, "Neither an overpass url nor local areas given")
+            }
+            Error::Config(e) => write!(out, "{e}"),
+            Error::NoPosition(id) => {
+                write!(out, "Photo #{id} has no position")
+            }
+            Error::Db(id, e) => write!(out, "Database error for #{id}: {e}"),
+            Error::Server(id, e) => {
+                write!(out, "Lookup failed for #{id}: {e}")
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::name_and_level;
+    use crate::place_class::Classification;
     use serde_json::Value;
-    use std::collections::BTreeSet;
 
-    #[test]
-    fn check_known_data() {
-        assert_eq!(
-            super::KNOWN
-                .iter()
-                .map(|(k, _v)| k)
-                .collect::<BTreeSet<_>>()
-                .len(),
-            super::KNOWN.len(),
-            "Types of KNOWN are not distinct",
-        );
-        for (key, val) in &super::KNOWN {
-            assert_eq!(
-                val.iter().map(|(k, _v)| k).collect::<BTreeSet<_>>().len(),
-                val.len(),
-                "Subtypes of KNOWN {key:?} are not distinct",
-            );
-        }
+    fn name_and_level(obj: &Value) -> Option<(&str, i16)> {
+        Classification::builtin().name_and_level(obj.get("tags")?, Some("SE"))
     }
+
     #[test]
     fn test_long_reply() -> Result<(), Box<dyn std::error::Error>> {
         let data: Value = TEST_DATA.parse()?;
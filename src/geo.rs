@@ -0,0 +1,130 @@
+//! Simple geometry on (multi)polygons in GeoJSON coordinate order.
+use serde_json::{Value, json};
+
+/// A point as (longitude, latitude), as in GeoJSON.
+pub type Point = (f64, f64);
+
+/// One or more polygons, each an outer ring followed by any holes.
+#[derive(Debug, Default)]
+pub struct MultiPolygon(Vec<Vec<Vec<Point>>>);
+
+impl MultiPolygon {
+    /// A single polygon without holes.
+    pub fn polygon(ring: Vec<Point>) -> Self {
+        MultiPolygon(vec![vec![ring]])
+    }
+
+    /// Get a (multi)polygon from a GeoJSON `Polygon` or `MultiPolygon`
+    /// geometry object.
+    pub fn from_geojson(geometry: &Value) -> Option<Self> {
+        let coords = geometry.get("coordinates")?;
+        match geometry.get("type")?.as_str()? {
+            "Polygon" => Some(MultiPolygon(vec![polygon(coords)?])),
+            "MultiPolygon" => coords
+                .as_array()?
+                .iter()
+                .map(polygon)
+                .collect::<Option<_>>()
+                .map(MultiPolygon),
+            _ => None,
+        }
+    }
+
+    /// Get this as a GeoJSON `MultiPolygon` geometry object.
+    pub fn to_geojson(&self) -> Value {
+        let coords = |ring: &Vec<Point>| {
+            ring.iter().map(|&(x, y)| json!([x, y])).collect::<Vec<_>>()
+        };
+        json!({
+            "type": "MultiPolygon",
+            "coordinates": self.0.iter()
+                .map(|rings| rings.iter().map(coords).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    /// True if the point is inside any of the polygons (and not in
+    /// any of its holes).
+    pub fn contains(&self, point: Point) -> bool {
+        self.0.iter().any(|rings| {
+            let mut rings = rings.iter();
+            rings
+                .next()
+                .is_some_and(|outer| ring_contains(outer, point))
+                && !rings.any(|hole| ring_contains(hole, point))
+        })
+    }
+
+    /// The bounding box, as (min, max) points.
+    pub fn bbox(&self) -> Option<(Point, Point)> {
+        let mut points = self.0.iter().flat_map(|p| p.first()).flatten();
+        let first = *points.next()?;
+        Some(points.fold((first, first), |(min, max), &(x, y)| {
+            ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y)))
+        }))
+    }
+}
+
+/// The distance in meters between two points, along the surface of
+/// the earth.
+pub fn distance((lng1, lat1): Point, (lng2, lat2): Point) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let (dlat, dlng) = (lat2 - lat1, (lng2 - lng1).to_radians());
+    let a = (dlat / 2.).sin().powi(2)
+        + lat1.cos() * lat2.cos() * (dlng / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * a.sqrt().asin()
+}
+
+fn polygon(coords: &Value) -> Option<Vec<Vec<Point>>> {
+    coords
+        .as_array()?
+        .iter()
+        .map(|ring| {
+            ring.as_array()?
+                .iter()
+                .map(|p| Some((p.get(0)?.as_f64()?, p.get(1)?.as_f64()?)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Check if a point is inside a ring, by counting crossings of a ray
+/// from the point.
+fn ring_contains(ring: &[Point], (x, y): Point) -> bool {
+    let mut inside = false;
+    let mut prev = match ring.last() {
+        Some(p) => *p,
+        None => return false,
+    };
+    for &(px, py) in ring {
+        let (qx, qy) = prev;
+        if (py > y) != (qy > y) && x < (qx - px) * (y - py) / (qy - py) + px {
+            inside = !inside;
+        }
+        prev = (px, py);
+    }
+    inside
+}
+
+#[test]
+fn polygon_with_hole() {
+    let geometry = serde_json::json!({
+        "type": "Polygon",
+        "coordinates": [
+            [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]],
+            [[4.0, 4.0], [6.0, 4.0], [6.0, 6.0], [4.0, 6.0], [4.0, 4.0]],
+        ],
+    });
+    let area = MultiPolygon::from_geojson(&geometry).unwrap();
+    assert!(area.contains((2.0, 3.0)));
+    assert!(!area.contains((5.0, 5.0)));
+    assert!(!area.contains((11.0, 5.0)));
+    assert_eq!(area.bbox(), Some(((0.0, 0.0), (10.0, 10.0))));
+}
+
+#[test]
+fn distance_stockholm_uppsala() {
+    let d = distance((18.0686, 59.3293), (17.6389, 59.8586));
+    assert!((d - 63_630.).abs() < 10., "Got {d}");
+}
@@ -0,0 +1,154 @@
+//! Write positions of photos as GeoJSON, KML or GPX.
+use crate::models::Coord;
+use crate::schema::photos;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as ps;
+use crate::server::PrivacyZones;
+use crate::templates;
+use chrono::NaiveDateTime;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde_json::{Value, json};
+use std::io::{self, Write};
+
+/// A file format for exported positions.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Geojson,
+    Kml,
+    Gpx,
+}
+
+impl Format {
+    /// Get the format for a file name extension.
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match ext.to_ascii_lowercase().as_str() {
+            "geojson" | "json" => Some(Format::Geojson),
+            "kml" => Some(Format::Kml),
+            "gpx" => Some(Format::Gpx),
+            _ => None,
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Geojson => "application/geo+json",
+            Format::Kml => "application/vnd.google-earth.kml+xml",
+            Format::Gpx => "application/gpx+xml",
+        }
+    }
+}
+
+/// A photo with a position, to export.
+pub struct GeoPhoto {
+    pub id: i32,
+    pub date: Option<NaiveDateTime>,
+    pub pos: Coord,
+    /// Url of the photo details page.
+    pub url: String,
+    /// Url of a small version of the photo.
+    pub thumbnail: String,
+}
+
+impl GeoPhoto {
+    /// Load the photos that has a position from a photo query.
+    ///
+    /// The photos are ordered by time.  Paths are made into urls by
+    /// the `url` function.  Positions in privacy `zones` are hidden or
+    /// coarsened.
+    pub async fn load(
+        photos: photos::BoxedQuery<'_, Pg>,
+        url: impl Fn(&str) -> String,
+        zones: &PrivacyZones,
+        db: &mut AsyncPgConnection,
+    ) -> Result<Vec<GeoPhoto>, diesel::result::Error> {
+        Ok(photos
+            .inner_join(ps::positions)
+            .select((p::id, p::date, (ps::latitude, ps::longitude)))
+            .order((p::date.asc().nulls_last(), p::id))
+            .load::<(i32, Option<NaiveDateTime>, Coord)>(db)
+            .await?
+            .into_iter()
+            .filter_map(|(id, date, pos)| {
+                Some(GeoPhoto {
+                    id,
+                    date,
+                    pos: zones.apply(pos)?,
+                    url: url(&format!("/img/{id}")),
+                    thumbnail: url(&format!("/img/{id}-s.jpg")),
+                })
+            })
+            .collect())
+    }
+
+    /// A name for the photo, the date if known.
+    pub fn name(&self) -> String {
+        match self.date {
+            Some(date) => date.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => format!("#{}", self.id),
+        }
+    }
+
+    /// The date, in xml schema format.
+    ///
+    /// The time zone of photo dates is not known, so none is given.
+    pub fn xml_time(&self) -> Option<String> {
+        self.date.map(|d| d.format("%Y-%m-%dT%H:%M:%S").to_string())
+    }
+}
+
+/// Write `photos` in a format.
+pub fn write(
+    format: Format,
+    photos: &[GeoPhoto],
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    match format {
+        Format::Geojson => {
+            serde_json::to_writer(&mut *out, &geojson(photos))?;
+            writeln!(out)
+        }
+        Format::Kml => templates::positions_kml_xml(out, photos),
+        Format::Gpx => templates::positions_gpx_xml(out, photos),
+    }
+}
+
+fn geojson(photos: &[GeoPhoto]) -> Value {
+    let features = photos
+        .iter()
+        .map(|photo| {
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [photo.pos.y, photo.pos.x],
+                },
+                "properties": {
+                    "id": photo.id,
+                    "name": photo.name(),
+                    "date": photo.xml_time(),
+                    "url": photo.url,
+                    "thumbnail": photo.thumbnail,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+#[test]
+fn geojson_feature() {
+    let photo = GeoPhoto {
+        id: 17,
+        date: chrono::NaiveDate::from_ymd_opt(2024, 7, 1)
+            .and_then(|d| d.and_hms_opt(12, 30, 0)),
+        pos: Coord { x: 59.3, y: 18.1 },
+        url: "/img/17".into(),
+        thumbnail: "/img/17-s.jpg".into(),
+    };
+    let json = geojson(&[photo]);
+    let feature = &json["features"][0];
+    assert_eq!(feature["geometry"]["coordinates"], json!([18.1, 59.3]));
+    assert_eq!(feature["properties"]["date"], "2024-07-01T12:30:00");
+    assert_eq!(feature["properties"]["thumbnail"], "/img/17-s.jpg");
+}
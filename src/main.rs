@@ -1,18 +1,33 @@
 #![allow(proc_macro_derive_resolution_fallback)]
 #![recursion_limit = "128"]
 mod adm;
+mod clockoffset;
 mod dbopt;
+mod duplicates;
+mod fences;
 mod fetch_places;
+mod geo;
+mod geoexport;
 mod models;
 mod myexif;
+mod pathdate;
 mod photosdir;
 mod pidfiles;
+mod place_class;
+mod place_queue;
 mod schema;
 mod server;
+mod stacks;
+mod tracks;
+mod xmp;
 
 use crate::adm::result::Error;
 use crate::adm::stats::show_stats;
-use crate::adm::{findphotos, makepublic, precache, storestatics, users};
+use crate::adm::{
+    dump, exportpositions, exportstatic, exportxmp, findduplicates,
+    findphotos, geotag, importareas, interpolate, makepublic, placeparents,
+    precache, reclassify, storestatics, timeshift, users,
+};
 use crate::dbopt::DbOpt;
 use clap::Parser;
 use dotenv::dotenv;
@@ -27,23 +42,90 @@ enum RPhotos {
     ///
     /// The image path(s) are relative to the image root.
     Makepublic(makepublic::Makepublic),
+    /// Dump all curated metadata as JSON lines.
+    ///
+    /// Includes photos (by path) with tags, people, places,
+    /// positions, grades, rotation, attribution and public flag, and
+    /// users.
+    Dump(dump::Dump),
+    /// List clusters of duplicate or nearly duplicate photos.
+    ///
+    /// Photos are compared by a perceptual hash, computed by
+    /// `findphotos` and `precache`.  With `--merge`, all but the
+    /// largest photo of each cluster are hidden.
+    Duplicates(findduplicates::Duplicates),
+    /// Export positions of photos as GeoJSON, KML or GPX.
+    ///
+    /// Photos can be selected by tags, people, places and dates.  The
+    /// features include the date and links to the photo and a
+    /// thumbnail.  The GPX track is ordered by time.
+    ExportPositions(exportpositions::ExportPositions),
+    /// Export the public photos as a static site.
+    ///
+    /// Pages and scaled images are written to a directory that can be
+    /// served by any web server.  Existing scaled images are kept.
+    ExportStatic(exportstatic::Args),
+    /// Write tags, people, places, grade, position and public flag
+    /// to xmp sidecar files.
+    ///
+    /// Sidecars are named as the photo with `.xmp` appended, either
+    /// next to the photos or in a separate directory.  Existing
+    /// sidecars written by other tools are not overwritten unless
+    /// forced.
+    ExportXmp(exportxmp::Args),
     /// Get place tags for photos by looking up coordinates in OSM
     Fetchplaces(fetch_places::Fetchplaces),
     /// Find new photos in the photo directory
     Findphotos(findphotos::Findphotos),
+    /// Set positions for photos from gpx, kml or geojson tracks.
+    ///
+    /// Photos are matched to the tracks by time, adjusted by a clock
+    /// offset for the camera.
+    Geotag(geotag::Geotag),
+    /// Import areas from OpenStreetMap, for finding places locally.
+    ///
+    /// With the areas imported, `--local-areas` can be used instead
+    /// of an overpass url, e.g. for `fetchplaces` and `runserver`.
+    ImportAreas(importareas::ImportAreas),
+    /// Infer positions for photos from photos taken close in time.
+    ///
+    /// For photos lacking a position, the position is interpolated
+    /// between the nearest photos before and after that has a known
+    /// position.
+    Interpolate(interpolate::Interpolate),
+    /// Find the parent of each place, for the place tree.
+    ///
+    /// The parent is the most specific larger place that most photos
+    /// of the place are also in.  This is done automatically after
+    /// fetching places.
+    PlaceParents(placeparents::PlaceParents),
     /// Make sure the photos has thumbnails stored in cache.
     ///
+    /// Perceptual hashes, for finding duplicates and stacks of similar
+    /// photos, are also computed for photos that lacks them.
+    ///
     /// The time limit is checked after each stored image, so the
     /// command will complete in slightly more than the max time and
     /// one image will be processed even if the max time is zero.
     Precache(precache::Args),
+    /// Update the names and levels of places after changing the
+    /// place classification.
+    ///
+    /// Uses the osm tags stored when the places were fetched, so
+    /// places fetched by older versions are not changed.
+    Reclassify(reclassify::Reclassify),
     /// Show some statistics from the database
     Stats(DbOpt),
     /// Store statics as files for a web server
     Storestatics {
         /// Directory to store the files in
-        dir: String,
+        dir: PathBuf,
     },
+    /// Shift the dates of photos, to correct a camera clock.
+    ///
+    /// Select the photos by camera, path and/or date.  The shift is
+    /// kept when `findphotos` reads the dates from exif again.
+    Timeshift(timeshift::Timeshift),
     /// List existing users
     Userlist {
         #[clap(flatten)]
@@ -57,6 +139,11 @@ enum RPhotos {
         // TODO: Use a special type that only accepts nice user names.
         user: String,
     },
+    /// Restore metadata from a dump.
+    ///
+    /// Photos are matched by path.  Restoring the same dump again
+    /// changes nothing.
+    Restore(dump::Restore),
     /// Run the rphotos web server.
     Runserver(server::Args),
 }
@@ -108,8 +195,20 @@ async fn run(args: &RPhotos) -> Result<(), Error> {
             users::passwd(&mut db.connect().await?, user).await
         }
         RPhotos::Fetchplaces(cmd) => cmd.run().await,
+        RPhotos::Geotag(cmd) => cmd.run().await,
+        RPhotos::ImportAreas(cmd) => cmd.run().await,
+        RPhotos::Interpolate(cmd) => cmd.run().await,
+        RPhotos::Timeshift(cmd) => cmd.run().await,
+        RPhotos::PlaceParents(cmd) => cmd.run().await,
         RPhotos::Precache(cmd) => cmd.run().await,
+        RPhotos::Reclassify(cmd) => cmd.run().await,
         RPhotos::Storestatics { dir } => storestatics::to_dir(dir),
+        RPhotos::Dump(cmd) => cmd.run().await,
+        RPhotos::Duplicates(cmd) => cmd.run().await,
+        RPhotos::Restore(cmd) => cmd.run().await,
+        RPhotos::ExportPositions(cmd) => cmd.run().await,
+        RPhotos::ExportStatic(cmd) => cmd.run().await,
+        RPhotos::ExportXmp(cmd) => cmd.run().await,
         RPhotos::Runserver(ra) => server::run(ra).await,
     }
 }
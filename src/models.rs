@@ -1,6 +1,7 @@
 use crate::schema::attributions::dsl as a;
 use crate::schema::cameras;
 use crate::schema::cameras::dsl as c;
+use crate::schema::people;
 use crate::schema::people::dsl as h;
 use crate::schema::photo_people::dsl as ph;
 use crate::schema::photo_places::dsl as pl;
@@ -10,7 +11,11 @@ use crate::schema::photos::dsl as p;
 use crate::schema::places::dsl as l;
 use crate::schema::positions::dsl as pos;
 use crate::schema::tags::dsl as t;
+use crate::stacks::PhotoStack;
 use chrono::naive::NaiveDateTime;
+use chrono::{
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveTime, TimeDelta, Utc,
+};
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::result::Error;
@@ -22,11 +27,18 @@ use std::cmp::max;
 pub struct PhotoDetails {
     photo: Photo,
     pub people: Vec<Person>,
+    /// Number of people not named, see [`PhotoDetails::hide_people`].
+    pub unnamed_people: usize,
     pub places: Vec<Place>,
     pub tags: Vec<Tag>,
     pub pos: Option<Coord>,
+    /// True if the position is interpolated from other photos.
+    pub pos_inferred: bool,
+    pub pos_details: PosDetails,
     pub attribution: Option<String>,
     pub camera: Option<Camera>,
+    /// The stack of similar photos this photo is in, if any.
+    pub stack: Option<PhotoStack>,
 }
 impl PhotoDetails {
     pub async fn load(
@@ -46,6 +58,16 @@ impl PhotoDetails {
             None
         };
 
+        let pos = pos::positions
+            .filter(pos::photo_id.eq(id))
+            .select((
+                (pos::latitude, pos::longitude),
+                pos::inferred,
+                PosDetails::as_select(),
+            ))
+            .first::<(Coord, bool, PosDetails)>(db)
+            .await
+            .optional()?;
         Ok(PhotoDetails {
             photo,
             people: h::people
@@ -58,6 +80,7 @@ impl PhotoDetails {
                 )
                 .load(db)
                 .await?,
+            unnamed_people: 0,
             places: l::places
                 .filter(
                     l::id.eq_any(
@@ -79,16 +102,28 @@ impl PhotoDetails {
                 )
                 .load(db)
                 .await?,
-            pos: pos::positions
-                .filter(pos::photo_id.eq(id))
-                .select((pos::latitude, pos::longitude))
-                .first(db)
-                .await
-                .optional()?,
+            pos_inferred: pos
+                .as_ref()
+                .is_some_and(|(_, inferred, _)| *inferred),
+            pos_details: pos.as_ref().map(|(_, _, d)| *d).unwrap_or_default(),
+            pos: pos.map(|(pos, _, _)| pos),
             attribution,
             camera,
+            stack: PhotoStack::load(db, id).await?,
         })
     }
+
+    /// Remove people that should not be named to anonymous viewers.
+    ///
+    /// Anonymous people are counted in `unnamed_people`.
+    pub fn hide_people(&mut self) {
+        self.unnamed_people = self
+            .people
+            .iter()
+            .filter(|p| p.visibility() == Visibility::Anonymous)
+            .count();
+        self.people.retain(Person::is_public);
+    }
 }
 
 impl std::ops::Deref for PhotoDetails {
@@ -110,6 +145,22 @@ pub struct Photo {
     pub attribution_id: Option<i32>,
     pub width: i32,
     pub height: i32,
+    pub description: Option<String>,
+    /// Newest modification time of the xmp sources when last read.
+    pub xmp_mtime: Option<DateTime<Utc>>,
+    /// Utc offset in minutes of the local `date`, if known.
+    pub utc_offset: Option<i16>,
+    /// The instant the photo was taken, if the utc offset of `date`
+    /// is known.
+    pub date_utc: Option<DateTime<Utc>>,
+    /// Seconds that `date` is shifted from the exif date.
+    pub date_shift: i32,
+    /// How exact `date` is, see [`DatePrecision`].
+    pub date_precision: i16,
+    /// Where the date came from, see [`DateSource`].
+    pub date_source: Option<String>,
+    /// The primary photo, if this is a hidden duplicate.
+    pub duplicate_of: Option<i32>,
 }
 
 #[derive(Debug)]
@@ -125,6 +176,26 @@ impl Photo {
         self.is_public
     }
 
+    /// The date with the utc offset where the photo was taken, if known.
+    pub fn local_date(&self) -> Option<DateTime<FixedOffset>> {
+        let offset = FixedOffset::east_opt(i32::from(self.utc_offset?) * 60)?;
+        self.date?.and_local_timezone(offset).single()
+    }
+
+    /// True for raw files, which are not shown, see [`Photo::query`].
+    pub fn is_raw(&self) -> bool {
+        self.path.ends_with(".CR2") || self.path.ends_with(".dng")
+    }
+
+    pub fn precision(&self) -> DatePrecision {
+        DatePrecision::from_i16(self.date_precision)
+    }
+
+    /// The date, formatted according to its precision.
+    pub fn date_text(&self) -> Option<String> {
+        self.date.map(|d| self.precision().format(d))
+    }
+
     pub fn cache_key(&self, size: SizeTag) -> String {
         format!("rp{}{:?}", self.id, size)
     }
@@ -134,6 +205,7 @@ impl Photo {
         let result = p::photos
             .filter(p::path.not_like("%.CR2"))
             .filter(p::path.not_like("%.dng"))
+            .filter(p::duplicate_of.is_null())
             .into_boxed();
         if auth {
             result
@@ -142,12 +214,22 @@ impl Photo {
         }
     }
 
+    /// True if the current date should be kept rather than replaced
+    /// by a date from `source`.
+    fn keeps_date(&self, source: DateSource) -> bool {
+        match self.date_source.as_deref() {
+            Some("manual") => true,
+            Some("path") => false,
+            _ => self.date.is_some() && source == DateSource::Path,
+        }
+    }
+
     pub async fn update_by_path(
         db: &mut AsyncPgConnection,
         file_path: &str,
         newwidth: i32,
         newheight: i32,
-        exifdate: Option<NaiveDateTime>,
+        newdate: Option<PhotoDate>,
         camera: &Option<Camera>,
     ) -> Result<Option<Modification<Photo>>, Error> {
         if let Some(mut pic) = p::photos
@@ -165,12 +247,26 @@ impl Photo {
                     .get_result::<Photo>(db)
                     .await?;
             }
-            if exifdate.is_some() && exifdate != pic.date {
-                change = true;
-                pic = diesel::update(p::photos.find(pic.id))
-                    .set(p::date.eq(exifdate))
-                    .get_result::<Photo>(db)
-                    .await?;
+            if let Some(mut newdate) = newdate
+                && !pic.keeps_date(newdate.source)
+            {
+                // Keep corrections of the camera clock.
+                newdate.date += TimeDelta::seconds(pic.date_shift.into());
+                if pic.date != Some(newdate.date)
+                    || pic.precision() != newdate.precision
+                    || pic.date_source.as_deref()
+                        != Some(newdate.source.as_str())
+                {
+                    change = true;
+                    pic = diesel::update(p::photos.find(pic.id))
+                        .set((
+                            p::date.eq(newdate.date),
+                            p::date_precision.eq(newdate.precision.as_i16()),
+                            p::date_source.eq(newdate.source.as_str()),
+                        ))
+                        .get_result::<Photo>(db)
+                        .await?;
+                }
             }
             if let Some(ref camera) = *camera
                 && pic.camera_id != Some(camera.id)
@@ -196,12 +292,12 @@ impl Photo {
         file_path: &str,
         newwidth: i32,
         newheight: i32,
-        exifdate: Option<NaiveDateTime>,
+        newdate: Option<PhotoDate>,
         exifrotation: i16,
         camera: Option<Camera>,
     ) -> Result<Modification<Photo>, Error> {
         if let Some(result) = Self::update_by_path(
-            db, file_path, newwidth, newheight, exifdate, &camera,
+            db, file_path, newwidth, newheight, newdate, &camera,
         )
         .await?
         {
@@ -210,7 +306,10 @@ impl Photo {
             let pic = diesel::insert_into(p::photos)
                 .values((
                     p::path.eq(file_path),
-                    p::date.eq(exifdate),
+                    p::date.eq(newdate.map(|d| d.date)),
+                    p::date_precision
+                        .eq(newdate.map_or(0, |d| d.precision.as_i16())),
+                    p::date_source.eq(newdate.map(|d| d.source.as_str())),
                     p::rotation.eq(exifrotation),
                     p::width.eq(newwidth),
                     p::height.eq(newheight),
@@ -222,6 +321,125 @@ impl Photo {
         }
     }
 
+    /// Limit a query to photos with an exact date from exif, the
+    /// dates that a camera clock correction applies to.
+    pub fn exif_dated(
+        query: photos::BoxedQuery<'_, Pg>,
+    ) -> photos::BoxedQuery<'_, Pg> {
+        query
+            .filter(p::date.is_not_null())
+            .filter(p::date_source.eq(DateSource::Exif.as_str()))
+            .filter(p::date_precision.eq(DatePrecision::Exact.as_i16()))
+    }
+
+    /// Shift the date of some photos, to correct a camera clock.
+    ///
+    /// Only exact dates from exif are shifted, manual and approximate
+    /// dates are kept.  The shift is recorded, so it is kept when the
+    /// photos are found again.  Returns the number of photos changed.
+    pub async fn shift_dates(
+        db: &mut AsyncPgConnection,
+        ids: &[i32],
+        shift: TimeDelta,
+    ) -> Result<usize, Error> {
+        diesel::update(p::photos.filter(p::id.eq_any(ids)))
+            .filter(p::date.is_not_null())
+            .filter(p::date_source.eq(DateSource::Exif.as_str()))
+            .filter(p::date_precision.eq(DatePrecision::Exact.as_i16()))
+            .set((
+                p::date.eq(p::date + shift),
+                p::date_shift.eq(p::date_shift + shift.num_seconds() as i32),
+            ))
+            .execute(db)
+            .await
+    }
+
+    /// Find a position by interpolating between photos taken close
+    /// in time.
+    ///
+    /// The nearest photos before and after this one (within `window`)
+    /// that has a known (not inferred) position are used.
+    pub async fn interpolate_position(
+        &self,
+        db: &mut AsyncPgConnection,
+        window: TimeDelta,
+    ) -> Result<Option<Coord>, Error> {
+        if let Some(utc) = self.date_utc
+            && let Some(coord) =
+                self.interpolate_by_instant(db, utc, window).await?
+        {
+            return Ok(Some(coord));
+        }
+        let Some(date) = self.date else {
+            return Ok(None);
+        };
+        let known = || {
+            p::photos
+                .inner_join(pos::positions)
+                .filter(pos::inferred.eq(false))
+                .filter(p::id.ne(self.id))
+                .select((
+                    p::date.assume_not_null(),
+                    (pos::latitude, pos::longitude),
+                ))
+        };
+        let before = known()
+            .filter(p::date.le(date))
+            .filter(p::date.ge(date - window))
+            .order(p::date.desc())
+            .first::<(NaiveDateTime, Coord)>(db)
+            .await
+            .optional()?;
+        let after = known()
+            .filter(p::date.ge(date))
+            .filter(p::date.le(date + window))
+            .order(p::date.asc())
+            .first::<(NaiveDateTime, Coord)>(db)
+            .await
+            .optional()?;
+        Ok(interpolate(date, before, after))
+    }
+
+    /// Interpolate from photos where the instant is known, so photos
+    /// taken in another time zone are matched correctly.
+    async fn interpolate_by_instant(
+        &self,
+        db: &mut AsyncPgConnection,
+        utc: DateTime<Utc>,
+        window: TimeDelta,
+    ) -> Result<Option<Coord>, Error> {
+        let known = || {
+            p::photos
+                .inner_join(pos::positions)
+                .filter(pos::inferred.eq(false))
+                .filter(p::id.ne(self.id))
+                .select((
+                    p::date_utc.assume_not_null(),
+                    (pos::latitude, pos::longitude),
+                ))
+        };
+        let before = known()
+            .filter(p::date_utc.le(utc))
+            .filter(p::date_utc.ge(utc - window))
+            .order(p::date_utc.desc())
+            .first::<(DateTime<Utc>, Coord)>(db)
+            .await
+            .optional()?;
+        let after = known()
+            .filter(p::date_utc.ge(utc))
+            .filter(p::date_utc.le(utc + window))
+            .order(p::date_utc.asc())
+            .first::<(DateTime<Utc>, Coord)>(db)
+            .await
+            .optional()?;
+        let naive = |(d, c): (DateTime<Utc>, Coord)| (d.naive_utc(), c);
+        Ok(interpolate(
+            utc.naive_utc(),
+            before.map(naive),
+            after.map(naive),
+        ))
+    }
+
     pub fn get_size(&self, size: SizeTag) -> (u32, u32) {
         let (width, height) = (self.width, self.height);
         let scale = f64::from(size.px()) / f64::from(max(width, height));
@@ -250,6 +468,14 @@ impl Photo {
             attribution_id: None,
             width: 4000,
             height: 3000,
+            description: None,
+            xmp_mtime: None,
+            utc_offset: None,
+            date_utc: None,
+            date_shift: 0,
+            date_precision: 0,
+            date_source: None,
+            duplicate_of: None,
         }
     }
 }
@@ -271,6 +497,27 @@ pub struct Tag {
     pub tag_name: String,
 }
 
+impl Tag {
+    pub async fn get_or_create_name(
+        db: &mut AsyncPgConnection,
+        name: &str,
+    ) -> Result<Tag, Error> {
+        if let Some(tag) = t::tags
+            .filter(t::tag_name.ilike(name))
+            .first(db)
+            .await
+            .optional()?
+        {
+            Ok(tag)
+        } else {
+            diesel::insert_into(t::tags)
+                .values((t::tag_name.eq(name), t::slug.eq(&slugify(name))))
+                .get_result(db)
+                .await
+        }
+    }
+}
+
 impl Facet for Tag {
     async fn load_slugs(
         slugs: &[String],
@@ -285,9 +532,27 @@ pub struct Person {
     pub id: i32,
     pub slug: String,
     pub person_name: String,
+    pub visibility: i16,
 }
 
 impl Person {
+    pub fn visibility(&self) -> Visibility {
+        Visibility::from_i16(self.visibility)
+    }
+    pub fn is_public(&self) -> bool {
+        self.visibility() == Visibility::Public
+    }
+
+    /// People that may be named to the viewer.
+    pub fn query<'a>(auth: bool) -> people::BoxedQuery<'a, Pg> {
+        let result = h::people.into_boxed();
+        if auth {
+            result
+        } else {
+            result.filter(h::visibility.eq(Visibility::Public.as_i16()))
+        }
+    }
+
     pub async fn get_or_create_name(
         db: &mut AsyncPgConnection,
         name: &str,
@@ -324,6 +589,28 @@ pub struct Place {
     pub place_name: String,
     pub osm_id: Option<i64>,
     pub osm_level: Option<i16>,
+    pub parent_id: Option<i32>,
+}
+
+impl Place {
+    /// Get the places containing this place, outermost first.
+    pub async fn ancestors(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> Result<Vec<Place>, Error> {
+        let mut result = Vec::<Place>::new();
+        let mut parent = self.parent_id;
+        while let Some(id) = parent {
+            if id == self.id || result.iter().any(|p| p.id == id) {
+                break; // Should not happen, but avoid looping forever.
+            }
+            let place = l::places.find(id).first::<Place>(db).await?;
+            parent = place.parent_id;
+            result.push(place);
+        }
+        result.reverse();
+        Ok(result)
+    }
 }
 
 impl Facet for Place {
@@ -365,7 +652,58 @@ impl Camera {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Interpolate a position at `date` between two known positions.
+///
+/// If only one is known, that is used.
+fn interpolate(
+    date: NaiveDateTime,
+    before: Option<(NaiveDateTime, Coord)>,
+    after: Option<(NaiveDateTime, Coord)>,
+) -> Option<Coord> {
+    match (before, after) {
+        (Some((t0, c0)), Some((t1, c1))) if t1 > t0 => {
+            let f = (date - t0).num_milliseconds() as f64
+                / (t1 - t0).num_milliseconds() as f64;
+            Some(Coord {
+                x: c0.x + f * (c1.x - c0.x),
+                y: c0.y + f * (c1.y - c0.y),
+            })
+        }
+        (Some((_, c)), _) | (None, Some((_, c))) => Some(c),
+        (None, None) => None,
+    }
+}
+
+/// Details of a gps position, as recorded by the camera.
+#[derive(
+    AsChangeset,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Insertable,
+    PartialEq,
+    Queryable,
+    Selectable,
+    serde::Serialize,
+)]
+#[diesel(table_name = crate::schema::positions, treat_none_as_null = true)]
+pub struct PosDetails {
+    /// Meters above sea level.
+    pub altitude: Option<f32>,
+    /// Estimated horizontal error, in meters.
+    pub accuracy: Option<f32>,
+    /// Compass direction of the camera, in degrees from north.
+    pub direction: Option<f32>,
+}
+
+impl PosDetails {
+    pub fn is_empty(&self) -> bool {
+        *self == PosDetails::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Coord {
     pub x: f64,
     pub y: f64,
@@ -388,6 +726,163 @@ impl From<(i32, i32)> for Coord {
     }
 }
 
+/// Who may see that a person is on a photo.
+///
+/// Authorized users always see everyone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    /// Named on public pages.
+    Public,
+    /// Counted, but not named, on public pages.
+    Anonymous,
+    /// Not shown at all on public pages.
+    Hidden,
+}
+
+impl Visibility {
+    pub const ALL: [Visibility; 3] = [
+        Visibility::Public,
+        Visibility::Anonymous,
+        Visibility::Hidden,
+    ];
+
+    pub fn from_i16(value: i16) -> Self {
+        match value {
+            0 => Visibility::Public,
+            1 => Visibility::Anonymous,
+            // Unknown values are handled as the most private.
+            _ => Visibility::Hidden,
+        }
+    }
+    pub fn as_i16(self) -> i16 {
+        self as i16
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Anonymous => "anonymous",
+            Visibility::Hidden => "hidden",
+        }
+    }
+}
+
+impl std::str::FromStr for Visibility {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Visibility::ALL
+            .into_iter()
+            .find(|v| v.name() == s)
+            .ok_or("unknown visibility")
+    }
+}
+
+/// A date for a photo, with its precision and where it was found.
+#[derive(Clone, Copy, Debug)]
+pub struct PhotoDate {
+    pub date: NaiveDateTime,
+    pub precision: DatePrecision,
+    pub source: DateSource,
+}
+
+/// Where the date of a photo came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DateSource {
+    Exif,
+    Path,
+    Manual,
+}
+
+impl DateSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DateSource::Exif => "exif",
+            DateSource::Path => "path",
+            DateSource::Manual => "manual",
+        }
+    }
+}
+
+/// How exact the date of a photo is known.
+///
+/// Approximate dates are set manually, e.g. for scanned prints, and
+/// are stored as the start of the period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DatePrecision {
+    Exact,
+    Day,
+    Month,
+    Year,
+    Decade,
+}
+
+impl DatePrecision {
+    pub const ALL: [DatePrecision; 5] = [
+        DatePrecision::Exact,
+        DatePrecision::Day,
+        DatePrecision::Month,
+        DatePrecision::Year,
+        DatePrecision::Decade,
+    ];
+
+    pub fn from_i16(value: i16) -> Self {
+        match value {
+            1 => DatePrecision::Day,
+            2 => DatePrecision::Month,
+            3 => DatePrecision::Year,
+            4 => DatePrecision::Decade,
+            _ => DatePrecision::Exact,
+        }
+    }
+    pub fn as_i16(self) -> i16 {
+        self as i16
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            DatePrecision::Exact => "exact",
+            DatePrecision::Day => "day",
+            DatePrecision::Month => "month",
+            DatePrecision::Year => "year",
+            DatePrecision::Decade => "decade",
+        }
+    }
+
+    /// The start of the period of this precision containing `date`.
+    pub fn truncate(self, date: NaiveDateTime) -> NaiveDateTime {
+        let day = date.date();
+        let day = match self {
+            DatePrecision::Exact => return date,
+            DatePrecision::Day => day,
+            DatePrecision::Month => day.with_day(1).unwrap(),
+            DatePrecision::Year => day.with_ordinal(1).unwrap(),
+            DatePrecision::Decade => {
+                NaiveDate::from_ymd_opt(day.year() - day.year() % 10, 1, 1)
+                    .unwrap_or(day)
+            }
+        };
+        day.and_time(NaiveTime::MIN)
+    }
+
+    pub fn format(self, date: NaiveDateTime) -> String {
+        match self {
+            DatePrecision::Exact => date.format("%F %T").to_string(),
+            DatePrecision::Day => date.format("%F").to_string(),
+            DatePrecision::Month => date.format("%B %Y").to_string(),
+            DatePrecision::Year => date.format("%Y").to_string(),
+            DatePrecision::Decade => format!("{}s", date.year()),
+        }
+    }
+}
+
+impl std::str::FromStr for DatePrecision {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DatePrecision::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or("unknown date precision")
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SizeTag {
     Small,
@@ -411,3 +906,42 @@ impl SizeTag {
         }
     }
 }
+
+#[test]
+fn interpolate_between_photos() {
+    let t = |h, m| {
+        NaiveDate::from_ymd_opt(2019, 5, 3)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+    };
+    let c = |x, y| Coord { x, y };
+    let pos = interpolate(
+        t(12, 15),
+        Some((t(12, 0), c(59.0, 18.0))),
+        Some((t(13, 0), c(60.0, 20.0))),
+    )
+    .unwrap();
+    assert_eq!((pos.x, pos.y), (59.25, 18.5));
+    let pos = interpolate(t(12, 15), None, Some((t(13, 0), c(60.0, 20.0))));
+    assert_eq!(pos.map(|p| (p.x, p.y)), Some((60.0, 20.0)));
+    assert!(interpolate(t(12, 15), None, None).is_none());
+}
+
+#[test]
+fn truncate_and_format_dates() {
+    let d = NaiveDate::from_ymd_opt(1978, 7, 14)
+        .unwrap()
+        .and_hms_opt(15, 30, 0)
+        .unwrap();
+    let t = |p: DatePrecision| p.format(p.truncate(d));
+    assert_eq!(t(DatePrecision::Exact), "1978-07-14 15:30:00");
+    assert_eq!(t(DatePrecision::Day), "1978-07-14");
+    assert_eq!(t(DatePrecision::Month), "July 1978");
+    assert_eq!(t(DatePrecision::Year), "1978");
+    assert_eq!(t(DatePrecision::Decade), "1970s");
+    assert_eq!(
+        DatePrecision::Decade.truncate(d).to_string(),
+        "1970-01-01 00:00:00"
+    );
+}
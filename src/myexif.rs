@@ -1,6 +1,10 @@
 //! Extract all the exif data I care about
 use crate::adm::result::Error;
-use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use crate::clockoffset::ClockOffset;
+use crate::models::PosDetails;
+use chrono::{
+    Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone,
+};
 use exif::{Field, In, Reader, Tag, Value};
 use std::fs::File;
 use std::io::BufReader;
@@ -11,6 +15,8 @@ use tracing::{debug, error, instrument, warn};
 #[derive(Debug, Default)]
 pub struct ExifData {
     dateval: Option<NaiveDateTime>,
+    /// Utc offset of dateval, from OffsetTimeOriginal or OffsetTime.
+    offset: Option<ClockOffset>,
     /// Combines with gpstime to a datetime which is Utc.
     gpsdate: Option<NaiveDate>,
     /// Combines with gpstime to a datetime which is Utc.
@@ -24,6 +30,11 @@ pub struct ExifData {
     longval: Option<f64>,
     latref: Option<String>,
     longref: Option<String>,
+    altval: Option<f64>,
+    /// 1 if the altitude is below sea level.
+    altref: Option<u32>,
+    accuracy: Option<f64>,
+    direction: Option<f64>,
 }
 
 impl ExifData {
@@ -44,6 +55,12 @@ impl ExifData {
                     if result.dateval.is_none() {
                         result.dateval = Some(d);
                     }
+                } else if let Some(o) = is_offset(f, Tag::OffsetTimeOriginal) {
+                    result.offset = Some(o);
+                } else if let Some(o) = is_offset(f, Tag::OffsetTime) {
+                    if result.offset.is_none() {
+                        result.offset = Some(o);
+                    }
                 } else if let Some(s) = is_string(f, Tag::Make) {
                     result.make = Some(s.to_string());
                 } else if let Some(s) = is_string(f, Tag::Model) {
@@ -66,12 +83,16 @@ impl ExifData {
                     result.latref = Some(s.to_string());
                 } else if let Some(s) = is_string(f, Tag::GPSLongitudeRef) {
                     result.longref = Some(s.to_string());
-                /*
-                } else if let Some(s) = is_string(f, Tag::GPSImgDirectionRef) {
-                    println!("  direction ref: {}", s);
-                } else if let Some(s) = is_string(f, Tag::GPSImgDirection) {
-                    println!("  direction: {}", s);
-                */
+                } else if let Some(alt) = is_rational(f, Tag::GPSAltitude) {
+                    result.altval = Some(alt);
+                } else if let Some(r) = is_u32(f, Tag::GPSAltitudeRef) {
+                    result.altref = Some(r);
+                } else if let Some(e) =
+                    is_rational(f, Tag::GPSHPositioningError)
+                {
+                    result.accuracy = Some(e);
+                } else if let Some(d) = is_rational(f, Tag::GPSImgDirection) {
+                    result.direction = Some(d);
                 } else if let Some(d) = is_date(f, Tag::GPSDateStamp) {
                     result.gpsdate = Some(d);
                 } else if let Some(hms) = is_time(f, Tag::GPSTimeStamp) {
@@ -83,30 +104,42 @@ impl ExifData {
         Ok(result)
     }
 
+    /// The local time where the photo was taken.
     pub fn date(&self) -> Option<NaiveDateTime> {
-        // Note: I probably should return and store datetime with tz,
-        // possibly utc, instead.
-        // Also note: I used to prefer the gps date, as I belived that
+        // Note: I used to prefer the gps date, as I belived that
         // to be more exact if present.  But at last one phone seems
         // to stick "the date of last time we had a gps position"
         // there rather than none if there is no "current" gps data.
         // So instead, use the gps date only as a fallback.
         if let Some(date) = self.dateval {
             Some(date)
-        } else if let (&Some(date), &Some(time)) =
-            (&self.gpsdate, &self.gpstime)
-        {
+        } else if let Some(utc) = self.gps_datetime() {
             // The gps date and time should always be utc.
-            // But time stored is in local time.
-            // Note: Sometimes (when traveling) local time for the
-            // picture may not be the same as local time for the
-            // server.  That is not properly handled here.
-            Some(Local.from_utc_datetime(&date.and_time(time)).naive_local())
+            // But time stored is in local time where the photo was
+            // taken (or at least local time for the server, if that
+            // is unknown).
+            match self.utc_offset() {
+                Some(offset) => Some(utc + offset.0),
+                None => Some(Local.from_utc_datetime(&utc).naive_local()),
+            }
         } else {
             warn!("No date found in exif");
             None
         }
     }
+
+    /// The utc offset of the local time where the photo was taken.
+    ///
+    /// Uses the exif offset tag if present, otherwise compares the
+    /// local time to the gps time.
+    pub fn utc_offset(&self) -> Option<ClockOffset> {
+        self.offset
+            .or_else(|| offset_from_gps(self.dateval?, self.gps_datetime()?))
+    }
+
+    fn gps_datetime(&self) -> Option<NaiveDateTime> {
+        Some(self.gpsdate?.and_time(self.gpstime?))
+    }
     pub fn camera(&self) -> Option<(&str, &str)> {
         if let (Some(make), Some(model)) = (&self.make, &self.model) {
             Some((make, model))
@@ -121,6 +154,22 @@ impl ExifData {
             None
         }
     }
+    /// Altitude, accuracy and direction of the position.
+    ///
+    /// The direction may be relative to magnetic north rather than
+    /// true north (if GPSImgDirectionRef is M), but the difference is
+    /// ignored.
+    pub fn position_details(&self) -> PosDetails {
+        let altitude = self.altval.map(|alt| match self.altref {
+            Some(1) => -alt,
+            _ => alt,
+        });
+        PosDetails {
+            altitude: altitude.map(|a| a as f32),
+            accuracy: self.accuracy.map(|a| a as f32),
+            direction: self.direction.map(|d| d.rem_euclid(360.) as f32),
+        }
+    }
     fn lat(&self) -> Option<f64> {
         match (&self.latref, self.latval) {
             (Some(r), Some(lat)) if r == "N" => Some(lat.abs()),
@@ -161,6 +210,37 @@ impl ExifData {
     }
 }
 
+/// Get an utc offset from the difference between local and gps time.
+///
+/// Real offsets are whole quarters of an hour, so if the difference
+/// is not close to that, the gps time is probably stale.
+fn offset_from_gps(
+    local: NaiveDateTime,
+    gps: NaiveDateTime,
+) -> Option<ClockOffset> {
+    let diff = (local - gps).num_seconds();
+    let quarters = (diff as f64 / 900.).round() as i64;
+    if (diff - quarters * 900).abs() <= 120 && quarters.abs() <= 14 * 4 {
+        Some(ClockOffset(TimeDelta::seconds(quarters * 900)))
+    } else {
+        None
+    }
+}
+
+fn is_offset(f: &Field, tag: Tag) -> Option<ClockOffset> {
+    if f.tag == tag {
+        single_ascii(&f.value)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or_else(|| {
+                error!("Expected utc offset for {tag}: {:?}", f.value);
+                None
+            })
+    } else {
+        None
+    }
+}
+
 fn is_lat_long(f: &Field, tag: Tag) -> Option<f64> {
     if f.tag == tag {
         match &f.value {
@@ -178,6 +258,22 @@ fn is_lat_long(f: &Field, tag: Tag) -> Option<f64> {
     }
 }
 
+fn is_rational(f: &Field, tag: Tag) -> Option<f64> {
+    if f.tag == tag {
+        match &f.value {
+            Value::Rational(v) if v.len() == 1 && v[0].denom != 0 => {
+                Some(v[0].to_f64())
+            }
+            v => {
+                error!("Bad value for {tag}: {v:?}");
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
 fn is_datetime(f: &Field, tag: Tag) -> Option<NaiveDateTime> {
     if f.tag == tag {
         single_ascii(&f.value)
@@ -251,6 +347,7 @@ fn is_u32(f: &Field, tag: Tag) -> Option<u32> {
         match &f.value {
             Value::Long(v) if v.len() == 1 => Some(v[0]),
             Value::Short(v) if v.len() == 1 => Some(u32::from(v[0])),
+            Value::Byte(v) if v.len() == 1 => Some(u32::from(v[0])),
             v => {
                 error!("Unsuppored value for {tag}: {v:?}");
                 None
@@ -279,3 +376,41 @@ fn single_ascii(value: &Value) -> Result<&str, Error> {
         ))),
     }
 }
+
+#[test]
+fn gps_offset() {
+    let t = |h, m, s| {
+        NaiveDate::from_ymd_opt(2019, 5, 3)
+            .unwrap()
+            .and_hms_opt(h, m, s)
+            .unwrap()
+    };
+    let secs = |o: Option<ClockOffset>| o.map(ClockOffset::seconds);
+    assert_eq!(
+        secs(offset_from_gps(t(14, 17, 3), t(12, 16, 58))),
+        Some(7200)
+    );
+    assert_eq!(
+        secs(offset_from_gps(t(10, 0, 0), t(15, 30, 30))),
+        Some(-19800)
+    );
+    assert_eq!(secs(offset_from_gps(t(14, 17, 3), t(9, 8, 0))), None);
+}
+
+#[test]
+fn below_sea_level_and_direction() {
+    let exif = ExifData {
+        altval: Some(12.5),
+        altref: Some(1),
+        direction: Some(365.),
+        ..ExifData::default()
+    };
+    assert_eq!(
+        exif.position_details(),
+        PosDetails {
+            altitude: Some(-12.5),
+            accuracy: None,
+            direction: Some(5.),
+        }
+    );
+}
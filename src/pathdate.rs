@@ -0,0 +1,160 @@
+//! Find dates in photo paths, for photos without an exif date.
+//!
+//! E.g. `IMG-20190704-WA0012.jpg` from a messaging app, or a scan
+//! stored as `1998/12 Julafton/scan01.jpg`.
+use crate::models::DatePrecision;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use regex::{Captures, Regex};
+use std::fmt;
+use std::path::Path;
+
+/// Patterns tried after any configured patterns.
+///
+/// The file name patterns are tried before the directory patterns.
+const DEFAULT_PATTERNS: &[&str] = &[
+    // IMG_20190704_153012.jpg, 2019-07-04 15.30.12.jpg
+    r"(?P<year>(?:19|20)\d\d)-?(?P<month>[01]\d)-?(?P<day>[0-3]\d)[ _T-](?P<hour>[0-2]\d)[.:]?(?P<minute>[0-5]\d)[.:]?(?P<second>[0-5]\d)[^/]*$",
+    // IMG-20190704-WA0012.jpg, 2019-07-04.jpg
+    r"(?P<year>(?:19|20)\d\d)-?(?P<month>[01]\d)-?(?P<day>[0-3]\d)[^/]*$",
+    // 1998/12 Julafton/scan01.jpg, 1998-12-24 Julafton/scan01.jpg
+    r"(?:^|/)(?P<year>(?:19|20)\d\d)[-/](?P<month>[01]?\d)(?:[-/](?P<day>[0-3]?\d))?\b[^/]*/",
+    // 1998/scan01.jpg, 1998 Sommar/scan01.jpg
+    r"(?:^|/)(?P<year>(?:19|20)\d\d)\b[^/]*/",
+];
+
+/// A list of regular expressions for dates in paths.
+///
+/// Each pattern must have a `year` group, and may have `month`,
+/// `day`, `hour`, `minute` and `second` groups.  The first match
+/// that is a valid date is used, and the precision of the date is
+/// given by the groups that matched.
+pub struct DatePatterns {
+    patterns: Vec<Regex>,
+}
+
+impl DatePatterns {
+    /// Get the default patterns, preceded by patterns from `file`.
+    ///
+    /// The file has one pattern per line.  Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(file: Option<&Path>) -> Result<Self, Error> {
+        let mut patterns = Vec::new();
+        if let Some(file) = file {
+            let data = std::fs::read_to_string(file).map_err(Error::Io)?;
+            for line in data.lines().map(str::trim) {
+                if !line.is_empty() && !line.starts_with('#') {
+                    patterns.push(checked_regex(line)?);
+                }
+            }
+        }
+        for pattern in DEFAULT_PATTERNS {
+            patterns.push(checked_regex(pattern)?);
+        }
+        Ok(DatePatterns { patterns })
+    }
+
+    /// Find a date in a path (relative to the photos directory).
+    pub fn date_for(
+        &self,
+        path: &str,
+    ) -> Option<(NaiveDateTime, DatePrecision)> {
+        self.patterns
+            .iter()
+            .filter_map(|re| re.captures(path))
+            .find_map(|caps| date_from(&caps))
+    }
+}
+
+fn checked_regex(pattern: &str) -> Result<Regex, Error> {
+    let re = Regex::new(pattern).map_err(Error::Regex)?;
+    if re.capture_names().any(|name| name == Some("year")) {
+        Ok(re)
+    } else {
+        Err(Error::NoYear(pattern.into()))
+    }
+}
+
+fn date_from(caps: &Captures) -> Option<(NaiveDateTime, DatePrecision)> {
+    let num = |name| -> Option<u32> {
+        caps.name(name).and_then(|m| m.as_str().parse().ok())
+    };
+    let year = num("year")?;
+    let (month, day) = (num("month"), num("day"));
+    let date = NaiveDate::from_ymd_opt(
+        year.try_into().ok()?,
+        month.unwrap_or(1),
+        day.unwrap_or(1),
+    )?;
+    let time = match (num("hour"), num("minute")) {
+        (Some(h), Some(m)) => {
+            Some(NaiveTime::from_hms_opt(h, m, num("second").unwrap_or(0))?)
+        }
+        _ => None,
+    };
+    let precision = match (month, day, time) {
+        (Some(_), Some(_), Some(_)) => DatePrecision::Exact,
+        (Some(_), Some(_), None) => DatePrecision::Day,
+        (Some(_), None, _) => DatePrecision::Month,
+        (None, _, _) => DatePrecision::Year,
+    };
+    Some((date.and_time(time.unwrap_or(NaiveTime::MIN)), precision))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Regex(regex::Error),
+    NoYear(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(out, "{e}"),
+            Error::Regex(e) => write!(out, "Bad date pattern: {e}"),
+            Error::NoYear(p) => {
+                write!(out, "Date pattern {p:?} lacks a year group")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatePatterns, DatePrecision};
+
+    fn date(path: &str) -> Option<(String, DatePrecision)> {
+        let patterns = DatePatterns::load(None).unwrap();
+        patterns.date_for(path).map(|(d, p)| (d.to_string(), p))
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            date("phone/IMG_20190704_153012.jpg"),
+            Some(("2019-07-04 15:30:12".into(), DatePrecision::Exact)),
+        );
+        assert_eq!(
+            date("whatsapp/IMG-20190704-WA0012.jpg"),
+            Some(("2019-07-04 00:00:00".into(), DatePrecision::Day)),
+        );
+    }
+
+    #[test]
+    fn directories() {
+        assert_eq!(
+            date("scans/1998/12 Julafton/scan01.jpg"),
+            Some(("1998-12-01 00:00:00".into(), DatePrecision::Month)),
+        );
+        assert_eq!(
+            date("1978 Sommar/bild3.jpg"),
+            Some(("1978-01-01 00:00:00".into(), DatePrecision::Year)),
+        );
+    }
+
+    #[test]
+    fn no_date() {
+        assert_eq!(date("scans/bild3.jpg"), None);
+        assert_eq!(date("IMG_20191340.jpg"), None);
+    }
+}
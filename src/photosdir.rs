@@ -153,9 +153,42 @@ fn do_get_scaled_jpeg(
     Ok(buf)
 }
 
+/// Get a perceptual hash of an image file.
+pub async fn get_phash(path: PathBuf) -> Result<i64, ImageLoadFailed> {
+    spawn_blocking(move || Ok(dhash(&image::open(path)?))).await?
+}
+
+/// A difference hash of an image.
+///
+/// Each bit tells if a pixel is darker than the one to its right, in
+/// an image scaled down to 9x8 gray pixels.  Images that look the
+/// same have hashes that differ in few bits, even if scaled or
+/// compressed differently.
+fn dhash(img: &DynamicImage) -> i64 {
+    let small = img.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let (left, right) =
+                (small.get_pixel(x, y), small.get_pixel(x + 1, y));
+            hash = hash << 1 | u64::from(left[0] < right[0]);
+        }
+    }
+    hash as i64
+}
+
 struct Size<'a>(&'a DynamicImage);
 impl std::fmt::Display for Size<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}x{}", self.0.width(), self.0.height())
     }
 }
+
+#[test]
+fn dhash_of_gradients() {
+    use image::{GrayImage, Luma};
+    let lighter = GrayImage::from_fn(90, 80, |x, _| Luma([x as u8 * 2]));
+    let darker = GrayImage::from_fn(90, 80, |x, _| Luma([200 - x as u8 * 2]));
+    assert_eq!(dhash(&lighter.into()), -1);
+    assert_eq!(dhash(&darker.into()), 0);
+}
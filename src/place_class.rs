@@ -0,0 +1,228 @@
+//! Classification of OpenStreetMap areas as places.
+//!
+//! Decides which areas are used as places, at what level, and which
+//! tag gives the name.  See `places.toml` for the format and the
+//! built-in classification.
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const BUILTIN: &str = include_str!("places.toml");
+
+#[derive(Clone, Debug, Default, clap::Parser)]
+pub struct ClassOpt {
+    /// A toml file with rules for which areas are places, and their
+    /// names and levels.
+    ///
+    /// The built-in rules are used by default.
+    #[clap(long, env = "RPHOTOS_PLACE_CONFIG")]
+    place_config: Option<PathBuf>,
+}
+
+impl ClassOpt {
+    pub fn classification(&self) -> Result<Classification, Error> {
+        Classification::load(self.place_config.as_deref())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Classification {
+    name_tags: Vec<String>,
+    #[serde(default)]
+    rule: Vec<Rule>,
+    #[serde(default)]
+    country: BTreeMap<String, CountryRules>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    tag: String,
+    values: BTreeMap<String, i16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CountryRules {
+    name_tags: Option<Vec<String>>,
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+impl Classification {
+    /// Load the classification from a file, or the built-in one.
+    pub fn load(file: Option<&Path>) -> Result<Self, Error> {
+        match file {
+            Some(file) => {
+                let data = std::fs::read_to_string(file).map_err(Error::Io)?;
+                toml::from_str(&data).map_err(Error::Toml)
+            }
+            None => Ok(Self::builtin()),
+        }
+    }
+
+    pub fn builtin() -> Self {
+        toml::from_str(BUILTIN).expect("Built-in places.toml is valid")
+    }
+
+    /// Get the name and level of an area, if it should be a place.
+    ///
+    /// The `tags` are the osm tags of the area, and `country` is the
+    /// ISO 3166-1 code of the country it is in, if known.
+    pub fn name_and_level<'a>(
+        &self,
+        tags: &'a Value,
+        country: Option<&str>,
+    ) -> Option<(&'a str, i16)> {
+        let local = country.and_then(|c| self.country.get(c));
+        let name = local
+            .and_then(|c| c.name_tags.as_ref())
+            .unwrap_or(&self.name_tags)
+            .iter()
+            .find_map(|name| tag_str(tags, name))?;
+        let level = tag_str(tags, "admin_level")
+            .and_then(|l| l.parse().ok())
+            .or_else(|| {
+                local
+                    .iter()
+                    .flat_map(|c| &c.rule)
+                    .chain(&self.rule)
+                    .find_map(|rule| rule.level(tags))
+            })?;
+        Some((name, level))
+    }
+
+    /// True if an area may be a place in any country.
+    pub fn may_be_place(&self, tags: &Value) -> bool {
+        self.name_and_level(tags, None).is_some()
+            || self
+                .country
+                .keys()
+                .any(|c| self.name_and_level(tags, Some(c)).is_some())
+    }
+}
+
+impl Rule {
+    fn level(&self, tags: &Value) -> Option<i16> {
+        let value = tag_str(tags, &self.tag)?;
+        self.values
+            .get(value)
+            .or_else(|| self.values.get("*"))
+            .copied()
+    }
+}
+
+/// Get the country code from a list of areas containing a position.
+///
+/// The country is the area with admin level 2, if any.
+pub fn country_of(elements: &[Value]) -> Option<&str> {
+    elements
+        .iter()
+        .filter_map(|e| e.get("tags"))
+        .find_map(|tags| {
+            (tag_str(tags, "admin_level") == Some("2"))
+                .then(|| {
+                    tag_str(tags, "ISO3166-1:alpha2")
+                        .or_else(|| tag_str(tags, "ISO3166-1"))
+                })
+                .flatten()
+        })
+}
+
+fn tag_str<'a>(tags: &'a Value, name: &str) -> Option<&'a str> {
+    tags.get(name).and_then(Value::as_str)
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(out, "{e}"),
+            Error::Toml(e) => write!(out, "Bad place classification: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Classification;
+    use serde_json::json;
+    use std::collections::BTreeSet;
+    use std::iter::once;
+
+    #[test]
+    fn builtin_rules_are_distinct() {
+        let classes = Classification::builtin();
+        assert_eq!(
+            classes
+                .rule
+                .iter()
+                .map(|r| &r.tag)
+                .collect::<BTreeSet<_>>()
+                .len(),
+            classes.rule.len(),
+            "Rule tags are not distinct",
+        );
+    }
+
+    #[test]
+    fn check_known_data() {
+        let classes = Classification::builtin();
+        let countries =
+            classes.country.iter().map(|(c, r)| (Some(c), &r.rule));
+        for (country, rules) in once((None, &classes.rule)).chain(countries) {
+            assert_eq!(
+                rules.iter().map(|r| &r.tag).collect::<BTreeSet<_>>().len(),
+                rules.len(),
+                "Rule tags for {country:?} are not distinct",
+            );
+            for rule in rules {
+                assert!(!rule.values.is_empty(), "No values for {}", rule.tag);
+                for (value, level) in &rule.values {
+                    assert!(
+                        (1..=20).contains(level),
+                        "Bad level {level} for {}={value}",
+                        rule.tag,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn country_override() {
+        let classes: Classification = toml::from_str(
+            r#"
+            name_tags = ["name"]
+            [[rule]]
+            tag = "landuse"
+            values = { residential = 11 }
+            [country.FI]
+            name_tags = ["name:sv", "name"]
+            [[country.FI.rule]]
+            tag = "landuse"
+            values = { residential = 12 }
+            "#,
+        )
+        .unwrap();
+        let tags = json!({
+            "landuse": "residential", "name": "Helsinki", "name:sv": "Helsingfors"
+        });
+        assert_eq!(
+            classes.name_and_level(&tags, None),
+            Some(("Helsinki", 11))
+        );
+        assert_eq!(
+            classes.name_and_level(&tags, Some("FI")),
+            Some(("Helsingfors", 12))
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! A persistent queue of photos waiting for places to be fetched.
+//!
+//! Photos are queued when they get a position in the admin views,
+//! and the queue is processed by a worker in the server.  Failed
+//! lookups are retried with exponential backoff, until
+//! [`MAX_ATTEMPTS`] is reached.
+use crate::adm::placeparents::update_parents;
+use crate::adm::result::Error;
+use crate::dbopt::PgPool;
+use crate::fetch_places::{self, PlaceLookup};
+use crate::schema::place_queue::dsl as q;
+use crate::xmp::SidecarWriter;
+use chrono::{DateTime, TimeDelta, Utc};
+use diesel::prelude::*;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+use tracing::{debug, info, warn};
+
+/// Number of failed lookups before a photo is considered failed.
+pub const MAX_ATTEMPTS: i16 = 10;
+
+/// How long the worker waits before checking an empty queue again.
+const IDLE: Duration = Duration::from_secs(10);
+
+/// Minimum time between updates of the place tree.
+///
+/// Updating the tree means checking every place, so it is not done
+/// each time the queue is drained.
+const TREE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Queryable)]
+pub struct QueueEntry {
+    pub photo_id: i32,
+    pub attempts: i16,
+    pub next_try: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+impl QueueEntry {
+    /// Get all entries, pending before failed, next due first.
+    pub async fn load_all(
+        db: &mut AsyncPgConnection,
+    ) -> Result<Vec<QueueEntry>, diesel::result::Error> {
+        q::place_queue
+            .order((q::attempts.ge(MAX_ATTEMPTS), q::next_try))
+            .load(db)
+            .await
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.attempts >= MAX_ATTEMPTS
+    }
+}
+
+/// Add a photo to the queue, or make it due now if already queued.
+pub async fn enqueue(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<(), diesel::result::Error> {
+    diesel::insert_into(q::place_queue)
+        .values(q::photo_id.eq(photo_id))
+        .on_conflict(q::photo_id)
+        .do_update()
+        .set((
+            q::attempts.eq(0),
+            q::next_try.eq(diesel::dsl::now),
+            q::last_error.eq(None::<String>),
+        ))
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Make failed entries due again, either one photo or all of them.
+pub async fn retry_failed(
+    db: &mut AsyncPgConnection,
+    photo_id: Option<i32>,
+) -> Result<usize, diesel::result::Error> {
+    let mut query = diesel::update(q::place_queue)
+        .filter(q::attempts.ge(MAX_ATTEMPTS))
+        .into_boxed();
+    if let Some(photo_id) = photo_id {
+        query = query.filter(q::photo_id.eq(photo_id));
+    }
+    query
+        .set((q::attempts.eq(0), q::next_try.eq(diesel::dsl::now)))
+        .execute(db)
+        .await
+}
+
+/// Process the queue forever.
+///
+/// The lookup itself limits the request rate.  When lookups keep
+/// failing (e.g. when the overpass server is down), the worker also
+/// waits longer and longer between attempts.
+/// If `xmp` is given, sidecars are written for photos that get
+/// places.
+pub async fn run_worker(
+    pool: PgPool,
+    lookup: PlaceLookup,
+    xmp: Option<SidecarWriter>,
+) {
+    info!("Starting place lookup worker.");
+    let mut failures = 0;
+    let mut found = false;
+    let mut last_tree: Option<Instant> = None;
+    loop {
+        let pause = match process_next(&pool, &lookup, xmp.as_ref()).await {
+            Ok(Some(true)) => {
+                failures = 0;
+                found = true;
+                continue;
+            }
+            Ok(None)
+                if found
+                    && last_tree
+                        .is_none_or(|t| t.elapsed() >= TREE_INTERVAL) =>
+            {
+                // The queue is done, now is a good time to update the
+                // place tree.
+                found = false;
+                last_tree = Some(Instant::now());
+                if let Err(e) = update_tree(&pool).await {
+                    warn!("Failed to update place parents: {e}");
+                }
+                IDLE
+            }
+            Ok(None) => IDLE,
+            Ok(Some(false)) => {
+                failures += 1;
+                backoff(failures)
+                    .min(TimeDelta::minutes(10))
+                    .to_std()
+                    .unwrap_or(IDLE)
+            }
+            Err(e) => {
+                warn!("Place queue failed: {e}");
+                IDLE
+            }
+        };
+        sleep(pause).await;
+    }
+}
+
+async fn update_tree(pool: &PgPool) -> Result<(), Error> {
+    let mut db = pool.get().await.map_err(|e| Error::Other(e.to_string()))?;
+    let n = update_parents(&mut db).await?;
+    debug!("Changed the parent of {} places.", n);
+    Ok(())
+}
+
+/// Look up places for the next due photo in the queue.
+///
+/// Returns `None` if no photo is due, otherwise `Some` of whether
+/// the lookup succeeded.
+async fn process_next(
+    pool: &PgPool,
+    lookup: &PlaceLookup,
+    xmp: Option<&SidecarWriter>,
+) -> Result<Option<bool>, Error> {
+    let mut db = pool.get().await.map_err(|e| Error::Other(e.to_string()))?;
+    let Some((photo_id, attempts)) = q::place_queue
+        .select((q::photo_id, q::attempts))
+        .filter(q::attempts.lt(MAX_ATTEMPTS))
+        .filter(q::next_try.le(diesel::dsl::now))
+        .order(q::next_try)
+        .first::<(i32, i16)>(&mut db)
+        .await
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    debug!("Fetching places for #{} from queue.", photo_id);
+    match lookup.update_image_places(&mut db, photo_id).await {
+        Ok(()) | Err(fetch_places::Error::NoPosition(_)) => {
+            diesel::delete(q::place_queue.find(photo_id))
+                .execute(&mut db)
+                .await?;
+            if let Some(xmp) = xmp {
+                xmp.update(&mut db, photo_id).await;
+            }
+            Ok(Some(true))
+        }
+        Err(e) => {
+            let attempts = attempts + 1;
+            warn!("Attempt {attempts} to fetch places failed: {e}");
+            diesel::update(q::place_queue.find(photo_id))
+                .set((
+                    q::attempts.eq(attempts),
+                    q::next_try.eq(Utc::now() + backoff(attempts)),
+                    q::last_error.eq(e.to_string()),
+                ))
+                .execute(&mut db)
+                .await?;
+            Ok(Some(false))
+        }
+    }
+}
+
+/// Time to wait after a number of failed attempts.
+///
+/// Starts at one minute and doubles for each attempt, up to a day.
+fn backoff(attempts: i16) -> TimeDelta {
+    let minutes = 1 << (attempts - 1).clamp(0, 11);
+    TimeDelta::minutes(minutes).min(TimeDelta::days(1))
+}
+
+#[test]
+fn backoff_doubles() {
+    assert_eq!(backoff(1), TimeDelta::minutes(1));
+    assert_eq!(backoff(4), TimeDelta::minutes(8));
+    assert_eq!(backoff(MAX_ATTEMPTS), TimeDelta::minutes(512));
+    assert_eq!(backoff(30), TimeDelta::days(1));
+}
@@ -1,5 +1,17 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    areas (osm_id) {
+        osm_id -> Int8,
+        tags -> Jsonb,
+        min_lat -> Int4,
+        max_lat -> Int4,
+        min_lng -> Int4,
+        max_lng -> Int4,
+        geometry -> Jsonb,
+    }
+}
+
 diesel::table! {
     attributions (id) {
         id -> Int4,
@@ -20,6 +32,14 @@ diesel::table! {
         id -> Int4,
         slug -> Varchar,
         person_name -> Varchar,
+        visibility -> Int2,
+    }
+}
+
+diesel::table! {
+    photo_hashes (photo_id) {
+        photo_id -> Int4,
+        phash -> Nullable<Int8>,
     }
 }
 
@@ -37,6 +57,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    photo_stacks (photo_id) {
+        photo_id -> Int4,
+        top_id -> Int4,
+        manual -> Bool,
+    }
+}
+
+diesel::table! {
+    photo_unstacked (photo_id) {
+        photo_id -> Int4,
+    }
+}
+
 diesel::table! {
     photo_tags (photo_id, tag_id) {
         photo_id -> Int4,
@@ -56,6 +90,43 @@ diesel::table! {
         attribution_id -> Nullable<Int4>,
         width -> Int4,
         height -> Int4,
+        description -> Nullable<Text>,
+        xmp_mtime -> Nullable<Timestamptz>,
+        utc_offset -> Nullable<Int2>,
+        date_utc -> Nullable<Timestamptz>,
+        date_shift -> Int4,
+        date_precision -> Int2,
+        date_source -> Nullable<Varchar>,
+        duplicate_of -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    place_fences (place_id) {
+        place_id -> Int4,
+        geometry -> Jsonb,
+        radius -> Nullable<Float8>,
+        min_lat -> Int4,
+        max_lat -> Int4,
+        min_lng -> Int4,
+        max_lng -> Int4,
+    }
+}
+
+diesel::table! {
+    place_osm (place_id) {
+        place_id -> Int4,
+        country -> Nullable<Varchar>,
+        tags -> Jsonb,
+    }
+}
+
+diesel::table! {
+    place_queue (photo_id) {
+        photo_id -> Int4,
+        attempts -> Int2,
+        next_try -> Timestamptz,
+        last_error -> Nullable<Varchar>,
     }
 }
 
@@ -66,6 +137,7 @@ diesel::table! {
         place_name -> Varchar,
         osm_id -> Nullable<Int8>,
         osm_level -> Nullable<Int2>,
+        parent_id -> Nullable<Int4>,
     }
 }
 
@@ -75,6 +147,10 @@ diesel::table! {
         photo_id -> Int4,
         latitude -> Int4,
         longitude -> Int4,
+        inferred -> Bool,
+        altitude -> Nullable<Float4>,
+        accuracy -> Nullable<Float4>,
+        direction -> Nullable<Float4>,
     }
 }
 
@@ -94,24 +170,36 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(photo_hashes -> photos (photo_id));
 diesel::joinable!(photo_people -> people (person_id));
 diesel::joinable!(photo_people -> photos (photo_id));
 diesel::joinable!(photo_places -> photos (photo_id));
 diesel::joinable!(photo_places -> places (place_id));
 diesel::joinable!(photo_tags -> photos (photo_id));
 diesel::joinable!(photo_tags -> tags (tag_id));
+diesel::joinable!(photo_unstacked -> photos (photo_id));
 diesel::joinable!(photos -> attributions (attribution_id));
 diesel::joinable!(photos -> cameras (camera_id));
+diesel::joinable!(place_fences -> places (place_id));
+diesel::joinable!(place_osm -> places (place_id));
+diesel::joinable!(place_queue -> photos (photo_id));
 diesel::joinable!(positions -> photos (photo_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    areas,
     attributions,
     cameras,
     people,
+    photo_hashes,
     photo_people,
     photo_places,
+    photo_stacks,
     photo_tags,
+    photo_unstacked,
     photos,
+    place_fences,
+    place_osm,
+    place_queue,
     places,
     positions,
     tags,
@@ -1,16 +1,30 @@
 //! Admin-only views, generally called by javascript.
 use super::error::ViewResult;
-use super::{Context, Result, ViewError, redirect_to_img, wrap};
-use crate::models::{Coord, Person, Photo, SizeTag, Tag};
+use super::search::SearchQuery;
+use super::{
+    Context, RenderRucte, Result, ViewError, redirect, redirect_to_img, wrap,
+};
+use crate::clockoffset::ClockOffset;
+use crate::duplicates::{self, MAX_DISTANCE};
+use crate::fences::{self, Fence, FencedPlace};
+use crate::fetch_places::is_duplicate;
+use crate::models::{
+    Coord, DatePrecision, DateSource, Person, Photo, SizeTag, Tag, Visibility,
+};
+use crate::place_queue::{self, QueueEntry};
+use crate::schema::people::dsl as h;
 use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_places::dsl as pl;
 use crate::schema::photo_tags::dsl as pt;
 use crate::schema::photos::dsl as p;
 use crate::schema::positions::dsl as ps;
-use crate::schema::tags::dsl as t;
+use crate::stacks;
+use crate::templates;
+use chrono::{NaiveDate, NaiveTime, TimeDelta};
 use diesel::{self, prelude::*};
 use diesel_async::{AsyncPgConnection, RunQueryDsl, SaveChangesDsl};
 use serde::Deserialize;
-use slug::slugify;
+use serde_json::{Value, json};
 use tracing::{info, warn};
 use warp::Filter;
 use warp::filters::BoxedFilter;
@@ -18,21 +32,66 @@ use warp::http::response::Builder;
 use warp::reply::Response;
 
 pub fn routes(s: BoxedFilter<(Context,)>) -> BoxedFilter<(Response,)> {
-    use warp::{body::form, path, post};
-    let route = path("grade")
+    use warp::{body::form, get, path, path::end, post};
+    let pages = path("places")
+        .and(end())
+        .and(get())
+        .and(s.clone())
+        .then(place_queue_page)
+        .or(path("fences")
+            .and(end())
+            .and(get())
+            .and(s.clone())
+            .then(fences))
+        .unify()
+        .or(path("duplicates")
+            .and(end())
+            .and(get())
+            .and(s.clone())
+            .then(duplicates_page))
+        .unify()
+        .map(wrap);
+    let route = path("date")
         .and(s.clone())
         .and(form())
-        .then(set_grade)
+        .then(set_date)
+        .or(path("duplicates")
+            .and(s.clone())
+            .and(form())
+            .then(merge_duplicates))
+        .unify()
+        .or(path("fences").and(s.clone()).and(form()).then(create_fence))
+        .unify()
+        .or(path("grade").and(s.clone()).and(form()).then(set_grade))
+        .unify()
+        .or(path("interpolate")
+            .and(s.clone())
+            .and(form())
+            .then(interpolate))
+        .unify()
         .or(path("locate").and(s.clone()).and(form()).then(set_location))
         .unify()
         .or(path("person").and(s.clone()).and(form()).then(set_person))
         .unify()
+        .or(path("places").and(s.clone()).and(form()).then(retry_places))
+        .unify()
         .or(path("rotate").and(s.clone()).and(form()).then(rotate))
         .unify()
-        .or(path("tag").and(s).and(form()).then(set_tag))
+        .or(path("stack").and(s.clone()).and(form()).then(set_stack))
+        .unify()
+        .or(path("tag").and(s.clone()).and(form()).then(set_tag))
+        .unify()
+        .or(path("timeshift").and(s.clone()).and(form()).then(timeshift))
+        .unify()
+        .or(path("unduplicate")
+            .and(s.clone())
+            .and(form())
+            .then(unmark_duplicate))
+        .unify()
+        .or(path("visibility").and(s).and(form()).then(set_visibility))
         .unify()
         .map(wrap);
-    post().and(route).boxed()
+    post().and(route).or(pages).unify().boxed()
 }
 
 async fn rotate(context: Context, form: RotateForm) -> Result<Response> {
@@ -64,24 +123,7 @@ async fn set_tag(context: Context, form: TagForm) -> Result<Response> {
         return Err(ViewError::PermissionDenied);
     }
     let mut c = context.db().await?;
-    let tag = {
-        if let Some(tag) = t::tags
-            .filter(t::tag_name.ilike(&form.tag))
-            .first::<Tag>(&mut c)
-            .await
-            .optional()?
-        {
-            tag
-        } else {
-            diesel::insert_into(t::tags)
-                .values((
-                    t::tag_name.eq(&form.tag),
-                    t::slug.eq(&slugify(&form.tag)),
-                ))
-                .get_result::<Tag>(&mut c)
-                .await?
-        }
-    };
+    let tag = Tag::get_or_create_name(&mut c, &form.tag).await?;
     let q = pt::photo_tags
         .filter(pt::photo_id.eq(form.image))
         .filter(pt::tag_id.eq(tag.id))
@@ -95,6 +137,7 @@ async fn set_tag(context: Context, form: TagForm) -> Result<Response> {
             .execute(&mut c)
             .await?;
     }
+    context.update_xmp(form.image).await;
     Ok(redirect_to_img(form.image))
 }
 
@@ -104,6 +147,43 @@ struct TagForm {
     tag: String,
 }
 
+/// Shift the time of the photos matching a search.
+///
+/// Shows a preview of the change, unless `apply` is given.
+async fn timeshift(
+    context: Context,
+    mut form: Vec<(String, String)>,
+) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let shift = form
+        .iter()
+        .find(|(key, _)| key == "shift")
+        .and_then(|(_, val)| val.parse::<ClockOffset>().ok())
+        .ok_or(ViewError::BadRequest("bad or missing time shift"))?;
+    let apply = form.iter().any(|(key, _)| key == "apply");
+    form.retain(|(key, _)| key != "apply");
+    let mut db = context.db().await?;
+    let query = SearchQuery::from_params(form.clone(), &mut db).await?;
+    // Include raw files and hidden duplicates, they have the same
+    // camera clock as the photos shown in the search.
+    let photos = Photo::exif_dated(query.filter(p::photos.into_boxed()))
+        .order((p::date, p::id))
+        .load::<Photo>(&mut db)
+        .await?;
+    if apply {
+        let ids = photos.iter().map(|p| p.id).collect::<Vec<_>>();
+        let n = Photo::shift_dates(&mut db, &ids, shift.0).await?;
+        info!("Shifted the time of {} photos by {}.", n, shift);
+        Ok(redirect(query.to_base_url().as_ref()))
+    } else {
+        Ok(Builder::new().html(|o| {
+            templates::timeshift_html(o, &context, &form, shift, &photos)
+        })?)
+    }
+}
+
 async fn set_person(context: Context, form: PersonForm) -> Result<Response> {
     if !context.is_authorized() {
         return Err(ViewError::PermissionDenied);
@@ -123,6 +203,7 @@ async fn set_person(context: Context, form: PersonForm) -> Result<Response> {
             .execute(&mut c)
             .await?;
     }
+    context.update_xmp(form.image).await;
     Ok(redirect_to_img(form.image))
 }
 
@@ -132,6 +213,183 @@ struct PersonForm {
     person: String,
 }
 
+/// Show the photos waiting for places, and failed lookups.
+async fn place_queue_page(context: Context) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    let entries = QueueEntry::load_all(&mut db).await?;
+    Ok(Builder::new()
+        .html(|o| templates::place_queue_html(o, &context, &entries))?)
+}
+
+/// Retry failed place lookups, for one photo or all of them.
+async fn retry_places(context: Context, form: RetryForm) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    let n = place_queue::retry_failed(&mut db, form.image).await?;
+    info!("Retrying place lookup for {} photos.", n);
+    Ok(redirect("/adm/places"))
+}
+
+#[derive(Deserialize)]
+struct RetryForm {
+    image: Option<i32>,
+}
+
+/// Show clusters of duplicate photos, with forms for merging them.
+async fn duplicates_page(context: Context) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    let clusters = duplicates::load_clusters(&mut db, MAX_DISTANCE).await?;
+    Ok(Builder::new()
+        .html(|o| templates::duplicates_html(o, &context, &clusters))?)
+}
+
+/// Keep the `primary` photo, hiding the other `photo`s as duplicates.
+async fn merge_duplicates(
+    context: Context,
+    form: Vec<(String, String)>,
+) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let ids = |name: &'static str| {
+        form.iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, val)| val.parse::<i32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| ViewError::BadRequest("bad photo id"))
+    };
+    let [primary] = ids("primary")?[..] else {
+        return Err(ViewError::BadRequest("one primary photo is required"));
+    };
+    let others = ids("photo")?;
+    let mut db = context.db().await?;
+    let n = duplicates::merge(&mut db, primary, &others, MAX_DISTANCE).await?;
+    info!("Hid {} duplicates of #{}.", n, primary);
+    Ok(redirect("/adm/duplicates"))
+}
+
+/// Show a photo that was hidden as a duplicate again.
+async fn unmark_duplicate(
+    context: Context,
+    form: ImageForm,
+) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    duplicates::unmark(&mut db, form.image).await?;
+    info!("Photo #{} is no longer a hidden duplicate.", form.image);
+    Ok(redirect_to_img(form.image))
+}
+
+/// Change the stack of similar photos a photo is in.
+///
+/// The `action` is `top` to show the photo for its stack, `remove`
+/// to take it out of the stack, or `auto` to let the stack be found
+/// automatically again.
+async fn set_stack(context: Context, form: StackForm) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    let image = form.image;
+    match form.action.as_str() {
+        "top" => {
+            if !stacks::set_top(&mut db, image).await? {
+                return Err(ViewError::BadRequest("the photo is not stacked"));
+            }
+        }
+        "remove" => stacks::unstack(&mut db, image).await?,
+        "auto" => stacks::reset(&mut db, image).await?,
+        _ => return Err(ViewError::BadRequest("unknown stack action")),
+    }
+    info!("Changed stack of #{}: {}", image, form.action);
+    Ok(redirect_to_img(image))
+}
+
+#[derive(Deserialize)]
+struct StackForm {
+    image: i32,
+    action: String,
+}
+
+/// Show the custom places, with a form for creating one.
+async fn fences(context: Context) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let mut db = context.db().await?;
+    let places = FencedPlace::load_all(&mut db).await?;
+    let json = places
+        .iter()
+        .map(|FencedPlace { place, fence }| {
+            let (geometry, radius) = fence.geometry();
+            json!({
+                "name": place.place_name,
+                "slug": place.slug,
+                "geometry": geometry,
+                "radius": radius,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Builder::new().html(|o| {
+        templates::fences_html(o, &context, &places, &Value::from(json))
+    })?)
+}
+
+/// Create a custom place, from a point and radius or a polygon.
+async fn create_fence(context: Context, form: FenceForm) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err(ViewError::BadRequest("a name is required"));
+    }
+    let points = serde_json::from_str::<Vec<(f64, f64)>>(&form.points)
+        .map_err(|_| ViewError::BadRequest("bad points"))?;
+    let radius = match form.radius.trim() {
+        "" => None,
+        r => Some(r.parse().map_err(|_| ViewError::BadRequest("bad radius"))?),
+    };
+    let fence = Fence::from_points(&points, radius).ok_or(
+        ViewError::BadRequest("need a point and a radius, or a polygon"),
+    )?;
+    let mut db = context.db().await?;
+    let place = fences::create_place(&mut db, name, form.level, &fence).await;
+    if is_duplicate(&place) {
+        return Err(ViewError::BadRequest("a place with that name exists"));
+    }
+    let place = place?;
+    info!("Created custom place {:?}", place);
+    let photos = pl::photo_places
+        .select(pl::photo_id)
+        .filter(pl::place_id.eq(place.id))
+        .load::<i32>(&mut db)
+        .await?;
+    for photo in photos {
+        context.update_xmp(photo).await;
+    }
+    Ok(redirect(&format!("/place/{}", place.slug)))
+}
+
+#[derive(Deserialize)]
+struct FenceForm {
+    name: String,
+    level: i16,
+    points: String,
+    #[serde(default)]
+    radius: String,
+}
+
 async fn set_grade(context: Context, form: GradeForm) -> Result<Response> {
     if !context.is_authorized() {
         return Err(ViewError::PermissionDenied);
@@ -142,6 +400,7 @@ async fn set_grade(context: Context, form: GradeForm) -> Result<Response> {
             .set(p::grade.eq(form.grade));
         match q.execute(&mut context.db().await?).await? {
             1 => {
+                context.update_xmp(form.image).await;
                 return Ok(redirect_to_img(form.image));
             }
             0 => (),
@@ -165,37 +424,168 @@ struct GradeForm {
     grade: i16,
 }
 
+/// Set the date manually, e.g. for a scanned photo.
+///
+/// The date is truncated to the start of the period given by the
+/// precision.
+async fn set_date(context: Context, form: DateForm) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let precision = form
+        .precision
+        .parse::<DatePrecision>()
+        .map_err(ViewError::BadRequest)?;
+    let time = match form.time.as_str() {
+        "" => NaiveTime::MIN,
+        time => time
+            .parse()
+            .map_err(|_| ViewError::BadRequest("bad time"))?,
+    };
+    let date = precision.truncate(form.date.and_time(time));
+    info!("Set date of #{} to {} ({:?})", form.image, date, precision);
+    let q = diesel::update(p::photos.find(form.image)).set((
+        p::date.eq(date),
+        p::date_precision.eq(precision.as_i16()),
+        p::date_source.eq(DateSource::Manual.as_str()),
+        p::date_shift.eq(0),
+        // The offset of the exif date does not apply to a manual date.
+        p::utc_offset.eq(None::<i16>),
+    ));
+    match q.execute(&mut context.db().await?).await? {
+        0 => Err(ViewError::NotFound(Some(context))),
+        _ => Ok(redirect_to_img(form.image)),
+    }
+}
+
+#[derive(Deserialize)]
+struct DateForm {
+    image: i32,
+    date: NaiveDate,
+    #[serde(default)]
+    time: String,
+    precision: String,
+}
+
+/// Set who may see a person on public pages.
+async fn set_visibility(
+    context: Context,
+    form: VisibilityForm,
+) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let visibility = form
+        .visibility
+        .parse::<Visibility>()
+        .map_err(ViewError::BadRequest)?;
+    info!(
+        "Set visibility of person #{} to {:?}",
+        form.person, visibility
+    );
+    let slug = diesel::update(h::people.find(form.person))
+        .set(h::visibility.eq(visibility.as_i16()))
+        .returning(h::slug)
+        .get_result::<String>(&mut context.db().await?)
+        .await
+        .optional()?;
+    match slug {
+        Some(slug) => Ok(redirect(&format!("/person/{slug}"))),
+        None => Err(ViewError::NotFound(Some(context))),
+    }
+}
+
+#[derive(Deserialize)]
+struct VisibilityForm {
+    person: i32,
+    visibility: String,
+}
+
 async fn set_location(context: Context, form: CoordForm) -> Result<Response> {
     if !context.is_authorized() {
         return Err(ViewError::PermissionDenied);
     }
-    let image = form.image;
     let coord = form.coord();
-    info!("Should set location of #{} to {:?}.", image, coord);
+    info!("Should set location of #{} to {:?}.", form.image, coord);
+    let mut db = context.db().await?;
+    store_position(&context, &mut db, form.image, &coord, false).await?;
+    Ok(redirect_to_img(form.image))
+}
 
-    let (lat, lng) = ((coord.x * 1e6) as i32, (coord.y * 1e6) as i32);
+/// Set the position of an image from images taken close in time.
+async fn interpolate(
+    context: Context,
+    form: InterpolateForm,
+) -> Result<Response> {
+    if !context.is_authorized() {
+        return Err(ViewError::PermissionDenied);
+    }
+    let minutes = form.minutes.unwrap_or(10);
+    if !(1..=24 * 60).contains(&minutes) {
+        return Err(ViewError::BadRequest("Bad number of minutes"));
+    }
     let mut db = context.db().await?;
+    let photo = or_404q!(
+        p::photos.find(form.image).first::<Photo>(&mut db).await,
+        context
+    );
+    let window = TimeDelta::minutes(minutes.into());
+    let Some(coord) = photo.interpolate_position(&mut db, window).await?
+    else {
+        return Err(ViewError::BadRequest(
+            "No positioned photos near in time",
+        ));
+    };
+    info!("Inferred location of #{} is {:?}.", photo.id, coord);
+    store_position(&context, &mut db, photo.id, &coord, true).await?;
+    Ok(redirect_to_img(photo.id))
+}
+
+#[derive(Deserialize)]
+struct ImageForm {
+    image: i32,
+}
+
+#[derive(Deserialize)]
+struct InterpolateForm {
+    image: i32,
+    /// Max time from photos the position is interpolated from.
+    minutes: Option<u32>,
+}
+
+async fn store_position(
+    context: &Context,
+    db: &mut AsyncPgConnection,
+    image: i32,
+    coord: &Coord,
+    inferred: bool,
+) -> Result<()> {
+    let (lat, lng) = ((coord.x * 1e6) as i32, (coord.y * 1e6) as i32);
     diesel::insert_into(ps::positions)
         .values((
             ps::photo_id.eq(image),
             ps::latitude.eq(lat),
             ps::longitude.eq(lng),
+            ps::inferred.eq(inferred),
         ))
         .on_conflict(ps::photo_id)
         .do_update()
-        .set((ps::latitude.eq(lat), ps::longitude.eq(lng)))
-        .execute(&mut db)
+        .set((
+            ps::latitude.eq(lat),
+            ps::longitude.eq(lng),
+            ps::inferred.eq(inferred),
+            // The gps altitude and accuracy are not for this position.
+            ps::altitude.eq(None::<f32>),
+            ps::accuracy.eq(None::<f32>),
+        ))
+        .execute(db)
         .await?;
-    match context
-        .overpass()
-        .update_image_places(&mut db, form.image)
-        .await
-    {
-        Ok(()) => (),
-        // Note: We log this error, but don't bother the user.
-        Err(err) => warn!("Failed to fetch places: {:?}", err),
+    fences::link_photo(db, image).await?;
+    if context.fetches_places() {
+        place_queue::enqueue(db, image).await?;
     }
-    Ok(redirect_to_img(form.image))
+    context.update_xmp(image).await;
+    Ok(())
 }
 
 #[derive(Deserialize)]
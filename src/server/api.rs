@@ -1,8 +1,9 @@
 //! API views
 use super::login::LoginForm;
-use super::{Context, ViewError};
-use crate::models::{Photo, SizeTag};
+use super::{Context, PrivacyZones, ViewError};
+use crate::models::{Coord, Photo, PosDetails, SizeTag};
 use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as ps;
 use diesel::{self, prelude::*, result::Error as DbError, update};
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use serde::{Deserialize, Serialize};
@@ -124,7 +125,7 @@ async fn get_img(context: Context, q: ImgQuery) -> ApiResult<GetImgResult> {
     if !context.is_authorized() && !img.is_public() {
         return Err(NOT_FOUND);
     }
-    Ok(GetImgResult::for_img(&img))
+    GetImgResult::load(&img, context.privacy_zones(), &mut db).await
 }
 
 async fn make_public(
@@ -142,9 +143,10 @@ async fn make_public(
     let img = id.get(&mut db).await?.ok_or(NOT_FOUND)?;
     let img = update(p::photos.find(img.id))
         .set(p::is_public.eq(true))
-        .get_result(&mut db)
+        .get_result::<Photo>(&mut db)
         .await?;
-    Ok(GetImgResult::for_img(&img))
+    context.update_xmp(img.id).await;
+    GetImgResult::load(&img, context.privacy_zones(), &mut db).await
 }
 
 struct ApiError {
@@ -207,18 +209,52 @@ struct GetImgResult {
     small: ImgLink,
     medium: ImgLink,
     public: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<ApiPosition>,
 }
 
 impl GetImgResult {
-    fn for_img(img: &Photo) -> Self {
-        GetImgResult {
+    async fn load(
+        img: &Photo,
+        zones: &PrivacyZones,
+        db: &mut AsyncPgConnection,
+    ) -> ApiResult<Self> {
+        let position = ps::positions
+            .filter(ps::photo_id.eq(img.id))
+            .select(((ps::latitude, ps::longitude), PosDetails::as_select()))
+            .first::<(Coord, PosDetails)>(db)
+            .await
+            .optional()?
+            .and_then(|(pos, details)| {
+                let shown = zones.apply(pos.clone())?;
+                Some(ApiPosition {
+                    // Details would make a coarsened position exact.
+                    details: if shown == pos {
+                        details
+                    } else {
+                        PosDetails::default()
+                    },
+                    lat: shown.x,
+                    lng: shown.y,
+                })
+            });
+        Ok(GetImgResult {
             small: ImgLink::new(img, SizeTag::Small),
             medium: ImgLink::new(img, SizeTag::Medium),
             public: img.is_public,
-        }
+            position,
+        })
     }
 }
 
+#[derive(Debug, Serialize)]
+struct ApiPosition {
+    lat: f64,
+    lng: f64,
+    #[serde(flatten)]
+    details: PosDetails,
+}
+
 #[derive(Debug, Serialize)]
 struct ImgLink {
     url: String,
@@ -1,4 +1,5 @@
 use super::{Context, Result, wrap};
+use crate::models::Visibility;
 use crate::schema::people::dsl as h; // h as in human
 use crate::schema::photo_people::dsl as pp;
 use crate::schema::photo_places::dsl as lp;
@@ -184,18 +185,20 @@ async fn select_people(
         .select((h::person_name, h::slug, ppos))
         .filter(ppos.gt(0))
         .into_boxed();
-    let query = if context.is_authorized() {
-        query
-    } else {
-        query.filter(
-            h::id.eq_any(
-                pp::photo_people.select(pp::person_id).filter(
-                    pp::photo_id
-                        .eq_any(p::photos.select(p::id).filter(p::is_public)),
-                ),
-            ),
-        )
-    };
+    let query =
+        if context.is_authorized() {
+            query
+        } else {
+            query
+                .filter(h::visibility.eq(Visibility::Public.as_i16()))
+                .filter(h::id.eq_any(
+                    pp::photo_people.select(pp::person_id).filter(
+                        pp::photo_id.eq_any(
+                            p::photos.select(p::id).filter(p::is_public),
+                        ),
+                    ),
+                ))
+        };
     let mut db = context.db().await?;
     Ok(query
         .order((ppos, h::person_name))
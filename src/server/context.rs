@@ -1,8 +1,8 @@
-use super::{Args, Result, error::ViewResult};
+use super::{Args, BaseUrl, PrivacyZones, Result, Tiles, error::ViewResult};
 use crate::adm::result::Error;
 use crate::dbopt::{PgPool, PooledPg};
-use crate::fetch_places::OverpassOpt;
 use crate::photosdir::PhotosDir;
+use crate::xmp::SidecarWriter;
 use medallion::{Header, Payload, Token};
 use r2d2_memcache::{MemcacheConnectionManager, r2d2};
 use std::future::Future;
@@ -34,8 +34,9 @@ pub fn create_session_filter(args: &Args) -> Result<ContextFilter, Error> {
                 .or(warp::any().map(|| None))
                 .unify(),
         )
-        .map(move |path, user| {
+        .map(move |path: FullPath, user| {
             let global = global.clone();
+            let path = path.as_str().into();
             Context { global, path, user }
         })
         .boxed())
@@ -43,12 +44,17 @@ pub fn create_session_filter(args: &Args) -> Result<ContextFilter, Error> {
 
 // Does _not_ derive debug, copy or clone, since it contains the jwt
 // secret and some connection pools.
-struct GlobalContext {
+pub struct GlobalContext {
     db_pool: PgPool,
     photosdir: PhotosDir,
-    memcache_pool: MemcachePool,
+    memcache_pool: Option<MemcachePool>,
     jwt_secret: String,
-    overpass: OverpassOpt,
+    fetch_places: bool,
+    base_url: Option<BaseUrl>,
+    tiles: Tiles,
+    privacy_zones: PrivacyZones,
+    xmp_writer: Option<SidecarWriter>,
+    static_export: bool,
 }
 
 impl GlobalContext {
@@ -60,19 +66,57 @@ impl GlobalContext {
                 Error::Other(format!("Failed to create db pool: {e}"))
             })?,
             photosdir: PhotosDir::new(&args.photos.photos_dir),
-            memcache_pool: r2d2::Pool::builder()
-                .connection_timeout(Duration::from_secs(1))
-                .build(mc_manager)
-                .map_err(|e| {
-                    Error::Other(format!(
-                        "Failed to create memcache pool: {e}"
-                    ))
-                })?,
+            memcache_pool: Some(
+                r2d2::Pool::builder()
+                    .connection_timeout(Duration::from_secs(1))
+                    .build(mc_manager)
+                    .map_err(|e| {
+                        Error::Other(format!(
+                            "Failed to create memcache pool: {e}"
+                        ))
+                    })?,
+            ),
             jwt_secret: args.jwt_key.clone(),
-            overpass: args.overpass.clone(),
+            fetch_places: args.places.lookup()?.is_some(),
+            base_url: args.base_url.clone(),
+            tiles: args.tiles.tiles(),
+            privacy_zones: args.privacy.zones(),
+            xmp_writer: args
+                .write_xmp
+                .then(|| args.xmp.writer(&args.photos.photos_dir)),
+            static_export: false,
         })
     }
 
+    /// Create a global context for rendering a static export.
+    ///
+    /// There is no cache, no login and no place lookup.
+    pub fn for_static_export(
+        db_pool: PgPool,
+        photosdir: PhotosDir,
+        base_url: Option<BaseUrl>,
+        tiles: Tiles,
+        privacy_zones: PrivacyZones,
+    ) -> Self {
+        GlobalContext {
+            db_pool,
+            photosdir,
+            memcache_pool: None,
+            jwt_secret: String::new(),
+            fetch_places: false,
+            base_url,
+            tiles,
+            privacy_zones,
+            xmp_writer: None,
+            static_export: true,
+        }
+    }
+
+    /// The privacy zones to apply for anonymous viewers.
+    pub fn privacy_zones(&self) -> &PrivacyZones {
+        &self.privacy_zones
+    }
+
     fn verify_key(&self, jwtstr: &str) -> Result<String, String> {
         let token = Token::<Header, ()>::parse(jwtstr)
             .map_err(|e| format!("Bad jwt token: {e:?}"))?;
@@ -98,8 +142,8 @@ impl GlobalContext {
             .sub
             .ok_or_else(|| "User missing in jwt claims".to_string())
     }
-    fn cache(&self) -> Result<PooledMemcache> {
-        Ok(self.memcache_pool.get()?)
+    fn cache(&self) -> Option<Result<PooledMemcache, r2d2::Error>> {
+        self.memcache_pool.as_ref().map(r2d2::Pool::get)
     }
 }
 
@@ -115,11 +159,19 @@ fn verify_token(
 /// The request context, providing database, memcache and authorized user.
 pub struct Context {
     global: Arc<GlobalContext>,
-    path: FullPath,
+    path: String,
     user: Option<String>,
 }
 
 impl Context {
+    /// An anonymous context for rendering `path` in a static export.
+    pub fn for_static_export(global: &Arc<GlobalContext>, path: &str) -> Self {
+        Context {
+            global: global.clone(),
+            path: path.into(),
+            user: None,
+        }
+    }
     pub async fn db(&self) -> Result<PooledPg> {
         Ok(self.global.db_pool.get().await?)
     }
@@ -132,8 +184,24 @@ impl Context {
     pub fn is_authorized(&self) -> bool {
         self.user.is_some()
     }
+    /// The privacy zones to apply to positions shown in this context.
+    ///
+    /// Authorized users see all positions as they are.
+    pub fn privacy_zones(&self) -> &PrivacyZones {
+        static NO_ZONES: PrivacyZones = PrivacyZones::NONE;
+        if self.is_authorized() {
+            &NO_ZONES
+        } else {
+            &self.global.privacy_zones
+        }
+    }
     pub fn path_without_query(&self) -> &str {
-        self.path.as_str()
+        &self.path
+    }
+    /// True when rendering pages for static hosting, where nothing
+    /// dynamic (like login or search) is available.
+    pub fn is_static_export(&self) -> bool {
+        self.global.static_export
     }
     pub async fn cached_or<F, R, E>(
         &self,
@@ -144,8 +212,8 @@ impl Context {
         F: FnOnce() -> R,
         R: Future<Output = Result<Vec<u8>, E>>,
     {
-        match self.global.memcache_pool.get() {
-            Ok(client) => {
+        match self.global.cache() {
+            Some(Ok(client)) => {
                 match client.get(key) {
                     Ok(Some(data)) => {
                         debug!("Cache: {} found", key);
@@ -165,14 +233,15 @@ impl Context {
                 }
                 Ok(data)
             }
-            Err(err) => {
+            Some(Err(err)) => {
                 warn!("Error connecting to memcache: {:?}", err);
                 calculate().await
             }
+            None => calculate().await,
         }
     }
     pub fn clear_cache(&self, key: &str) {
-        if let Ok(client) = self.global.cache() {
+        if let Some(Ok(client)) = self.global.cache() {
             match client.delete(key) {
                 Ok(flag) => debug!("Cache: deleted {}: {:?}", key, flag),
                 Err(e) => warn!("Cache: Failed to delete {}: {}", key, e),
@@ -182,8 +251,37 @@ impl Context {
     pub fn photos(&self) -> &PhotosDir {
         &self.global.photosdir
     }
-    pub fn overpass(&self) -> &OverpassOpt {
-        &self.global.overpass
+    /// True if places should be fetched for photos that get a
+    /// position (by the place queue worker).
+    pub fn fetches_places(&self) -> bool {
+        self.global.fetch_places
+    }
+    /// Write the xmp sidecar for a photo, if enabled.
+    ///
+    /// Failures are logged, but not reported to the user.
+    pub async fn update_xmp(&self, photo_id: i32) {
+        if let Some(writer) = &self.global.xmp_writer {
+            match self.db().await {
+                Ok(mut db) => writer.update(&mut db, photo_id).await,
+                Err(_) => warn!("Failed to get db connection for xmp"),
+            }
+        }
+    }
+    /// Where maps should get their tiles.
+    pub fn tiles(&self) -> &Tiles {
+        &self.global.tiles
+    }
+    pub fn base_url(&self) -> Option<&BaseUrl> {
+        self.global.base_url.as_ref()
+    }
+    /// Get an absolute url for `path` if a base url is configured.
+    ///
+    /// Otherwise, `path` is returned as it is.
+    pub fn absolute_url(&self, path: &str) -> String {
+        match self.base_url() {
+            Some(base) => base.join(path),
+            None => path.to_string(),
+        }
     }
 
     pub fn make_token(&self, user: &str) -> Result<String> {
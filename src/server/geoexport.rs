@@ -0,0 +1,54 @@
+//! Export positions of photos matching a search query.
+use super::search::SearchQuery;
+use super::{Context, Result, ViewError, ViewResult, wrap};
+use crate::geoexport::{Format, GeoPhoto, write};
+use crate::models::Photo;
+use warp::Filter;
+use warp::filters::BoxedFilter;
+use warp::filters::method::get;
+use warp::http::header;
+use warp::http::response::Builder;
+use warp::path::{end, param};
+use warp::query::query;
+use warp::reply::Response;
+
+/// Routes for `/export/positions.{geojson,kml,gpx}`.
+///
+/// The photos are selected by the same query parameters as search.
+pub fn routes(s: BoxedFilter<(Context,)>) -> BoxedFilter<(Response,)> {
+    param()
+        .and(end())
+        .and(get())
+        .and(s)
+        .and(query())
+        .then(positions)
+        .map(wrap)
+        .boxed()
+}
+
+async fn positions(
+    name: String,
+    context: Context,
+    query: Vec<(String, String)>,
+) -> Result<Response> {
+    let format = name
+        .strip_prefix("positions.")
+        .and_then(Format::from_extension)
+        .ok_or(ViewError::NotFound(None))?;
+    let mut db = context.db().await?;
+    let query = SearchQuery::for_viewer(query, &context, &mut db).await?;
+    let photos = query.filter(Photo::query(context.is_authorized()));
+    let url = |path: &str| context.absolute_url(path);
+    let zones = context.privacy_zones();
+    let photos = GeoPhoto::load(photos, url, zones, &mut db).await?;
+    let mut buf = Vec::new();
+    write(format, &photos, &mut buf).ise()?;
+    Builder::new()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}\""),
+        )
+        .body(buf.into())
+        .ise()
+}
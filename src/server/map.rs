@@ -0,0 +1,149 @@
+//! A full-screen map of photo positions, with clustering done in the
+//! database.
+use super::error::ViewResult;
+use super::search::{Bbox, SearchQuery, micro};
+use super::{Context, RenderRucte, Result, ViewError, wrap};
+use crate::models::{Coord, Photo};
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as pos;
+use crate::templates;
+use diesel::dsl::{count_star, max, not, sql};
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use diesel_async::RunQueryDsl;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use warp::filters::BoxedFilter;
+use warp::filters::method::get;
+use warp::http::response::Builder;
+use warp::path::{end, path};
+use warp::query::query;
+use warp::reply::{Response, json};
+use warp::{Filter, Reply};
+
+pub fn routes(s: BoxedFilter<(Context,)>) -> BoxedFilter<(Response,)> {
+    let page = end().and(get()).and(s.clone()).and(query()).then(map_page);
+    let markers = path("markers")
+        .and(end())
+        .and(get())
+        .and(s)
+        .and(query())
+        .then(markers);
+    page.or(markers).unify().map(wrap).boxed()
+}
+
+async fn map_page(
+    context: Context,
+    query: Vec<(String, String)>,
+) -> Result<Response> {
+    let mut db = context.db().await?;
+    let query = SearchQuery::for_viewer(query, &context, &mut db).await?;
+    Ok(Builder::new().html(|o| templates::map_html(o, &context, &query))?)
+}
+
+/// Get clustered markers for the photos matching a search query.
+///
+/// In addition to the search parameters, the visible area `view` (as
+/// west,south,east,north in degrees) and a zoom level `z` is
+/// required.
+///
+/// For anonymous viewers, photos in privacy zones are left out of the
+/// clustering and added as hidden or coarsened by the zones.
+async fn markers(
+    context: Context,
+    query: Vec<(String, String)>,
+) -> Result<Response> {
+    let mut view = None;
+    let mut zoom = None;
+    for (key, val) in &query {
+        match key.as_ref() {
+            "view" => view = Some(val.parse::<Bbox>()?),
+            "z" => zoom = Some(val.parse().req("z")?),
+            _ => (),
+        }
+    }
+    let view = view.ok_or(ViewError::BadRequest("view is required"))?;
+    let cell = cell_size(zoom.ok_or(ViewError::BadRequest("z is required"))?);
+
+    let mut db = context.db().await?;
+    let zones = context.privacy_zones();
+    let query = SearchQuery::for_viewer(query, &context, &mut db).await?;
+    let photos = || {
+        query
+            .filter(Photo::query(context.is_authorized()))
+            .select(p::id)
+    };
+    let lat_cell = format!("(latitude + 90000000) / {cell}");
+    let lng_cell = format!("(longitude + 180000000) / {cell}");
+    let mut clusters = pos::positions
+        .filter(pos::photo_id.eq_any(photos()))
+        .filter(view.contains())
+        .filter(zones.outside())
+        .group_by((sql::<Integer>(&lat_cell), sql::<Integer>(&lng_cell)))
+        .select((
+            sql::<Integer>("avg(latitude)::integer"),
+            sql::<Integer>("avg(longitude)::integer"),
+            count_star(),
+            max(pos::photo_id),
+        ))
+        .load::<(i32, i32, i64, Option<i32>)>(&mut db)
+        .await?
+        .into_iter()
+        .filter_map(|(lat, lng, count, photo)| {
+            Some(Cluster {
+                lat: f64::from(lat) * 1e-6,
+                lng: f64::from(lng) * 1e-6,
+                count,
+                photo: photo?,
+            })
+        })
+        .collect::<Vec<_>>();
+    if !zones.is_empty() {
+        let private = pos::positions
+            .filter(pos::photo_id.eq_any(photos()))
+            .filter(view.contains())
+            .filter(not(zones.outside()))
+            .select(((pos::latitude, pos::longitude), pos::photo_id))
+            .load::<(Coord, i32)>(&mut db)
+            .await?;
+        let mut cells = BTreeMap::new();
+        for (pos, photo) in zones.apply_all(private) {
+            let key = (micro(pos.x, 90.), micro(pos.y, 180.));
+            let cell = cells.entry(key).or_insert(Cluster {
+                lat: pos.x,
+                lng: pos.y,
+                count: 0,
+                photo,
+            });
+            cell.count += 1;
+            cell.photo = cell.photo.max(photo);
+        }
+        clusters.extend(cells.into_values());
+    }
+    Ok(json(&clusters).into_response())
+}
+
+/// A group of photos close to each other at the current zoom level.
+#[derive(Serialize)]
+struct Cluster {
+    lat: f64,
+    lng: f64,
+    count: i64,
+    /// The id of a photo to represent the cluster.
+    photo: i32,
+}
+
+/// The size of a cluster cell (in microdegrees) for a zoom level.
+///
+/// A cell is about 60 pixels wide on 256 pixel tiles.
+fn cell_size(zoom: u8) -> i32 {
+    (84_375_000 >> zoom.min(24)).max(1)
+}
+
+#[test]
+fn cells_halve_per_zoom_level() {
+    assert_eq!(cell_size(0), 84_375_000);
+    assert_eq!(cell_size(1), 42_187_500);
+    assert_eq!(cell_size(18), 321);
+    assert_eq!(cell_size(30), 5);
+}
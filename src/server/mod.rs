@@ -4,38 +4,50 @@ mod admin;
 mod api;
 mod autocomplete;
 mod context;
+mod geoexport;
 mod image;
 mod login;
+mod map;
 mod photolink;
+mod privacy;
 mod render_ructe;
 pub mod search;
+mod sitemap;
 mod splitlist;
+mod tiles;
 mod urlstring;
 mod views_by_category;
 mod views_by_date;
 
 use self::context::create_session_filter;
-pub use self::context::{Context, ContextFilter};
+pub use self::context::{Context, ContextFilter, GlobalContext};
 use self::error::{ViewError, ViewResult, for_rejection};
-pub use self::photolink::PhotoLink;
+pub use self::photolink::{PhotoLink, StackPart};
+pub use self::privacy::{PrivacyOpt, PrivacyZones};
 use self::render_ructe::BuilderExt;
 use self::search::search;
+use self::tiles::TileProxyOpt;
+pub use self::tiles::{TileOpt, Tiles};
+pub use self::urlstring::BaseUrl;
 use self::views_by_category::*;
-use self::views_by_date::monthname;
+pub use self::views_by_category::{PlaceTree, TreeNode};
+pub use self::views_by_date::monthname;
 use super::{CacheOpt, DbOpt, DirOpt};
 use crate::adm::result::Error;
-use crate::fetch_places::OverpassOpt;
-use crate::models::{Photo, PhotoDetails};
+use crate::fetch_places::PlacesOpt;
+use crate::models::{Photo, PhotoDetails, Place};
 use crate::pidfiles::handle_pid_file;
+use crate::place_queue;
 use crate::schema::photos::dsl as p;
 use crate::templates::{self, Html, RenderRucte};
+use crate::xmp::XmpOpt;
 use chrono::Datelike;
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use tracing::info;
+use tracing::{info, warn};
 use warp::filters::path::Tail;
 use warp::http::{StatusCode, header, response::Builder};
 use warp::reply::Response;
@@ -50,7 +62,11 @@ pub struct Args {
     #[clap(flatten)]
     photos: DirOpt,
     #[clap(flatten)]
-    overpass: OverpassOpt,
+    places: PlacesOpt,
+    #[clap(flatten)]
+    tiles: TileProxyOpt,
+    #[clap(flatten)]
+    privacy: PrivacyOpt,
 
     /// Write (and read, if --replace) a pid file with the name
     /// given as <PIDFILE>.
@@ -65,6 +81,17 @@ pub struct Args {
     /// Signing key for jwt
     #[clap(long, env = "JWT_KEY", hide_env_values = true)]
     jwt_key: String,
+    /// Public base url of the site, e.g. `https://img.example.org`.
+    ///
+    /// Needed for absolute canonical and Open Graph urls and for
+    /// the sitemap.
+    #[clap(long, env = "RPHOTOS_BASE_URL")]
+    base_url: Option<BaseUrl>,
+    /// Write xmp sidecars for photos changed in the admin views.
+    #[clap(long)]
+    write_xmp: bool,
+    #[clap(flatten)]
+    xmp: XmpOpt,
 }
 
 pub async fn run(args: &Args) -> Result<(), Error> {
@@ -75,6 +102,18 @@ pub async fn run(args: &Args) -> Result<(), Error> {
     if let Some(pidfile) = &args.pidfile {
         handle_pid_file(pidfile, args.replace)?;
     }
+    if let Some(lookup) = args.places.lookup()? {
+        let xmp = args
+            .write_xmp
+            .then(|| args.xmp.writer(&args.photos.photos_dir));
+        let pool = args.db.create_pool()?;
+        tokio::spawn(place_queue::run_worker(pool, lookup, xmp));
+    } else {
+        warn!(
+            "Neither --overpass-url nor --local-areas given, \
+             places will not be fetched for new positions."
+        );
+    }
     let session_filter = create_session_filter(args)?;
     let s = move || session_filter.clone();
     let static_routes = path("static")
@@ -95,6 +134,9 @@ pub async fn run(args: &Args) -> Result<(), Error> {
         .or(path("person").and(person_routes(s())))
         .or(path("place").and(place_routes(s())))
         .or(path("tag").and(tag_routes(s())))
+        .or(path("map").and(map::routes(s())))
+        .or(path("export").and(geoexport::routes(s())))
+        .or(path("tile").and(tiles::routes(&args.tiles)?))
         .or(path("random").and(end()).and(get()).and(s()).then(random_image).map(wrap))
         .or(path("ac").and(autocomplete::routes(s())))
         .or(path("search").and(end()).and(get()).and(s()).and(query()).then(search).map(wrap))
@@ -103,8 +145,10 @@ pub async fn run(args: &Args) -> Result<(), Error> {
         .or(path("robots.txt")
             .and(end())
             .and(get())
+            .and(s())
             .map(robots_txt)
-            .map(wrap));
+            .map(wrap))
+        .or(sitemap::routes(s()));
     warp::serve(routes.recover(for_rejection))
         .run(args.listen)
         .await;
@@ -159,7 +203,16 @@ async fn random_image(context: Context) -> Result<Response> {
 
 async fn photo_details(id: i32, context: Context) -> Result<Response> {
     let mut c = context.db().await?;
-    let photo = or_404q!(PhotoDetails::load(id, &mut c).await, context);
+    let mut photo = or_404q!(PhotoDetails::load(id, &mut c).await, context);
+    if let Some(primary) = photo.duplicate_of
+        && !context.is_authorized()
+    {
+        return Ok(redirect_to_img(primary));
+    }
+    context.privacy_zones().apply_details(&mut photo);
+    if !context.is_authorized() {
+        photo.hide_people();
+    }
 
     if context.is_authorized() || photo.is_public() {
         Ok(Builder::new().html(|o| {
@@ -189,12 +242,12 @@ async fn photo_details(id: i32, context: Context) -> Result<Response> {
 pub type Link = Html<String>;
 
 impl Link {
-    fn year(year: i32) -> Self {
+    pub fn year(year: i32) -> Self {
         Html(format!(
             "<a href='/{year}/' title='Images from {year}' accessKey='y'>{year}</a>",
         ))
     }
-    fn month(year: i32, month: u32) -> Self {
+    pub fn month(year: i32, month: u32) -> Self {
         Html(format!(
             "<a href='/{0}/{1}/' title='Images from {2} {0}' \
              accessKey='m'>{1}</a>",
@@ -203,7 +256,7 @@ impl Link {
             monthname(month),
         ))
     }
-    fn day(year: i32, month: u32, day: u32) -> Self {
+    pub fn day(year: i32, month: u32, day: u32) -> Self {
         Html(format!(
             "<a href='/{0}/{1}/{2}' title='Images from {2} {3} {0}' \
              accessKey='d'>{2}</a>",
@@ -213,6 +266,14 @@ impl Link {
             monthname(month),
         ))
     }
+    pub fn place(place: &Place) -> Self {
+        let name = place
+            .place_name
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        Html(format!("<a href='/place/{}'>{name}</a>", place.slug))
+    }
     fn prev(from: i32) -> Self {
         Html(format!(
             "<a href='/prev?from={from}' title='Previous image (by time)'>\
@@ -233,16 +294,18 @@ pub struct ImgRange {
     pub to: Option<i32>,
 }
 
-fn robots_txt() -> Result<Response> {
+fn robots_txt(context: Context) -> Result<Response> {
+    let mut body = "User-agent: *\n\
+                    Disallow: /login\n\
+                    Disallow: /logout\n\
+                    Disallow: /ac\n"
+        .to_string();
+    if let Some(base) = context.base_url() {
+        body.push_str(&format!("Sitemap: {}\n", base.join("/sitemap.xml")));
+    }
     Builder::new()
         .header(header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
-        .body(
-            "User-agent: *\n\
-             Disallow: /login\n\
-             Disallow: /logout\n\
-             Disallow: /ac\n"
-                .into(),
-        )
+        .body(body.into())
         .ise()
 }
 
@@ -1,6 +1,8 @@
+use super::splitlist::Hidden;
 use super::urlstring::UrlString;
-use crate::models::{Photo, SizeTag};
+use crate::models::{DatePrecision, Photo, SizeTag};
 use chrono::Datelike;
+use std::iter::once;
 
 pub struct PhotoLink {
     pub title: Option<String>,
@@ -8,11 +10,26 @@ pub struct PhotoLink {
     pub id: i32,
     pub size: (u32, u32),
     pub lable: Option<String>,
+    /// How the photo is part of a stack of similar photos, if it is.
+    pub stack: Option<StackPart>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackPart {
+    /// The photo shown for a stack of this many photos.
+    Top(usize),
+    /// A photo hidden in the stack shown as the photo with this id.
+    Under(i32),
 }
 
 impl PhotoLink {
-    pub fn for_group(
-        g: &[Photo],
+    /// A link to a group of photos.
+    ///
+    /// Photos in `hidden` are counted, and included in the time range
+    /// of the group, but not chosen to represent it.
+    pub fn for_group<'a>(
+        g: &'a [Photo],
+        hidden: &Hidden<'a>,
         url: UrlString,
         with_date: bool,
     ) -> PhotoLink {
@@ -23,14 +40,24 @@ impl PhotoLink {
                 PhotoLink::no_title(&g[0])
             }
         } else {
-            fn imgscore(p: &Photo) -> i16 {
-                // Only score below 19 is worse than ungraded.
-                p.grade.unwrap_or(19) * if p.is_public { 5 } else { 4 }
-            }
             let photo = g.iter().max_by_key(|p| imgscore(p)).unwrap();
+            let stack = |p: &'a Photo| {
+                let hidden = hidden.get(&p.id).into_iter().flatten().copied();
+                once(p).chain(hidden)
+            };
+            let first = g
+                .last()
+                .and_then(|p| stack(p).min_by_key(|p| (p.date, p.id)));
+            let last = g
+                .first()
+                .and_then(|p| stack(p).max_by_key(|p| (p.date, p.id)));
+            let count = g
+                .iter()
+                .map(|p| 1 + hidden.get(&p.id).map_or(0, Vec::len))
+                .sum::<usize>();
             let (title, lable) = {
-                let from = g.last().and_then(|p| p.date);
-                let to = g.first().and_then(|p| p.date);
+                let from = first.and_then(|p| p.date);
+                let to = last.and_then(|p| p.date);
                 if let (Some(from), Some(to)) = (from, to) {
                     if from.date() == to.date() {
                         (
@@ -39,7 +66,7 @@ impl PhotoLink {
                                 "{} - {} ({})",
                                 from.format("%R"),
                                 to.format("%R"),
-                                g.len(),
+                                count,
                             ),
                         )
                     } else if from.year() == to.year() {
@@ -50,7 +77,7 @@ impl PhotoLink {
                                     "{} - {} ({})",
                                     from.format("%F"),
                                     to.format("%d"),
-                                    g.len(),
+                                    count,
                                 ),
                             )
                         } else {
@@ -60,7 +87,7 @@ impl PhotoLink {
                                     "{} - {} ({})",
                                     from.format("%F"),
                                     to.format("%m-%d"),
-                                    g.len(),
+                                    count,
                                 ),
                             )
                         }
@@ -77,7 +104,7 @@ impl PhotoLink {
                                 "{} - {} ({})",
                                 from.format("%F"),
                                 to.format("%F"),
-                                g.len(),
+                                count,
                             ),
                         )
                     }
@@ -94,18 +121,18 @@ impl PhotoLink {
                                 || "-".to_string(),
                                 |d| format!("{}", d.format("%F %R"))
                             ),
-                            g.len(),
+                            count,
                         ),
                     )
                 }
             };
             let title = if with_date { title } else { None };
             let mut url = url;
-            if let Some(last) = g.last() {
-                url.query("from", last.id);
+            if let Some(first) = first {
+                url.query("from", first.id);
             }
-            if let Some(first) = g.first() {
-                url.query("to", first.id);
+            if let Some(last) = last {
+                url.query("to", last.id);
             }
             PhotoLink {
                 title,
@@ -113,16 +140,25 @@ impl PhotoLink {
                 id: photo.id,
                 size: photo.get_size(SizeTag::Small),
                 lable: Some(lable),
+                stack: None,
             }
         }
     }
     pub fn date_title(p: &Photo) -> PhotoLink {
+        if p.precision() != DatePrecision::Exact {
+            return PhotoLink {
+                title: p.date_text(),
+                lable: None,
+                ..PhotoLink::no_title(p)
+            };
+        }
         PhotoLink {
             title: p.date.map(|d| d.format("%F").to_string()),
             href: format!("/img/{}", p.id),
             id: p.id,
             size: p.get_size(SizeTag::Small),
             lable: p.date.map(|d| d.format("%T").to_string()),
+            stack: None,
         }
     }
     pub fn no_title(p: &Photo) -> PhotoLink {
@@ -131,10 +167,21 @@ impl PhotoLink {
             href: format!("/img/{}", p.id),
             id: p.id,
             size: p.get_size(SizeTag::Small),
-            lable: p.date.map(|d| d.format("%T").to_string()),
+            lable: if p.precision() == DatePrecision::Exact {
+                p.date.map(|d| d.format("%T").to_string())
+            } else {
+                p.date_text()
+            },
+            stack: None,
         }
     }
     pub fn is_portrait(&self) -> bool {
         self.size.1 > self.size.0
     }
 }
+
+/// How good a photo is for representing a group.
+pub fn imgscore(p: &Photo) -> i16 {
+    // Only score below 19 is worse than ungraded.
+    p.grade.unwrap_or(19) * if p.is_public { 5 } else { 4 }
+}
@@ -0,0 +1,161 @@
+//! Privacy zones, where positions are hidden from anonymous viewers.
+use super::search::{M_PER_UDEG, Near, micro};
+use crate::models::{Coord, PhotoDetails, PosDetails};
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::sql_types::Bool;
+
+#[derive(Clone, Debug, clap::Parser)]
+pub struct PrivacyOpt {
+    /// A zone where positions of public photos are hidden from
+    /// anonymous viewers, as `LAT,LNG,RADIUS_M[,GRID_M]`.
+    ///
+    /// If a grid size (in meters) is given, positions in the zone are
+    /// rounded to a grid of that size instead of hidden.  May be
+    /// given more than once (or separated by `;` in the environment).
+    #[clap(
+        long = "privacy-zone",
+        env = "RPHOTOS_PRIVACY_ZONES",
+        value_delimiter = ';'
+    )]
+    zones: Vec<PrivacyZone>,
+}
+
+impl PrivacyOpt {
+    pub fn zones(&self) -> PrivacyZones {
+        PrivacyZones(self.zones.clone())
+    }
+}
+
+/// A set of privacy zones.
+///
+/// An empty set changes nothing.
+#[derive(Clone, Debug, Default)]
+pub struct PrivacyZones(Vec<PrivacyZone>);
+
+impl PrivacyZones {
+    /// No privacy zones, for authorized viewers.
+    pub const NONE: PrivacyZones = PrivacyZones(Vec::new());
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get a position as it should be shown.
+    ///
+    /// None if the position should be hidden.
+    pub fn apply(&self, pos: Coord) -> Option<Coord> {
+        match self.zone_of(&pos) {
+            Some(zone) => zone.coarsen(&pos),
+            None => Some(pos),
+        }
+    }
+
+    /// Apply the zones to a list of photo positions.
+    pub fn apply_all(
+        &self,
+        positions: impl IntoIterator<Item = (Coord, i32)>,
+    ) -> Vec<(Coord, i32)> {
+        positions
+            .into_iter()
+            .filter_map(|(pos, id)| Some((self.apply(pos)?, id)))
+            .collect()
+    }
+
+    /// Hide or coarsen the position of a photo.
+    ///
+    /// Altitude, accuracy and direction are removed from positions in
+    /// a zone, since they would make the position easier to guess.
+    pub fn apply_details(&self, photo: &mut PhotoDetails) {
+        if let Some(zone) = photo.pos.as_ref().and_then(|p| self.zone_of(p)) {
+            photo.pos = photo.pos.as_ref().and_then(|p| zone.coarsen(p));
+            photo.pos_details = PosDetails::default();
+        }
+    }
+
+    /// Sql condition for a position outside of all zones.
+    pub fn outside(&self) -> SqlLiteral<Bool> {
+        if self.0.is_empty() {
+            return sql("true");
+        }
+        let inside = self
+            .0
+            .iter()
+            .map(|zone| format!("({})", zone.area.condition()))
+            .collect::<Vec<_>>();
+        sql(&format!("not ({})", inside.join(" or ")))
+    }
+
+    fn zone_of(&self, pos: &Coord) -> Option<&PrivacyZone> {
+        let (lat, lng) = (micro(pos.x, 90.), micro(pos.y, 180.));
+        self.0.iter().find(|zone| zone.area.covers(lat, lng))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrivacyZone {
+    area: Near,
+    /// Size of the grid to round positions to, in meters.
+    grid: Option<f64>,
+}
+
+impl PrivacyZone {
+    /// Round a position to the center of a grid cell.
+    ///
+    /// The cells are about `grid` meters square, based on the latitude
+    /// of the zone, so all positions in the zone use the same grid.
+    fn coarsen(&self, pos: &Coord) -> Option<Coord> {
+        let dlat = self.grid? / M_PER_UDEG;
+        let dlng = dlat / self.area.coslat();
+        let snap = |val: f64, d: f64| ((val * 1e6 / d).floor() + 0.5) * d;
+        Some(Coord::from((
+            snap(pos.x, dlat).round() as i32,
+            snap(pos.y, dlng).round() as i32,
+        )))
+    }
+}
+
+/// Parse `lat,lng,radius[,grid]`, position in degrees, sizes in meters.
+impl std::str::FromStr for PrivacyZone {
+    type Err = &'static str;
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let parts = val
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "privacy zone values must be numbers")?;
+        let (lat, lng, radius, grid) = match parts[..] {
+            [lat, lng, radius] => (lat, lng, radius, None),
+            [lat, lng, radius, grid] => (lat, lng, radius, Some(grid)),
+            _ => return Err("privacy zone must be LAT,LNG,RADIUS_M[,GRID_M]"),
+        };
+        if !(radius.is_finite() && radius > 0.) {
+            return Err("privacy zone radius must be positive");
+        }
+        if grid.is_some_and(|g| !(g.is_finite() && g > 0.)) {
+            return Err("privacy zone grid must be positive");
+        }
+        Ok(PrivacyZone {
+            area: Near::new(lat, lng, radius),
+            grid,
+        })
+    }
+}
+
+#[test]
+fn hide_and_coarsen() {
+    let zones = PrivacyZones(vec![
+        "59.33,18.07,500".parse().unwrap(),
+        "57.7,11.97,2000,1000".parse().unwrap(),
+    ]);
+    let pos = |x, y| Coord { x, y };
+    // Outside of the zones, positions are unchanged.
+    assert_eq!(zones.apply(pos(59.35, 18.07)), Some(pos(59.35, 18.07)));
+    // About 330 m from the center of the first zone.
+    assert_eq!(zones.apply(pos(59.333, 18.07)), None);
+    // Positions close to each other are rounded to the same cell.
+    let a = zones.apply(pos(57.7012, 11.9705)).unwrap();
+    let b = zones.apply(pos(57.7031, 11.9742)).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a, pos(57.704933, 11.974625));
+}
@@ -1,4 +1,6 @@
+use super::error::{ViewError, ViewResult};
 use chrono::{Duration, Utc};
+use std::io;
 use warp::http::response::Builder;
 use warp::http::{StatusCode, header};
 use warp::reply::Response;
@@ -7,6 +9,11 @@ pub trait BuilderExt {
     fn redirect(self, url: &str) -> Response;
 
     fn far_expires(self) -> Self;
+
+    /// Render an xml template (like the html method from ructe).
+    fn xml<F>(self, f: F) -> Result<Response, ViewError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
 }
 
 impl BuilderExt for Builder {
@@ -21,4 +28,15 @@ impl BuilderExt for Builder {
         let far_expires = Utc::now() + Duration::days(180);
         self.header(header::EXPIRES, far_expires.to_rfc2822())
     }
+
+    fn xml<F>(self, f: F) -> Result<Response, ViewError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut buf = Vec::new();
+        f(&mut buf).ise()?;
+        self.header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(buf.into())
+            .ise()
+    }
 }
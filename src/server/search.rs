@@ -1,18 +1,24 @@
 use super::error::{ViewError, ViewResult};
 use super::splitlist::split_to_group_links;
 use super::urlstring::UrlString;
-use super::{Context, RenderRucte, Result, SomeVec};
+use super::{Context, PrivacyZones, RenderRucte, Result, SomeVec};
 use crate::models::{Coord, Facet, Person, Photo, Place, Tag};
 use crate::schema::photo_people::dsl as pp;
 use crate::schema::photo_places::dsl as pl;
 use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos;
 use crate::schema::photos::dsl as p;
 use crate::schema::positions::dsl as pos;
+use crate::stacks;
 use crate::templates;
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
 use diesel::pg::Pg;
+use diesel::sql_types::Bool;
 use diesel::{debug_query, prelude::*};
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::fmt;
 use std::time::Instant;
 use tracing::{info, warn};
 use warp::http::response::Builder;
@@ -24,81 +30,11 @@ pub async fn search(
 ) -> Result<Response> {
     let start = Instant::now();
     let mut db = context.db().await?;
-    let query = SearchQuery::load(query.try_into()?, &mut db).await?;
+    let query = SearchQuery::for_viewer(query, &context, &mut db).await?;
     info!("Loaded query after {:.3?}", start.elapsed());
 
-    let mut photos = Photo::query(context.is_authorized());
-    if let Some(since) = query.since.as_ref() {
-        photos = photos.filter(p::date.ge(since));
-    }
-    if let Some(until) = query.until.as_ref() {
-        photos = photos.filter(p::date.le(until));
-    }
-    for tag in &query.t.include {
-        let ids = pt::photo_tags
-            .select(pt::photo_id)
-            .distinct()
-            .filter(pt::tag_id.eq(tag.id));
-        photos = photos.filter(p::id.eq_any(ids));
-    }
-    if !query.t.exclude.is_empty() {
-        let ids = query.t.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
-        photos = photos.filter(
-            pt::photo_tags
-                .select(pt::photo_id)
-                .filter(pt::photo_id.eq(p::id))
-                .filter(pt::tag_id.eq_any(ids))
-                .single_value()
-                .is_null(),
-        );
-    }
-    for location in &query.l.include {
-        let ids = pl::photo_places
-            .select(pl::photo_id)
-            .distinct()
-            .filter(pl::place_id.eq(location.id));
-        photos = photos.filter(p::id.eq_any(ids));
-    }
-    if !query.l.exclude.is_empty() {
-        let ids = query.l.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
-        photos = photos.filter(
-            pl::photo_places
-                .select(pl::photo_id)
-                .filter(pl::photo_id.eq(p::id))
-                .filter(pl::place_id.eq_any(ids))
-                .single_value()
-                .is_null(),
-        );
-    }
-    for person in &query.p.include {
-        let ids = pp::photo_people
-            .select(pp::photo_id)
-            .distinct()
-            .filter(pp::person_id.eq(person.id));
-        photos = photos.filter(p::id.eq_any(ids));
-    }
-    if !query.p.exclude.is_empty() {
-        let ids = query.p.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
-        photos = photos.filter(
-            pp::photo_people
-                .select(pp::photo_id)
-                .filter(pp::photo_id.eq(p::id))
-                .filter(pp::person_id.eq_any(ids))
-                .single_value()
-                .is_null(),
-        );
-    }
-
-    if let Some(pos) = query.pos {
-        let pos_ids = pos::positions.select(pos::photo_id);
-        if pos {
-            photos = photos.filter(p::id.eq_any(pos_ids));
-        } else {
-            photos = photos.filter(p::id.ne_all(pos_ids));
-        }
-    }
-
-    let photos = photos
+    let photos = query
+        .filter(Photo::query(context.is_authorized()))
         .order((p::date.desc().nulls_last(), p::id.desc()))
         .left_join(pos::positions)
         .select((
@@ -114,13 +50,16 @@ pub async fn search(
     info!("Executed search statement after {:.3?}", start.elapsed());
 
     let (photos, coords): (Vec<_>, SomeVec<_>) = photos.into_iter().unzip();
+    let coords = context.privacy_zones().apply_all(coords.0);
     info!("Unzipped pics and coords after {:.3?}", start.elapsed());
     let n = photos.len();
-    let links = split_to_group_links(&photos, &query.to_base_url(), true);
+    let stacks = stacks::load(&mut db, &photos).await?;
+    let links =
+        split_to_group_links(&photos, &stacks, &query.to_base_url(), true);
     info!("Grouped links after {:.3?}", start.elapsed());
 
     Ok(Builder::new().html(|o| {
-        templates::search_html(o, &context, &query, n, &links, &coords.0)
+        templates::search_html(o, &context, &query, n, &links, &coords)
     })?)
 }
 
@@ -130,6 +69,8 @@ struct RawQuery {
     people: InclExcl<String>,
     locations: InclExcl<String>,
     pos: Option<bool>,
+    near: Option<Near>,
+    bbox: Option<Bbox>,
     q: String,
     since: DateTimeImg,
     until: DateTimeImg,
@@ -164,6 +105,8 @@ impl TryFrom<Vec<(String, String)>> for RawQuery {
                         }
                     }
                 }
+                "near" if !val.is_empty() => to.near = Some(val.parse()?),
+                "bbox" if !val.is_empty() => to.bbox = Some(val.parse()?),
                 "since_date" if !val.is_empty() => {
                     to.since.date = Some(val.parse().req("since_date")?);
                 }
@@ -205,11 +148,46 @@ pub struct SearchQuery {
     pub since: QueryDateTime,
     pub until: QueryDateTime,
     pub pos: Option<bool>,
+    /// Within a distance from a point.
+    pub near: Option<Near>,
+    /// Inside a bounding box.
+    pub bbox: Option<Bbox>,
     /// Query (free-text, don't know what to do)
     pub q: String,
+    /// Positions in these zones are not found by `near` or `bbox`.
+    hidden: PrivacyZones,
 }
 
 impl SearchQuery {
+    /// Load a query from url query or form parameters.
+    pub async fn from_params(
+        params: Vec<(String, String)>,
+        db: &mut AsyncPgConnection,
+    ) -> Result<Self> {
+        Self::load(params.try_into()?, db).await
+    }
+
+    /// Load a query for the viewer of `context`.
+    ///
+    /// For anonymous viewers, only public people can be searched for,
+    /// and photos in privacy zones are not found by their position
+    /// (otherwise an exact position could be found by searching
+    /// smaller and smaller areas).
+    pub async fn for_viewer(
+        params: Vec<(String, String)>,
+        context: &Context,
+        db: &mut AsyncPgConnection,
+    ) -> Result<Self> {
+        let mut query = Self::from_params(params, db).await?;
+        if !context.is_authorized() {
+            if query.p.into_iter().any(|(person, _)| !person.is_public()) {
+                return Err(ViewError::NotFound(None));
+            }
+            query.hidden = context.privacy_zones().clone();
+        }
+        Ok(query)
+    }
+
     async fn load(
         query: RawQuery,
         db: &mut AsyncPgConnection,
@@ -219,7 +197,10 @@ impl SearchQuery {
             p: InclExcl::load(query.people, db).await?,
             l: InclExcl::load(query.locations, db).await?,
             pos: query.pos,
+            near: query.near,
+            bbox: query.bbox,
             q: query.q,
+            hidden: PrivacyZones::default(),
             since: QueryDateTime::from_raw(
                 &query.since,
                 NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
@@ -234,8 +215,103 @@ impl SearchQuery {
             .await?,
         })
     }
-    fn to_base_url(&self) -> UrlString {
-        let mut result = UrlString::new("/search/");
+    /// Add the conditions of this query to a photo query.
+    pub fn filter<'a>(
+        &self,
+        mut photos: photos::BoxedQuery<'a, Pg>,
+    ) -> photos::BoxedQuery<'a, Pg> {
+        if let Some(since) = self.since.as_ref() {
+            photos = photos.filter(p::date.ge(*since));
+        }
+        if let Some(until) = self.until.as_ref() {
+            photos = photos.filter(p::date.le(*until));
+        }
+        for tag in &self.t.include {
+            let ids = pt::photo_tags
+                .select(pt::photo_id)
+                .distinct()
+                .filter(pt::tag_id.eq(tag.id));
+            photos = photos.filter(p::id.eq_any(ids));
+        }
+        if !self.t.exclude.is_empty() {
+            let ids = self.t.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
+            photos = photos.filter(
+                pt::photo_tags
+                    .select(pt::photo_id)
+                    .filter(pt::photo_id.eq(p::id))
+                    .filter(pt::tag_id.eq_any(ids))
+                    .single_value()
+                    .is_null(),
+            );
+        }
+        for location in &self.l.include {
+            let ids = pl::photo_places
+                .select(pl::photo_id)
+                .distinct()
+                .filter(pl::place_id.eq(location.id));
+            photos = photos.filter(p::id.eq_any(ids));
+        }
+        if !self.l.exclude.is_empty() {
+            let ids = self.l.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
+            photos = photos.filter(
+                pl::photo_places
+                    .select(pl::photo_id)
+                    .filter(pl::photo_id.eq(p::id))
+                    .filter(pl::place_id.eq_any(ids))
+                    .single_value()
+                    .is_null(),
+            );
+        }
+        for person in &self.p.include {
+            let ids = pp::photo_people
+                .select(pp::photo_id)
+                .distinct()
+                .filter(pp::person_id.eq(person.id));
+            photos = photos.filter(p::id.eq_any(ids));
+        }
+        if !self.p.exclude.is_empty() {
+            let ids = self.p.exclude.iter().map(|t| t.id).collect::<Vec<_>>();
+            photos = photos.filter(
+                pp::photo_people
+                    .select(pp::photo_id)
+                    .filter(pp::photo_id.eq(p::id))
+                    .filter(pp::person_id.eq_any(ids))
+                    .single_value()
+                    .is_null(),
+            );
+        }
+
+        if let Some(pos) = self.pos {
+            let pos_ids = pos::positions.select(pos::photo_id);
+            if pos {
+                photos = photos.filter(p::id.eq_any(pos_ids));
+            } else {
+                photos = photos.filter(p::id.ne_all(pos_ids));
+            }
+        }
+        if let Some(near) = &self.near {
+            let ids = pos::positions
+                .select(pos::photo_id)
+                .filter(near.bbox().contains())
+                .filter(near.contains())
+                .filter(self.hidden.outside());
+            photos = photos.filter(p::id.eq_any(ids));
+        }
+        if let Some(bbox) = &self.bbox {
+            let ids = pos::positions
+                .select(pos::photo_id)
+                .filter(bbox.contains())
+                .filter(self.hidden.outside());
+            photos = photos.filter(p::id.eq_any(ids));
+        }
+        photos
+    }
+    pub fn to_base_url(&self) -> UrlString {
+        self.url_at("/search/")
+    }
+    /// Get an url for the same query on another page, like the map.
+    pub fn url_at(&self, base: &str) -> UrlString {
+        let mut result = UrlString::new(base);
         for (t, i) in &self.t {
             result.cond_query("t", i, &t.slug);
         }
@@ -248,10 +324,246 @@ impl SearchQuery {
         if let Some(i) = &self.pos {
             result.cond_query("pos", *i, "t");
         }
+        for (key, time) in [("since", &self.since), ("until", &self.until)] {
+            if let Some(time) = time.as_ref() {
+                result.query(&format!("{key}_date"), time.format("%Y-%m-%d"));
+                result.query(&format!("{key}_time"), time.format("%H:%M:%S"));
+            }
+        }
+        if let Some(near) = &self.near {
+            result.query("near", near);
+        }
+        if let Some(bbox) = &self.bbox {
+            result.query("bbox", bbox);
+        }
         result
     }
 }
 
+/// Command line options for selecting photos like a search.
+#[derive(clap::Parser)]
+pub struct SearchOpt {
+    /// Only photos with this tag (slug), or without it if prefixed
+    /// with `!`.
+    #[clap(long, short)]
+    tag: Vec<String>,
+    /// Only photos of this person (slug), or not of the person if
+    /// prefixed with `!`.
+    #[clap(long, short)]
+    person: Vec<String>,
+    /// Only photos from this place (slug), or not from the place if
+    /// prefixed with `!`.
+    #[clap(long, short = 'l')]
+    place: Vec<String>,
+    /// Only photos taken this date or later.
+    #[clap(long)]
+    since: Option<NaiveDate>,
+    /// Only photos taken this date or earlier.
+    #[clap(long)]
+    until: Option<NaiveDate>,
+}
+
+impl SearchOpt {
+    /// Load the selection as a search query.
+    ///
+    /// Unlike a search on the site, an unknown tag, person or place
+    /// is an error rather than ignored.
+    pub async fn query(
+        &self,
+        db: &mut AsyncPgConnection,
+    ) -> Result<SearchQuery> {
+        let query = SearchQuery::from_params(self.params(), db).await?;
+        if query.t.into_iter().count() != self.tag.len()
+            || query.p.into_iter().count() != self.person.len()
+            || query.l.into_iter().count() != self.place.len()
+        {
+            return Err(ViewError::NotFound(None));
+        }
+        Ok(query)
+    }
+
+    /// The selection as search parameters.
+    fn params(&self) -> Vec<(String, String)> {
+        let tags = self.tag.iter().map(|t| ("t", t.clone()));
+        let people = self.person.iter().map(|p| ("p", p.clone()));
+        let places = self.place.iter().map(|l| ("l", l.clone()));
+        let dates = [("since_date", self.since), ("until_date", self.until)]
+            .into_iter()
+            .filter_map(|(k, d)| Some((k, d?.to_string())));
+        tags.chain(people)
+            .chain(places)
+            .chain(dates)
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+    }
+}
+
+/// A bounding box in microdegrees.
+#[derive(Debug, PartialEq)]
+pub struct Bbox {
+    west: i32,
+    south: i32,
+    east: i32,
+    north: i32,
+}
+
+impl Bbox {
+    /// Sql condition for a position inside the box.
+    ///
+    /// The expression matches the gist index on positions.
+    pub fn contains(&self) -> SqlLiteral<Bool> {
+        sql(&format!(
+            "point(longitude, latitude) <@ box(point({}, {}), point({}, {}))",
+            self.west, self.south, self.east, self.north,
+        ))
+    }
+}
+
+/// Parse `west,south,east,north` in degrees, as given by leaflet.
+///
+/// Values outside of the world (when a map is panned around the date
+/// line) are clamped.
+impl std::str::FromStr for Bbox {
+    type Err = ViewError;
+    fn from_str(val: &str) -> Result<Bbox> {
+        let [west, south, east, north] = parse_degrees(val, "bbox")?;
+        Ok(Bbox {
+            west: micro(west, 180.),
+            south: micro(south, 90.),
+            east: micro(east, 180.),
+            north: micro(north, 90.),
+        })
+    }
+}
+
+impl fmt::Display for Bbox {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            out,
+            "{},{},{},{}",
+            Degrees(self.west),
+            Degrees(self.south),
+            Degrees(self.east),
+            Degrees(self.north),
+        )
+    }
+}
+
+/// A circle around a point, with position in microdegrees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Near {
+    lat: i32,
+    lng: i32,
+    /// Radius in meters.
+    radius: f64,
+}
+
+/// Meters per microdegree of latitude.
+pub(super) const M_PER_UDEG: f64 = 0.111_195;
+
+impl Near {
+    /// A circle around `lat`, `lng` (in degrees), `radius` meters.
+    pub(super) fn new(lat: f64, lng: f64, radius: f64) -> Near {
+        Near {
+            lat: micro(lat, 90.),
+            lng: micro(lng, 180.),
+            radius,
+        }
+    }
+    /// A bounding box around the circle, to use the index.
+    fn bbox(&self) -> Bbox {
+        let dlat = (self.radius / M_PER_UDEG).ceil() as i32;
+        let dlng = (f64::from(dlat) / self.coslat()).min(360e6) as i32;
+        Bbox {
+            west: self.lng.saturating_sub(dlng),
+            south: self.lat.saturating_sub(dlat),
+            east: self.lng.saturating_add(dlng),
+            north: self.lat.saturating_add(dlat),
+        }
+    }
+    /// Sql condition for a position inside the circle.
+    ///
+    /// Uses an equirectangular approximation, which is good enough
+    /// for distances much shorter than the size of the earth.
+    fn contains(&self) -> SqlLiteral<Bool> {
+        sql(&self.condition())
+    }
+    /// The sql condition of [`Near::contains`], as a string.
+    pub(super) fn condition(&self) -> String {
+        let r = self.radius / M_PER_UDEG;
+        format!(
+            "power(latitude - {}, 2) + power((longitude - {}) * {}, 2) <= {}",
+            self.lat,
+            self.lng,
+            self.coslat(),
+            r * r,
+        )
+    }
+    /// True if a position (in microdegrees) is inside the circle.
+    ///
+    /// Same approximation as [`Near::contains`].
+    pub(super) fn covers(&self, lat: i32, lng: i32) -> bool {
+        let dlat = f64::from(lat - self.lat);
+        let dlng = f64::from(lng - self.lng) * self.coslat();
+        let r = self.radius / M_PER_UDEG;
+        dlat * dlat + dlng * dlng <= r * r
+    }
+    pub(super) fn coslat(&self) -> f64 {
+        (f64::from(self.lat) * 1e-6).to_radians().cos().max(1e-3)
+    }
+}
+
+/// Parse `lat,lng,km` with the position in degrees.
+impl std::str::FromStr for Near {
+    type Err = ViewError;
+    fn from_str(val: &str) -> Result<Near> {
+        let [lat, lng, km] = parse_degrees(val, "near")?;
+        if !km.is_finite() || km <= 0. {
+            return Err(ViewError::BadRequest("near"));
+        }
+        Ok(Near::new(lat, lng, km * 1000.))
+    }
+}
+
+impl fmt::Display for Near {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        let (lat, lng) = (Degrees(self.lat), Degrees(self.lng));
+        write!(out, "{lat},{lng},{}", self.radius / 1000.)
+    }
+}
+
+fn parse_degrees<const N: usize>(
+    val: &str,
+    name: &'static str,
+) -> Result<[f64; N]> {
+    val.split(',')
+        .map(|v| v.trim().parse::<f64>().req(name))
+        .collect::<Result<Vec<_>>>()?
+        .try_into()
+        .map_err(|_| ViewError::BadRequest(name))
+}
+
+pub(super) fn micro(val: f64, lim: f64) -> i32 {
+    (val.clamp(-lim, lim) * 1e6).round() as i32
+}
+
+/// Microdegrees displayed as degrees.
+struct Degrees(i32);
+
+impl fmt::Display for Degrees {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let val = self.0.unsigned_abs();
+        let frac = format!("{:06}", val % 1_000_000);
+        let frac = frac.trim_end_matches('0');
+        write!(out, "{sign}{}", val / 1_000_000)?;
+        if !frac.is_empty() {
+            write!(out, ".{frac}")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct InclExcl<T> {
     include: Vec<T>,
@@ -370,3 +682,31 @@ impl templates::ToHtml for QueryTimeFmt<'_> {
         }
     }
 }
+
+#[test]
+fn parse_bbox() {
+    assert_eq!(
+        "17.9,59.2,18.2,59.4".parse::<Bbox>().ok(),
+        Some(Bbox {
+            west: 17_900_000,
+            south: 59_200_000,
+            east: 18_200_000,
+            north: 59_400_000,
+        }),
+    );
+    let wide = "-200,-10,200,10".parse::<Bbox>().ok();
+    assert_eq!(wide.map(|b| b.east), Some(180_000_000));
+    assert!("1,2,3".parse::<Bbox>().is_err());
+    assert!("1,2,x,4".parse::<Bbox>().is_err());
+}
+
+#[test]
+fn near_roundtrip_and_bbox() {
+    let near = "59.3293,18.0686,2.5".parse::<Near>().ok().unwrap();
+    assert_eq!(near.to_string(), "59.3293,18.0686,2.5");
+    let bbox = near.bbox();
+    assert_eq!(bbox.north - near.lat, 22_484);
+    // A degree of longitude is about half as long at this latitude.
+    assert_eq!(bbox.east - near.lng, 44_077);
+    assert!("59,18,0".parse::<Near>().is_err());
+}
@@ -0,0 +1,175 @@
+//! Sitemap for the public parts of the site.
+//!
+//! See <https://www.sitemaps.org/protocol.html> and
+//! <https://developers.google.com/search/docs/crawling-indexing/sitemaps/image-sitemaps>.
+use super::{
+    BaseUrl, BuilderExt, Context, ContextFilter, Result, ViewError, wrap,
+};
+use crate::models::{Photo, SizeTag, Visibility};
+use crate::schema::people::dsl as h;
+use crate::schema::photo_people::dsl as pp;
+use crate::schema::photo_places::dsl as pl;
+use crate::schema::photo_tags::dsl as pt;
+use crate::schema::photos::dsl as p;
+use crate::schema::places::dsl as l;
+use crate::schema::tags::dsl as t;
+use crate::templates;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use std::str::FromStr;
+use warp::filters::BoxedFilter;
+use warp::http::response::Builder;
+use warp::path::{end, param};
+use warp::reply::Response;
+use warp::{Filter, get, path};
+
+/// Number of photos in each photo part of the sitemap.
+///
+/// The protocol allows 50000 urls per sitemap, but smaller parts
+/// are cheaper to create.
+const PHOTOS_PER_PART: i64 = 10_000;
+
+pub fn routes(s: ContextFilter) -> BoxedFilter<(Response,)> {
+    let index = path("sitemap.xml")
+        .and(end())
+        .and(get())
+        .and(s.clone())
+        .then(sitemap_index);
+    let pages = path("sitemap")
+        .and(path("pages.xml"))
+        .and(end())
+        .and(get())
+        .and(s.clone())
+        .then(sitemap_pages);
+    let photos = path("sitemap")
+        .and(param())
+        .and(end())
+        .and(get())
+        .and(s)
+        .then(sitemap_photos);
+    index.or(pages).unify().or(photos).unify().map(wrap).boxed()
+}
+
+async fn sitemap_index(context: Context) -> Result<Response> {
+    let base = or_404!(context.base_url(), context);
+    let n_photos: i64 = Photo::query(false)
+        .count()
+        .get_result(&mut context.db().await?)
+        .await?;
+    let parts = (0..(n_photos + PHOTOS_PER_PART - 1) / PHOTOS_PER_PART)
+        .map(|n| base.join(&PhotosPart(n).to_string()))
+        .collect::<Vec<_>>();
+    let pages = base.join("/sitemap/pages.xml");
+    Builder::new().xml(|o| templates::sitemap_index_xml(o, &pages, &parts))
+}
+
+/// All public list pages; tags, people and places.
+async fn sitemap_pages(context: Context) -> Result<Response> {
+    let base = or_404!(context.base_url(), context);
+    let mut db = context.db().await?;
+    let public_ids = || Photo::query(false).select(p::id);
+    let tags = t::tags
+        .select(t::slug)
+        .filter(
+            t::id.eq_any(
+                pt::photo_tags
+                    .select(pt::tag_id)
+                    .filter(pt::photo_id.eq_any(public_ids())),
+            ),
+        )
+        .order(t::slug)
+        .load::<String>(&mut db)
+        .await?;
+    let people = h::people
+        .select(h::slug)
+        .filter(h::visibility.eq(Visibility::Public.as_i16()))
+        .filter(
+            h::id.eq_any(
+                pp::photo_people
+                    .select(pp::person_id)
+                    .filter(pp::photo_id.eq_any(public_ids())),
+            ),
+        )
+        .order(h::slug)
+        .load::<String>(&mut db)
+        .await?;
+    let places = l::places
+        .select(l::slug)
+        .filter(
+            l::id.eq_any(
+                pl::photo_places
+                    .select(pl::place_id)
+                    .filter(pl::photo_id.eq_any(public_ids())),
+            ),
+        )
+        .order(l::slug)
+        .load::<String>(&mut db)
+        .await?;
+
+    let urls = ["/", "/tag/", "/person/", "/place/"]
+        .into_iter()
+        .map(|path| (base.join(path), None))
+        .chain(tags.iter().map(|s| page(base, "tag", s)))
+        .chain(people.iter().map(|s| page(base, "person", s)))
+        .chain(places.iter().map(|s| page(base, "place", s)))
+        .collect::<Vec<_>>();
+    Builder::new().xml(|o| templates::sitemap_urls_xml(o, &urls))
+}
+
+fn page(base: &BaseUrl, kind: &str, slug: &str) -> (String, Option<String>) {
+    (base.join(&format!("/{kind}/{slug}")), None)
+}
+
+/// Detail pages for public photos, with their images.
+async fn sitemap_photos(
+    part: PhotosPart,
+    context: Context,
+) -> Result<Response> {
+    let base = or_404!(context.base_url(), context);
+    let ids = Photo::query(false)
+        .select(p::id)
+        .order(p::id)
+        .offset(part.0 * PHOTOS_PER_PART)
+        .limit(PHOTOS_PER_PART)
+        .load::<i32>(&mut context.db().await?)
+        .await?;
+    if ids.is_empty() {
+        return Err(ViewError::NotFound(Some(context)));
+    }
+    let urls = ids
+        .into_iter()
+        .map(|id| {
+            let img = format!("/img/{id}-{}.jpg", SizeTag::Medium.tag());
+            (base.join(&format!("/img/{id}")), Some(base.join(&img)))
+        })
+        .collect::<Vec<_>>();
+    Builder::new().xml(|o| templates::sitemap_urls_xml(o, &urls))
+}
+
+/// A part of the photos sitemap, as in `photos-17.xml`.
+struct PhotosPart(i64);
+
+impl FromStr for PhotosPart {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix("photos-")
+            .and_then(|s| s.strip_suffix(".xml"))
+            .and_then(|n| n.parse().ok())
+            .filter(|n| *n >= 0)
+            .map(PhotosPart)
+            .ok_or(())
+    }
+}
+
+impl std::fmt::Display for PhotosPart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/sitemap/photos-{}.xml", self.0)
+    }
+}
+
+#[test]
+fn parse_photos_part() {
+    assert_eq!("photos-17.xml".parse::<PhotosPart>().map(|p| p.0), Ok(17));
+    assert!("photos--1.xml".parse::<PhotosPart>().is_err());
+    assert!("pages.xml".parse::<PhotosPart>().is_err());
+}
@@ -1,3 +1,4 @@
+use super::photolink::{StackPart, imgscore};
 use super::urlstring::UrlString;
 use super::views_by_date::date_of_img;
 use super::{Context, ImgRange, PhotoLink, Result, SomeVec, ViewError};
@@ -5,12 +6,18 @@ use crate::models::{Coord, Photo};
 use crate::schema::photos;
 use crate::schema::photos::dsl as p;
 use crate::schema::positions::dsl as ps;
+use crate::stacks::{self, Stacks};
 use chrono::NaiveDateTime;
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::{HashMap, HashSet};
+use std::iter::once;
 use tracing::debug;
 
+/// Photos hidden in stacks, by the id of the photo shown for the stack.
+pub type Hidden<'a> = HashMap<i32, Vec<&'a Photo>>;
+
 pub async fn links_by_time(
     context: &Context,
     photos: photos::BoxedQuery<'_, Pg>,
@@ -43,10 +50,11 @@ pub async fn links_by_time(
     }
 
     let (photos, positions): (Vec<_>, SomeVec<_>) = photos.into_iter().unzip();
+    let stacks = stacks::load(&mut c, &photos).await?;
     let baseurl = UrlString::new(context.path_without_query());
     Ok((
-        split_to_group_links(&photos, &baseurl, with_date),
-        positions.0,
+        split_to_group_links(&photos, &stacks, &baseurl, with_date),
+        context.privacy_zones().apply_all(positions.0),
     ))
 }
 
@@ -57,15 +65,22 @@ async fn date_of_opt_img(
     date_of_img(db, img?).await
 }
 
+/// Links to the photos, or to groups of them if there are many.
+///
+/// Each stack of similar photos is shown as one photo.  When the
+/// photos are not grouped, the hidden photos of a stack are also
+/// included, marked as hidden under the photo shown.
 pub fn split_to_group_links(
     photos: &[Photo],
+    stacks: &Stacks,
     path: &UrlString,
     with_date: bool,
 ) -> Vec<PhotoLink> {
-    if let Some(groups) = split_to_groups(photos) {
+    let (photos, hidden) = collapse_stacks(photos, stacks);
+    if let Some(groups) = split_to_groups(&photos) {
         groups
             .iter()
-            .map(|g| PhotoLink::for_group(g, path.clone(), with_date))
+            .map(|g| PhotoLink::for_group(g, &hidden, path.clone(), with_date))
             .collect()
     } else {
         let make_link = if with_date {
@@ -73,10 +88,68 @@ pub fn split_to_group_links(
         } else {
             PhotoLink::no_title
         };
-        photos.iter().map(make_link).collect()
+        photos
+            .iter()
+            .flat_map(|photo| {
+                let stack =
+                    hidden.get(&photo.id).map_or(&[][..], Vec::as_slice);
+                let top = PhotoLink {
+                    stack: (!stack.is_empty())
+                        .then_some(StackPart::Top(stack.len() + 1)),
+                    ..make_link(photo)
+                };
+                once(top).chain(stack.iter().map(move |p| PhotoLink {
+                    stack: Some(StackPart::Under(photo.id)),
+                    ..make_link(p)
+                }))
+            })
+            .collect()
     }
 }
 
+/// Keep only one photo of each stack with more than one photo.
+///
+/// The photo kept is the one chosen for a manual stack, or else the
+/// best graded.  Returns the photos kept, in the same order, and the
+/// hidden photos.
+fn collapse_stacks<'a>(
+    photos: &'a [Photo],
+    stacks: &Stacks,
+) -> (Vec<Photo>, Hidden<'a>) {
+    let mut by_top = HashMap::<i32, Vec<&Photo>>::new();
+    for photo in photos {
+        if let Some(stacked) = stacks.get(&photo.id) {
+            by_top.entry(stacked.top_id).or_default().push(photo);
+        }
+    }
+    let mut hidden = Hidden::new();
+    for (top_id, mut stack) in by_top {
+        if stack.len() < 2 {
+            continue;
+        }
+        let manual = stack.iter().any(|p| stacks[&p.id].manual);
+        let shown = stack
+            .iter()
+            .find(|p| manual && p.id == top_id)
+            .or_else(|| stack.iter().rev().max_by_key(|p| imgscore(p)))
+            .map(|p| p.id)
+            .unwrap_or(top_id);
+        stack.retain(|p| p.id != shown);
+        hidden.insert(shown, stack);
+    }
+    let is_hidden = hidden
+        .values()
+        .flatten()
+        .map(|p| p.id)
+        .collect::<HashSet<_>>();
+    let shown = photos
+        .iter()
+        .filter(|p| !is_hidden.contains(&p.id))
+        .cloned()
+        .collect();
+    (shown, hidden)
+}
+
 fn split_to_groups(photos: &[Photo]) -> Option<Vec<&[Photo]>> {
     let wanted_groups = match photos.len() {
         l if l <= 18 => return None,
@@ -148,6 +221,7 @@ fn timestamp(p: &Photo) -> i64 {
 }
 
 #[test]
+#[allow(clippy::zero_prefixed_literal)]
 fn split_two() {
     let photos = [
         Photo::mock(2018, 08, 31, 21, 45, 48),
@@ -157,6 +231,7 @@ fn split_two() {
 }
 
 #[test]
+#[allow(clippy::zero_prefixed_literal)]
 fn split_group_by_time() {
     let photos = [
         Photo::mock(2018, 08, 31, 21, 45, 22),
@@ -173,6 +248,7 @@ fn split_group_by_time() {
 }
 
 #[test]
+#[allow(clippy::zero_prefixed_literal)]
 fn split_group_same_time() {
     let photos = [
         Photo::mock(2018, 08, 31, 21, 45, 22),
@@ -183,6 +259,39 @@ fn split_group_same_time() {
     assert_eq!(paths(split(&photos)), paths((&photos[..2], &photos[2..])));
 }
 
+#[test]
+fn collapse_manual_and_best() {
+    use crate::stacks::Stacked;
+    let mut photos = [
+        Photo::mock(2018, 8, 31, 21, 45, 22),
+        Photo::mock(2018, 8, 31, 21, 45, 21),
+        Photo::mock(2018, 8, 31, 21, 45, 20),
+        Photo::mock(2018, 8, 31, 21, 45, 10),
+        Photo::mock(2018, 8, 31, 21, 45, 9),
+        Photo::mock(2018, 8, 31, 21, 40, 0),
+    ];
+    photos[1].grade = Some(40);
+    photos[4].grade = Some(60);
+    let id = |i: usize| photos[i].id;
+    let stacked = |top_id, manual| Stacked { top_id, manual };
+    let stacks = Stacks::from([
+        (id(0), stacked(id(0), false)),
+        (id(1), stacked(id(0), false)),
+        (id(2), stacked(id(0), false)),
+        (id(3), stacked(id(3), true)),
+        (id(4), stacked(id(3), true)),
+    ]);
+    let (shown, hidden) = collapse_stacks(&photos, &stacks);
+    let ids =
+        |photos: &[&Photo]| photos.iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(
+        ids(&shown.iter().collect::<Vec<_>>()),
+        [id(1), id(3), id(5)]
+    );
+    assert_eq!(ids(&hidden[&id(1)]), [id(0), id(2)]);
+    assert_eq!(ids(&hidden[&id(3)]), [id(4)]);
+}
+
 #[cfg(test)]
 fn paths<'a>(
     (a, b): (&'a [Photo], &'a [Photo]),
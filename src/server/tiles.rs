@@ -0,0 +1,281 @@
+//! Map tile configuration, and an optional caching tile proxy.
+use super::error::{ViewError, ViewResult};
+use super::{Result, wrap};
+use crate::adm::result::Error;
+use reqwest::Client;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use warp::Filter;
+use warp::filters::BoxedFilter;
+use warp::filters::method::get;
+use warp::http::header;
+use warp::http::response::Builder;
+use warp::path::{end, param};
+use warp::reply::Response;
+
+/// Where browsers get map tiles from.
+#[derive(Clone, Debug, clap::Parser)]
+pub struct TileOpt {
+    /// Url template for map tiles, in the form used by leaflet.
+    ///
+    /// Note the tile usage policy of the server, see e.g.
+    /// <https://operations.osmfoundation.org/policies/tiles/>.
+    #[clap(
+        long,
+        env = "RPHOTOS_TILE_URL",
+        default_value = "https://tile.openstreetmap.org/{z}/{x}/{y}.png"
+    )]
+    tile_url: String,
+    /// Attribution (as html) to show on maps.
+    #[clap(
+        long,
+        env = "RPHOTOS_TILE_ATTRIBUTION",
+        default_value = "© <a href=\"https://osm.org/copyright\">OpenStreetMap</a> contributors"
+    )]
+    tile_attribution: String,
+}
+
+impl TileOpt {
+    /// The tiles to use in pages, fetched directly from the tile url.
+    pub fn tiles(&self) -> Tiles {
+        Tiles {
+            url: self.tile_url.clone(),
+            attribution: self.tile_attribution.clone(),
+        }
+    }
+}
+
+/// Options for serving map tiles through rphotos.
+#[derive(Clone, Debug, clap::Parser)]
+pub struct TileProxyOpt {
+    #[clap(flatten)]
+    tiles: TileOpt,
+    /// Serve map tiles from rphotos, caching tiles fetched from the
+    /// tile url in this directory.
+    ///
+    /// Tiles are stored as `{z}/{x}/{y}.png`, so a pre-seeded tile
+    /// directory can be used.
+    #[clap(long, env = "RPHOTOS_TILE_CACHE")]
+    tile_cache: Option<PathBuf>,
+    /// Only serve tiles that exist in the tile cache, never fetch
+    /// any tiles.
+    #[clap(long, requires = "tile_cache")]
+    tile_offline: bool,
+    /// Max zoom level of tiles to fetch for the tile cache.
+    ///
+    /// Tiles with higher zoom are only served if already cached.
+    #[clap(long, env = "RPHOTOS_TILE_MAX_ZOOM", default_value = "19")]
+    tile_max_zoom: u8,
+    /// Max number of tiles to fetch for the tile cache per minute.
+    ///
+    /// Requests for more uncached tiles than that get a "503 Service
+    /// Unavailable" response, rather than overloading the tile server.
+    #[clap(long, env = "RPHOTOS_TILE_FETCH_LIMIT", default_value = "120")]
+    tile_fetch_limit: u32,
+}
+
+impl TileProxyOpt {
+    /// The tiles to use in pages, from the proxy if there is one.
+    pub fn tiles(&self) -> Tiles {
+        let mut tiles = self.tiles.tiles();
+        if self.tile_cache.is_some() {
+            tiles.url = "/tile/{z}/{x}/{y}.png".into();
+        }
+        tiles
+    }
+
+    fn proxy(&self) -> Result<Option<TileProxy>, Error> {
+        let Some(dir) = self.tile_cache.clone() else {
+            return Ok(None);
+        };
+        let client = Client::builder()
+            .user_agent(concat!("rphotos/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| Error::Other(format!("Tile client: {e}")))?;
+        Ok(Some(TileProxy {
+            upstream: (!self.tile_offline)
+                .then(|| self.tiles.tile_url.clone()),
+            dir,
+            client,
+            max_zoom: self.tile_max_zoom,
+            limit: FetchLimit::new(self.tile_fetch_limit),
+        }))
+    }
+}
+
+/// How pages should get map tiles.
+#[derive(Clone, Debug)]
+pub struct Tiles {
+    /// Url template, in the form used by leaflet.
+    pub url: String,
+    /// Attribution, as html.
+    pub attribution: String,
+}
+
+struct TileProxy {
+    /// The url template to fetch tiles from, None when offline.
+    upstream: Option<String>,
+    dir: PathBuf,
+    client: Client,
+    /// Max zoom level of tiles to fetch.
+    max_zoom: u8,
+    limit: FetchLimit,
+}
+
+/// A limit on the number of tiles fetched per minute, shared by all
+/// requests.
+struct FetchLimit {
+    per_minute: u32,
+    /// Start of the current minute, and tiles fetched in it.
+    window: Mutex<(Instant, u32)>,
+}
+
+impl FetchLimit {
+    fn new(per_minute: u32) -> Self {
+        FetchLimit {
+            per_minute,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Count a fetch, or return false if the limit is reached.
+    fn allow(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        if now - window.0 >= Duration::from_secs(60) {
+            *window = (now, 0);
+        }
+        if window.1 < self.per_minute {
+            window.1 += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Routes for `/tile/{z}/{x}/{y}.png`, only found if a tile cache is
+/// configured.
+pub fn routes(opt: &TileProxyOpt) -> Result<BoxedFilter<(Response,)>, Error> {
+    let proxy = Arc::new(opt.proxy()?);
+    if let Some(proxy) = proxy.as_ref() {
+        info!("Serving map tiles cached in {:?}", proxy.dir);
+    }
+    Ok(warp::any()
+        .map(move || proxy.clone())
+        .and(param())
+        .and(param())
+        .and(param())
+        .and(end())
+        .and(get())
+        .then(get_tile)
+        .map(wrap)
+        .boxed())
+}
+
+async fn get_tile(
+    proxy: Arc<Option<TileProxy>>,
+    z: u8,
+    x: u32,
+    y: String,
+) -> Result<Response> {
+    let proxy = or_404!(proxy.as_ref());
+    let y = or_404!(y.strip_suffix(".png").and_then(|y| y.parse().ok()));
+    if z > 20 || x >> z != 0 || y >> z != 0 {
+        return Err(ViewError::NotFound(None));
+    }
+    let path = proxy.dir.join(format!("{z}/{x}/{y}.png"));
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let url = or_404!(&proxy.upstream);
+            if z > proxy.max_zoom {
+                return Err(ViewError::NotFound(None));
+            }
+            if !proxy.limit.allow() {
+                warn!("Tile fetch limit reached, not fetching {z}/{x}/{y}");
+                return Err(ViewError::ServiceUnavailable);
+            }
+            let url = tile_url(url, z, x, y);
+            debug!("Fetching tile {}", url);
+            let response = proxy
+                .client
+                .get(&url)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| {
+                    warn!("Failed to fetch tile {}: {}", url, e);
+                    ViewError::ServiceUnavailable
+                })?;
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|t| t.to_str().ok())
+                .unwrap_or_default();
+            if !content_type.starts_with("image/") {
+                warn!("Not caching tile {url} of type {content_type:?}");
+                return Err(ViewError::ServiceUnavailable);
+            }
+            let data = response.bytes().await.ise()?;
+            save_tile(&path, &data).await.ise()?;
+            data.to_vec()
+        }
+        Err(e) => return Err(e).ise(),
+    };
+    Builder::new()
+        .header(header::CONTENT_TYPE, mime::IMAGE_PNG.as_ref())
+        .header(header::CACHE_CONTROL, "max-age=604800")
+        .body(data.into())
+        .ise()
+}
+
+/// Write a tile to the cache.
+///
+/// The tile is written to a temporary file that is renamed into
+/// place, so a concurrent request never reads a partial tile.
+async fn save_tile(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    tokio::fs::create_dir_all(dir).await?;
+    let n = SEQ.fetch_add(1, Ordering::Relaxed);
+    let tmp = dir.join(format!(".tile-{}-{n}.tmp", std::process::id()));
+    let result = match tokio::fs::write(&tmp, data).await {
+        Ok(()) => tokio::fs::rename(&tmp, path).await,
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result
+}
+
+/// Fill in a leaflet-style url template for a tile.
+fn tile_url(template: &str, z: u8, x: u32, y: u32) -> String {
+    template
+        .replace("{s}", "a")
+        .replace("{r}", "")
+        .replace("{z}", &z.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+}
+
+#[test]
+fn fill_tile_url() {
+    assert_eq!(
+        tile_url("https://{s}.tile.example.org/{z}/{x}/{y}{r}.png", 3, 4, 5),
+        "https://a.tile.example.org/3/4/5.png",
+    );
+}
+
+#[test]
+fn fetch_limit() {
+    let limit = FetchLimit::new(2);
+    assert!(limit.allow());
+    assert!(limit.allow());
+    assert!(!limit.allow());
+}
@@ -1,4 +1,5 @@
 use std::fmt::{Display, Write};
+use std::str::FromStr;
 
 #[derive(Clone, Debug)]
 pub struct UrlString {
@@ -48,3 +49,43 @@ impl AsRef<str> for UrlString {
         &self.value
     }
 }
+
+/// The public base url of the site, without a trailing slash.
+///
+/// Used to create absolute urls where relative urls are not
+/// accepted, such as for Open Graph metadata and in the sitemap.
+#[derive(Clone, Debug)]
+pub struct BaseUrl(String);
+
+impl BaseUrl {
+    /// Create an absolute url for a site-relative path.
+    pub fn join(&self, path: &str) -> String {
+        format!("{}{}", self.0, path)
+    }
+}
+
+impl FromStr for BaseUrl {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_end_matches('/');
+        if !(s.starts_with("https://") || s.starts_with("http://")) {
+            Err("base url must start with https:// or http://")
+        } else if s.contains(['?', '#']) {
+            Err("base url must not have a query or fragment")
+        } else {
+            Ok(BaseUrl(s.into()))
+        }
+    }
+}
+
+#[test]
+fn base_url_join() {
+    let base: BaseUrl = "https://img.example.org/".parse().unwrap();
+    assert_eq!(base.join("/img/17"), "https://img.example.org/img/17");
+}
+
+#[test]
+fn base_url_bad() {
+    assert!("img.example.org".parse::<BaseUrl>().is_err());
+    assert!("https://img.example.org/?x".parse::<BaseUrl>().is_err());
+}
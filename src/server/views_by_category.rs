@@ -1,7 +1,8 @@
 //! Handle photos by tag, person, or place.
 use super::splitlist::links_by_time;
 use super::{
-    Context, ContextFilter, ImgRange, RenderRucte, Result, ViewError, wrap,
+    Context, ContextFilter, ImgRange, Link, RenderRucte, Result, ViewError,
+    wrap,
 };
 use crate::models::{Person, Photo, Place, Tag};
 use crate::schema::people::dsl as h;
@@ -14,6 +15,7 @@ use crate::schema::tags::dsl as t;
 use crate::templates;
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
+use std::collections::{BTreeMap, BTreeSet};
 use warp::Filter;
 use warp::filters::BoxedFilter;
 use warp::filters::method::get;
@@ -54,7 +56,7 @@ pub fn tag_routes(s: ContextFilter) -> BoxedFilter<(Response,)> {
 }
 
 async fn person_all(context: Context) -> Result<Response> {
-    let query = h::people.into_boxed();
+    let query = Person::query(context.is_authorized());
     let query = if context.is_authorized() {
         query
     } else {
@@ -82,7 +84,7 @@ async fn person_one(
 ) -> Result<Response> {
     let mut c = context.db().await?;
     let person = or_404q!(
-        h::people
+        Person::query(context.is_authorized())
             .filter(h::slug.eq(tslug))
             .first::<Person>(&mut c)
             .await,
@@ -157,10 +159,86 @@ async fn place_all(context: Context) -> Result<Response> {
         .order(l::place_name)
         .load(&mut context.db().await?)
         .await?;
-    Ok(
-        Builder::new()
-            .html(|o| templates::places_html(o, &context, &found))?,
-    )
+    let tree = PlaceTree::new(found);
+    Ok(Builder::new().html(|o| templates::places_html(o, &context, &tree))?)
+}
+
+/// A step in a depth-first walk of a [`PlaceTree`].
+pub enum TreeNode<'a> {
+    /// A place without children.
+    Leaf(&'a Place),
+    /// A place whose children follow, up to the matching `Close`.
+    Open(&'a Place),
+    Close,
+}
+
+/// Places as a tree, where each place is listed under its parent.
+///
+/// A place whose parent is not among the places is a root.
+pub struct PlaceTree {
+    children: BTreeMap<Option<i32>, Vec<Place>>,
+}
+
+impl PlaceTree {
+    /// Create a tree from places sorted by name.
+    pub fn new(places: Vec<Place>) -> Self {
+        let ids = places.iter().map(|p| p.id).collect::<BTreeSet<_>>();
+        let mut children = BTreeMap::<_, Vec<_>>::new();
+        for place in places {
+            let parent = place.parent_id.filter(|id| ids.contains(id));
+            children.entry(parent).or_default().push(place);
+        }
+        PlaceTree { children }
+    }
+
+    /// Get the places directly under `parent`, or the roots for `None`.
+    pub fn children(&self, parent: Option<i32>) -> &[Place] {
+        self.children.get(&parent).map_or(&[], Vec::as_slice)
+    }
+
+    /// Get the tree in depth-first order, for rendering as nested lists.
+    pub fn nodes(&self) -> Vec<TreeNode<'_>> {
+        let mut result = Vec::new();
+        self.add_nodes(None, &mut result);
+        result
+    }
+
+    fn add_nodes<'a>(
+        &'a self,
+        parent: Option<i32>,
+        to: &mut Vec<TreeNode<'a>>,
+    ) {
+        for place in self.children(parent) {
+            if self.children(Some(place.id)).is_empty() {
+                to.push(TreeNode::Leaf(place));
+            } else {
+                to.push(TreeNode::Open(place));
+                self.add_nodes(Some(place.id), to);
+                to.push(TreeNode::Close);
+            }
+        }
+    }
+
+    /// Get the places containing a place, outermost first.
+    pub fn ancestors(&self, place: &Place) -> Vec<&Place> {
+        let by_id = self
+            .children
+            .values()
+            .flatten()
+            .map(|p| (p.id, p))
+            .collect::<BTreeMap<_, _>>();
+        let mut result = Vec::new();
+        let mut parent = place.parent_id;
+        while let Some(p) = parent.and_then(|id| by_id.get(&id)) {
+            if result.len() > by_id.len() {
+                break; // A loop in the data, don't hang.
+            }
+            result.push(*p);
+            parent = p.parent_id;
+        }
+        result.reverse();
+        result
+    }
 }
 
 async fn place_one(
@@ -184,7 +262,21 @@ async fn place_one(
         ),
     );
     let (links, coord) = links_by_time(&context, photos, range, true).await?;
+    let mut db = context.db().await?;
+    let mut ancestors = place.ancestors(&mut db).await?;
+    if !context.is_authorized() {
+        // Don't show places without public photos to anonymous viewers.
+        let public = pl::photo_places
+            .select(pl::place_id)
+            .distinct()
+            .filter(pl::place_id.eq_any(ancestors.iter().map(|a| a.id)))
+            .filter(pl::photo_id.eq_any(Photo::query(false).select(p::id)))
+            .load::<i32>(&mut db)
+            .await?;
+        ancestors.retain(|a| public.contains(&a.id));
+    }
+    let lpath = ancestors.iter().map(Link::place).collect::<Vec<_>>();
     Ok(Builder::new().html(|o| {
-        templates::place_html(o, &context, &links, &coord, &place)
+        templates::place_html(o, &context, &lpath, &links, &coord, &place)
     })?)
 }
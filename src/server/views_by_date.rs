@@ -3,7 +3,7 @@ use super::{
     Context, ContextFilter, ImgRange, Link, PhotoLink, Result, ViewError,
     redirect, redirect_to_img, wrap,
 };
-use crate::models::{Photo, SizeTag};
+use crate::models::{DatePrecision, Photo, SizeTag};
 use crate::schema::photos::dsl as p;
 use crate::schema::positions::dsl as ps;
 use crate::server::error::ViewResult as _;
@@ -79,6 +79,8 @@ pub fn routes(s: ContextFilter) -> BoxedFilter<(Response,)> {
         .boxed()
 }
 
+// Photos are intentionally grouped by their local `date`, the
+// calendar day where they were taken, not by `date_utc`.
 define_sql_function! {
     #[aggregate]
     fn year_of_timestamp(date: Nullable<Timestamp>) -> Nullable<SmallInt>
@@ -112,6 +114,7 @@ async fn all_years(context: Context) -> Result<Response> {
     let groups_in = p::photos
         .filter(p::path.not_like("%.CR2"))
         .filter(p::path.not_like("%.dng"))
+        .filter(p::duplicate_of.is_null())
         .filter(p::is_public.or::<_, Bool>(context.is_authorized()))
         .select((y, count_star()))
         .group_by(y)
@@ -139,6 +142,7 @@ async fn all_years(context: Context) -> Result<Response> {
             lable: Some(format!("{count} images")),
             id: photo.id,
             size: photo.get_size(SizeTag::Small),
+            stack: None,
         });
     }
 
@@ -153,26 +157,45 @@ async fn months_in_year(year: i32, context: Context) -> Result<Response> {
     let m = month_of_timestamp(p::date);
     let start = or_404!(start_of_year(year).ok(), context);
     let end = or_404!(start_of_year(year + 1).ok(), context);
+    let year_prec = DatePrecision::Year.as_i16();
     let groups_in = p::photos
         .filter(p::path.not_like("%.CR2"))
         .filter(p::path.not_like("%.dng"))
+        .filter(p::duplicate_of.is_null())
         .filter(p::is_public.or::<_, Bool>(context.is_authorized()))
         .filter(p::date.ge(start))
         .filter(p::date.lt(end))
+        .filter(p::date_precision.lt(year_prec))
         .select((m, count_star()))
         .group_by(m)
         .order(m.desc().nulls_last())
         .load::<(Option<i16>, i64)>(&mut db)
         .await?;
-    if groups_in.is_empty() {
+    // Photos with only a known year (or decade) are stored at the
+    // start of the year (or decade), but shown as separate groups.
+    let approximate = |precision: DatePrecision| {
+        Photo::query(context.is_authorized())
+            .filter(p::date.ge(start))
+            .filter(p::date.lt(end))
+            .filter(p::date_precision.eq(precision.as_i16()))
+            .order((p::grade.desc().nulls_last(), p::date.asc()))
+    };
+    let in_year = approximate(DatePrecision::Year)
+        .load::<Photo>(&mut db)
+        .await?;
+    let in_decade = approximate(DatePrecision::Decade)
+        .load::<Photo>(&mut db)
+        .await?;
+    if groups_in.is_empty() && in_year.is_empty() && in_decade.is_empty() {
         return Err(ViewError::NotFound(Some(context)));
     }
-    let mut groups = Vec::with_capacity(groups_in.len());
+    let mut groups = Vec::with_capacity(groups_in.len() + 2);
     for (month, count) in groups_in {
         let month = month.unwrap() as u32; // cant be null when in range!
         let photo = Photo::query(context.is_authorized())
             .filter(p::date.ge(start_of_month(year, month).ise()?))
             .filter(p::date.lt(start_of_month(year, month + 1).ise()?))
+            .filter(p::date_precision.lt(year_prec))
             .order((p::grade.desc().nulls_last(), p::date.asc()))
             .limit(1)
             .first::<Photo>(&mut db)
@@ -184,6 +207,27 @@ async fn months_in_year(year: i32, context: Context) -> Result<Response> {
             lable: Some(format!("{count} pictures")),
             id: photo.id,
             size: photo.get_size(SizeTag::Small),
+            stack: None,
+        });
+    }
+    if let [photo, ..] = in_year.as_slice() {
+        groups.push(PhotoLink {
+            title: Some("Unknown month".into()),
+            href: format!("/{year}/0/"),
+            lable: Some(format!("{} pictures", in_year.len())),
+            id: photo.id,
+            size: photo.get_size(SizeTag::Small),
+            stack: None,
+        });
+    }
+    if let [photo, ..] = in_decade.as_slice() {
+        groups.push(PhotoLink {
+            title: Some(format!("Some time in the {year}s")),
+            href: format!("/{year}/0/0"),
+            lable: Some(format!("{} pictures", in_decade.len())),
+            id: photo.id,
+            size: photo.get_size(SizeTag::Small),
+            stack: None,
         });
     }
 
@@ -195,8 +239,8 @@ async fn months_in_year(year: i32, context: Context) -> Result<Response> {
         .load(&mut db)
         .await?
         .into_iter()
-        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id))
-        .collect::<Vec<_>>();
+        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id));
+    let pos = context.privacy_zones().apply_all(pos);
     Ok(Builder::new().html(|o| {
         templates::index_html(o, &context, &title, &[], &groups, &pos)
     })?)
@@ -243,6 +287,9 @@ async fn days_in_month(
     month: u32,
     context: Context,
 ) -> Result<Response> {
+    if month == 0 {
+        return approximate_in_year(year, context).await;
+    }
     let start = or_404!(start_of_month(year, month).ok(), context);
     let end = or_404!(start_of_month(year, month + 1).ok(), context);
     if start.year() != year || start.month() != month {
@@ -253,27 +300,38 @@ async fn days_in_month(
     let lpath: Vec<Link> = vec![Link::year(year)];
     let title: String = format!("Photos from {} {}", monthname(month), year);
     let mut db = context.db().await?;
+    let month_prec = DatePrecision::Month.as_i16();
     let groups_in = p::photos
         .filter(p::path.not_like("%.CR2"))
         .filter(p::path.not_like("%.dng"))
+        .filter(p::duplicate_of.is_null())
         .filter(p::is_public.or::<_, Bool>(context.is_authorized()))
         .filter(p::date.ge(start))
         .filter(p::date.lt(end))
+        .filter(p::date_precision.lt(month_prec))
         .select((d, count_star()))
         .group_by(d)
         .order(d.desc().nulls_last())
         .load::<(Option<i16>, i64)>(&mut db)
         .await?;
-    if groups_in.is_empty() {
+    let approximate = Photo::query(context.is_authorized())
+        .filter(p::date.ge(start))
+        .filter(p::date.lt(end))
+        .filter(p::date_precision.eq(month_prec))
+        .order((p::grade.desc().nulls_last(), p::date.asc()))
+        .load::<Photo>(&mut db)
+        .await?;
+    if groups_in.is_empty() && approximate.is_empty() {
         return Err(ViewError::NotFound(Some(context)));
     }
-    let mut groups = Vec::with_capacity(groups_in.len());
+    let mut groups = Vec::with_capacity(groups_in.len() + 1);
     for (day, count) in groups_in {
         let day = day.unwrap() as u32;
         let fromdate = start_of_day(year, month, day).ise()?;
         let photo = Photo::query(context.is_authorized())
             .filter(p::date.ge(fromdate))
             .filter(p::date.lt(fromdate + Duration::days(1)))
+            .filter(p::date_precision.lt(month_prec))
             .order((p::grade.desc().nulls_last(), p::date.asc()))
             .limit(1)
             .first::<Photo>(&mut db)
@@ -285,6 +343,17 @@ async fn days_in_month(
             lable: Some(format!("{count} pictures")),
             id: photo.id,
             size: photo.get_size(SizeTag::Small),
+            stack: None,
+        });
+    }
+    if let [photo, ..] = approximate.as_slice() {
+        groups.push(PhotoLink {
+            title: Some("Unknown day".into()),
+            href: format!("/{year}/{month}/0"),
+            lable: Some(format!("{} pictures", approximate.len())),
+            id: photo.id,
+            size: photo.get_size(SizeTag::Small),
+            stack: None,
         });
     }
 
@@ -296,13 +365,73 @@ async fn days_in_month(
         .load(&mut db)
         .await?
         .into_iter()
-        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id))
-        .collect::<Vec<_>>();
+        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id));
+    let pos = context.privacy_zones().apply_all(pos);
     Ok(Builder::new().html(|o| {
         templates::index_html(o, &context, &title, &lpath, &groups, &pos)
     })?)
 }
 
+async fn approximate_in_year(year: i32, context: Context) -> Result<Response> {
+    let start = or_404!(start_of_year(year).ok(), context);
+    let end = or_404!(start_of_year(year + 1).ok(), context);
+    let title = format!("Photos from some time in {year}");
+    let lpath = [Link::year(year)];
+    let precision = (DatePrecision::Year, DatePrecision::Year);
+    approximate_in(context, (start, end), precision, &title, &lpath).await
+}
+
+async fn approximate_in_decade(
+    year: i32,
+    context: Context,
+) -> Result<Response> {
+    let start = or_404!(start_of_year(year).ok(), context);
+    let end = or_404!(start_of_year(year + 10).ok(), context);
+    let title = format!("Photos from some time in the {year}s");
+    let lpath = [Link::year(year)];
+    let precision = (DatePrecision::Decade, DatePrecision::Decade);
+    approximate_in(context, (start, end), precision, &title, &lpath).await
+}
+
+async fn approximate_in_month(
+    year: i32,
+    month: u32,
+    context: Context,
+) -> Result<Response> {
+    let start = or_404!(start_of_month(year, month).ok(), context);
+    let end = or_404!(start_of_month(year, month + 1).ok(), context);
+    let title =
+        format!("Photos from some time in {} {year}", monthname(month));
+    let lpath = [Link::year(year), Link::month(year, month)];
+    let precision = (DatePrecision::Month, DatePrecision::Month);
+    approximate_in(context, (start, end), precision, &title, &lpath).await
+}
+
+/// List photos in a time span with a date of some (min, max) precision.
+async fn approximate_in(
+    context: Context,
+    (start, end): (NaiveDateTime, NaiveDateTime),
+    (min, max): (DatePrecision, DatePrecision),
+    title: &str,
+    lpath: &[Link],
+) -> Result<Response> {
+    let mut db = context.db().await?;
+    let photos = Photo::query(context.is_authorized())
+        .filter(p::date.ge(start))
+        .filter(p::date.lt(end))
+        .filter(p::date_precision.between(min.as_i16(), max.as_i16()))
+        .order((p::date, p::id))
+        .load::<Photo>(&mut db)
+        .await?;
+    if photos.is_empty() {
+        return Err(ViewError::NotFound(Some(context)));
+    }
+    let links = photos.iter().map(PhotoLink::no_title).collect::<Vec<_>>();
+    Ok(Builder::new().html(|o| {
+        templates::index_html(o, &context, title, lpath, &links, &[])
+    })?)
+}
+
 async fn all_null_date(context: Context) -> Result<Response> {
     let images = Photo::query(context.is_authorized())
         .filter(p::date.is_null())
@@ -332,20 +461,28 @@ async fn all_for_day(
     range: ImgRange,
     context: Context,
 ) -> Result<Response> {
+    if day == 0 && month == 0 {
+        return approximate_in_decade(year, context).await;
+    }
+    if day == 0 {
+        return approximate_in_month(year, month, context).await;
+    }
     let thedate = or_404!(start_of_day(year, month, day).ok());
 
     let photos = Photo::query(context.is_authorized())
         .filter(p::date.ge(thedate))
-        .filter(p::date.lt(thedate + Duration::days(1)));
+        .filter(p::date.lt(thedate + Duration::days(1)))
+        .filter(p::date_precision.lt(DatePrecision::Month.as_i16()));
     let (links, coords) =
         links_by_time(&context, photos, range, false).await?;
 
     Ok(Builder::new().html(|o| {
-        templates::index_html(
+        templates::day_html(
             o,
             &context,
             &format!("Photos from {} {} {}", day, monthname(month), year),
             &[Link::year(year), Link::month(year, month)],
+            thedate.date(),
             &links,
             &coords,
         )
@@ -362,20 +499,23 @@ async fn on_this_day(context: Context) -> Result<Response> {
         .inner_join(ps::positions)
         .filter(filter::month_of_timestamp(p::date).eq(month as i16))
         .filter(filter::day_of_timestamp(p::date).eq(day as i16))
+        .filter(p::date_precision.lt(DatePrecision::Month.as_i16()))
         .select((ps::photo_id, ps::latitude, ps::longitude))
         .load(&mut db)
         .await?
         .into_iter()
-        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id))
-        .collect::<Vec<_>>();
+        .map(|(p_id, lat, long): (i32, i32, i32)| ((lat, long).into(), p_id));
+    let pos = context.privacy_zones().apply_all(pos);
 
     let y = year_of_timestamp(p::date);
     let photos_in = p::photos
         .filter(p::path.not_like("%.CR2"))
         .filter(p::path.not_like("%.dng"))
+        .filter(p::duplicate_of.is_null())
         .filter(p::is_public.or::<_, Bool>(context.is_authorized()))
         .filter(filter::month_of_timestamp(p::date).eq(month as i16))
         .filter(filter::day_of_timestamp(p::date).eq(day as i16))
+        .filter(p::date_precision.lt(DatePrecision::Month.as_i16()))
         .select((y, count_star()))
         .group_by(y)
         .order(y.desc())
@@ -388,6 +528,7 @@ async fn on_this_day(context: Context) -> Result<Response> {
         let photo = Photo::query(context.is_authorized())
             .filter(p::date.ge(fromdate))
             .filter(p::date.lt(fromdate + Duration::days(1)))
+            .filter(p::date_precision.lt(DatePrecision::Month.as_i16()))
             .order((p::grade.desc().nulls_last(), p::date.asc()))
             .limit(1)
             .first::<Photo>(&mut db)
@@ -398,6 +539,7 @@ async fn on_this_day(context: Context) -> Result<Response> {
             lable: Some(format!("{count} pictures")),
             id: photo.id,
             size: photo.get_size(SizeTag::Small),
+            stack: None,
         });
     }
     Ok(Builder::new().html(|o| {
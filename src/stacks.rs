@@ -0,0 +1,399 @@
+//! Stacks of similar photos taken close in time, such as bursts.
+//!
+//! A stack is shown as one photo in list views, with the others
+//! hidden until the stack is expanded.  Stacks are found from the
+//! date and perceptual hash of the photos.  When an admin chooses the
+//! photo shown for a stack or takes a photo out of it, the stack is
+//! manual, and is not changed when stacks are found again.  A photo
+//! taken out of its stack is not stacked again.
+use crate::duplicates::distance;
+use crate::models::{DatePrecision, Photo};
+use crate::schema::photo_hashes::dsl as ph;
+use crate::schema::photo_stacks::dsl as s;
+use crate::schema::photo_unstacked::dsl as u;
+use crate::schema::photos::dsl as p;
+use chrono::{NaiveDateTime, TimeDelta};
+use diesel::dsl::{count_star, max, min, not};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::collections::HashMap;
+
+/// Max number of seconds between two photos in a stack.
+pub const MAX_SECONDS: i64 = 5;
+/// Max number of differing hash bits between two photos in a stack.
+pub const MAX_DISTANCE: u32 = 12;
+
+/// How a photo is stacked.
+#[derive(Clone, Copy, Debug)]
+pub struct Stacked {
+    /// The photo to show for the stack, if the stack is manual.
+    pub top_id: i32,
+    pub manual: bool,
+}
+
+/// The stacks of a list of photos, by photo id.
+pub type Stacks = HashMap<i32, Stacked>;
+
+/// Load the stacks of some photos.
+pub async fn load(
+    db: &mut AsyncPgConnection,
+    photos: &[Photo],
+) -> Result<Stacks, Error> {
+    let ids = photos.iter().map(|photo| photo.id).collect::<Vec<_>>();
+    Ok(s::photo_stacks
+        .select((s::photo_id, s::top_id, s::manual))
+        .filter(s::photo_id.eq_any(ids))
+        .load::<(i32, i32, bool)>(db)
+        .await?
+        .into_iter()
+        .map(|(id, top_id, manual)| (id, Stacked { top_id, manual }))
+        .collect())
+}
+
+/// The stack of a single photo, for its details page.
+///
+/// A photo taken out of its stack is a manual stack of one.
+pub struct PhotoStack {
+    pub top_id: i32,
+    pub manual: bool,
+    /// Number of photos in the stack.
+    pub size: i64,
+}
+
+impl PhotoStack {
+    pub async fn load(
+        db: &mut AsyncPgConnection,
+        photo_id: i32,
+    ) -> Result<Option<Self>, Error> {
+        let Some((top_id, manual)) = s::photo_stacks
+            .find(photo_id)
+            .select((s::top_id, s::manual))
+            .first::<(i32, bool)>(db)
+            .await
+            .optional()?
+        else {
+            let unstacked = u::photo_unstacked
+                .find(photo_id)
+                .select(u::photo_id)
+                .first::<i32>(db)
+                .await
+                .optional()?;
+            return Ok(unstacked.map(|top_id| PhotoStack {
+                top_id,
+                manual: true,
+                size: 1,
+            }));
+        };
+        let size = s::photo_stacks
+            .select(count_star())
+            .filter(s::top_id.eq(top_id))
+            .first(db)
+            .await?;
+        Ok(Some(PhotoStack {
+            top_id,
+            manual,
+            size,
+        }))
+    }
+}
+
+/// Find stacks among all photos that are not in manual stacks.
+///
+/// Returns the number of stacks found.
+pub async fn update_stacks(
+    db: &mut AsyncPgConnection,
+) -> Result<usize, Error> {
+    store_stacks(db, None).await
+}
+
+/// Find stacks among photos taken close to `dates`, e.g. the dates
+/// of new or rehashed photos.
+///
+/// Returns the number of stacks found.
+pub async fn update_stacks_near(
+    db: &mut AsyncPgConnection,
+    mut dates: Vec<NaiveDateTime>,
+) -> Result<usize, Error> {
+    dates.sort_unstable();
+    let margin = TimeDelta::seconds(MAX_SECONDS);
+    let mut ranges = Vec::<(NaiveDateTime, NaiveDateTime)>::new();
+    for date in dates {
+        let (from, to) = widen(db, date - margin, date + margin).await?;
+        match ranges.last_mut() {
+            Some((_, last)) if from <= *last => *last = to.max(*last),
+            _ => ranges.push((from, to)),
+        }
+    }
+    let mut n = 0;
+    for range in ranges {
+        n += store_stacks(db, Some(range)).await?;
+    }
+    Ok(n)
+}
+
+/// Find stacks among photos that are not in manual stacks, taken in
+/// `range` if given, and replace the automatic stacks there.
+///
+/// The range must not split an automatic stack, see [`widen`].
+async fn store_stacks(
+    db: &mut AsyncPgConnection,
+    range: Option<(NaiveDateTime, NaiveDateTime)>,
+) -> Result<usize, Error> {
+    let manual = s::photo_stacks.select(s::photo_id).filter(s::manual);
+    let unstacked = u::photo_unstacked.select(u::photo_id);
+    let mut query = Photo::query(true)
+        .inner_join(ph::photo_hashes)
+        .filter(ph::phash.is_not_null())
+        .filter(p::date_precision.eq(DatePrecision::Exact.as_i16()))
+        .filter(not(p::id.eq_any(manual)))
+        .filter(not(p::id.eq_any(unstacked)))
+        .select((p::id, p::date, p::grade, ph::phash.assume_not_null()))
+        .order((p::date, p::id));
+    let mut delete =
+        diesel::delete(s::photo_stacks.filter(not(s::manual))).into_boxed();
+    if let Some((from, to)) = range {
+        query = query.filter(p::date.between(from, to));
+        delete = delete.filter(s::photo_id.eq_any(
+            p::photos.select(p::id).filter(p::date.between(from, to)),
+        ));
+    }
+    let photos = query
+        .load::<(i32, Option<NaiveDateTime>, Option<i16>, i64)>(db)
+        .await?;
+    let grades = photos
+        .iter()
+        .map(|(id, _, grade, _)| (*id, *grade))
+        .collect::<HashMap<_, _>>();
+    let photos = photos
+        .iter()
+        .filter_map(|(id, date, _, hash)| {
+            Some((*id, date.as_ref()?.and_utc().timestamp(), *hash))
+        })
+        .collect::<Vec<_>>();
+    let stacks = find_stacks(&photos);
+    let rows = stacks
+        .iter()
+        .flat_map(|stack| {
+            // The first of the highest graded photos.
+            let top = stack.iter().rev().max_by_key(|id| grades[id]);
+            let top = *top.unwrap_or(&stack[0]);
+            stack
+                .iter()
+                .map(move |id| (s::photo_id.eq(*id), s::top_id.eq(top)))
+        })
+        .collect::<Vec<_>>();
+    db.transaction(|db| {
+        async move {
+            delete.execute(db).await?;
+            for chunk in rows.chunks(10_000) {
+                diesel::insert_into(s::photo_stacks)
+                    .values(chunk)
+                    .on_conflict_do_nothing()
+                    .execute(db)
+                    .await?;
+            }
+            Ok::<_, Error>(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+    Ok(stacks.len())
+}
+
+/// Show a photo for its stack.
+///
+/// Returns false if the photo is not in a stack.
+pub async fn set_top(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<bool, Error> {
+    let Some(top) = top_of(db, photo_id).await? else {
+        return Ok(false);
+    };
+    diesel::update(s::photo_stacks.filter(s::top_id.eq(top)))
+        .set((s::top_id.eq(photo_id), s::manual.eq(true)))
+        .execute(db)
+        .await?;
+    Ok(true)
+}
+
+/// Take a photo out of its stack.
+///
+/// The rest of the stack is kept as a manual stack, and the photo is
+/// not stacked automatically again.
+pub async fn unstack(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<(), Error> {
+    db.transaction(|db| {
+        async move { unstack_in(db, photo_id).await }.scope_boxed()
+    })
+    .await
+}
+
+async fn unstack_in(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<(), Error> {
+    if let Some(top) = top_of(db, photo_id).await? {
+        let others = s::photo_stacks
+            .select(s::photo_id)
+            .filter(s::top_id.eq(top))
+            .filter(s::photo_id.ne(photo_id))
+            .order(s::photo_id)
+            .load::<i32>(db)
+            .await?;
+        let top = if top == photo_id {
+            others[..].first().copied()
+        } else {
+            Some(top)
+        };
+        let rest = s::photo_stacks.filter(s::photo_id.eq_any(&others));
+        match top {
+            Some(top) if others.len() > 1 => {
+                diesel::update(rest)
+                    .set((s::top_id.eq(top), s::manual.eq(true)))
+                    .execute(db)
+                    .await?;
+            }
+            // A single photo left is not a stack.
+            _ => {
+                diesel::delete(rest).execute(db).await?;
+            }
+        }
+        diesel::delete(s::photo_stacks.find(photo_id))
+            .execute(db)
+            .await?;
+    }
+    diesel::insert_into(u::photo_unstacked)
+        .values(u::photo_id.eq(photo_id))
+        .on_conflict_do_nothing()
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Forget the manual stack of a photo, or that it was taken out of
+/// its stack, and find stacks again.
+///
+/// Only photos taken close to the stack are considered, so this is
+/// cheap enough to do in a request.
+pub async fn reset(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<(), Error> {
+    diesel::delete(u::photo_unstacked.find(photo_id))
+        .execute(db)
+        .await?;
+    let top = top_of(db, photo_id).await?.unwrap_or(photo_id);
+    let members = s::photo_stacks
+        .select(s::photo_id)
+        .filter(s::top_id.eq(top));
+    let (first, last) = p::photos
+        .select((min(p::date), max(p::date)))
+        .filter(p::id.eq(photo_id).or(p::id.eq_any(members)))
+        .first::<(Option<NaiveDateTime>, Option<NaiveDateTime>)>(db)
+        .await?;
+    diesel::delete(s::photo_stacks.filter(s::top_id.eq(top)))
+        .execute(db)
+        .await?;
+    if let (Some(first), Some(last)) = (first, last) {
+        let margin = TimeDelta::seconds(MAX_SECONDS);
+        let range = widen(db, first - margin, last + margin).await?;
+        store_stacks(db, Some(range)).await?;
+    }
+    Ok(())
+}
+
+/// Widen a time range to cover all automatic stacks with photos in
+/// it.
+///
+/// Photos just outside the widened range are not similar to the
+/// photos at its ends, or they would be in the same stack, so the
+/// stacks in the range can be found without the rest of the photos.
+async fn widen(
+    db: &mut AsyncPgConnection,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+) -> Result<(NaiveDateTime, NaiveDateTime), Error> {
+    let in_range = p::photos.select(p::id).filter(p::date.between(from, to));
+    let tops = s::photo_stacks
+        .select(s::top_id)
+        .distinct()
+        .filter(not(s::manual))
+        .filter(s::photo_id.eq_any(in_range))
+        .load::<i32>(db)
+        .await?;
+    let stacked = s::photo_stacks
+        .select(s::photo_id)
+        .filter(not(s::manual))
+        .filter(s::top_id.eq_any(tops));
+    let (first, last) = p::photos
+        .select((min(p::date), max(p::date)))
+        .filter(p::id.eq_any(stacked))
+        .first::<(Option<NaiveDateTime>, Option<NaiveDateTime>)>(db)
+        .await?;
+    Ok((
+        first.map_or(from, |first| first.min(from)),
+        last.map_or(to, |last| last.max(to)),
+    ))
+}
+
+async fn top_of(
+    db: &mut AsyncPgConnection,
+    photo_id: i32,
+) -> Result<Option<i32>, Error> {
+    s::photo_stacks
+        .find(photo_id)
+        .select(s::top_id)
+        .first(db)
+        .await
+        .optional()
+}
+
+/// Group photos that are taken close in time and look similar.
+///
+/// The photos are `(id, timestamp, hash)`, ordered by time.  Each
+/// photo in a stack is compared to the one before it, so a slow pan
+/// may be one stack even if the first and last photos differ.
+fn find_stacks(photos: &[(i32, i64, i64)]) -> Vec<Vec<i32>> {
+    let mut stacks = Vec::new();
+    let mut current = Vec::<i32>::new();
+    for (i, (id, time, hash)) in photos.iter().enumerate() {
+        let similar = i.checked_sub(1).map(|i| photos[i]).is_some_and(
+            |(_, prev_time, prev_hash)| {
+                time - prev_time <= MAX_SECONDS
+                    && distance(*hash, prev_hash) <= MAX_DISTANCE
+            },
+        );
+        if !similar {
+            if current.len() > 1 {
+                stacks.push(std::mem::take(&mut current));
+            } else {
+                current.clear();
+            }
+        }
+        current.push(*id);
+    }
+    if current.len() > 1 {
+        stacks.push(current);
+    }
+    stacks
+}
+
+#[test]
+fn stack_bursts() {
+    let photos = [
+        (1, 100, 0x0f0f_0f0f),
+        (2, 101, 0x0f0f_0f0e),
+        (3, 104, 0x0f0f_0f00),
+        // Too long after the one before.
+        (4, 110, 0x0f0f_0f00),
+        // Not similar to the one before.
+        (5, 111, 0x7777_7777),
+        (6, 111, 0x7777_7777),
+    ];
+    assert_eq!(find_stacks(&photos), [vec![1, 2, 3], vec![5, 6]]);
+}
@@ -0,0 +1,392 @@
+//! Read timestamped tracks from GPX, GeoJSON and KML files.
+use chrono::{DateTime, TimeDelta, Utc};
+use roxmltree::{Document, Node};
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+
+const NS_KML: &str = "http://www.opengis.net/kml/2.2";
+const NS_GX: &str = "http://www.google.com/kml/ext/2.2";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// Track points from any number of files, sorted by time.
+#[derive(Debug, Default)]
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+impl Track {
+    /// Read a track file, deciding the format from the extension.
+    pub fn read_file(&mut self, path: &Path) -> Result<(), Error> {
+        let data = std::fs::read_to_string(path).map_err(Error::Io)?;
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let points = match ext.as_deref() {
+            Some("gpx") => parse_gpx(&data)?,
+            Some("kml") => parse_kml(&data)?,
+            Some("json" | "geojson") => parse_geojson(&data)?,
+            _ => return Err(Error::UnknownFormat),
+        };
+        self.add(points);
+        Ok(())
+    }
+
+    pub fn add(&mut self, points: Vec<TrackPoint>) {
+        self.points.extend(points);
+        self.points.sort_by_key(|p| p.time);
+    }
+
+    pub fn n_points(&self) -> usize {
+        self.points.len()
+    }
+
+    /// The first and last time in the track.
+    pub fn span(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        Some((self.points.first()?.time, self.points.last()?.time))
+    }
+
+    /// Find the position at `time`.
+    ///
+    /// If `time` is between two points less than `max_gap` apart,
+    /// the position is interpolated between them.  Otherwise, the
+    /// nearest point is used if it is within `max_gap` from `time`.
+    pub fn position_at(
+        &self,
+        time: DateTime<Utc>,
+        max_gap: TimeDelta,
+    ) -> Option<(f64, f64)> {
+        let i = self.points.partition_point(|p| p.time < time);
+        let after = self.points.get(i);
+        let before = i.checked_sub(1).and_then(|i| self.points.get(i));
+        match (before, after) {
+            (_, Some(a)) if a.time == time => Some((a.lat, a.lng)),
+            (Some(b), Some(a)) if a.time - b.time <= max_gap => {
+                let f = (time - b.time).num_milliseconds() as f64
+                    / (a.time - b.time).num_milliseconds() as f64;
+                Some((
+                    b.lat + f * (a.lat - b.lat),
+                    b.lng + f * (a.lng - b.lng),
+                ))
+            }
+            (b, a) => [b, a]
+                .into_iter()
+                .flatten()
+                .map(|p| ((p.time - time).abs(), p))
+                .filter(|(d, _)| *d <= max_gap)
+                .min_by_key(|(d, _)| *d)
+                .map(|(_, p)| (p.lat, p.lng)),
+        }
+    }
+}
+
+fn parse_gpx(data: &str) -> Result<Vec<TrackPoint>, Error> {
+    let doc = Document::parse(data)?;
+    let mut result = Vec::new();
+    for node in doc.descendants().filter(|n| {
+        n.is_element()
+            && matches!(n.tag_name().name(), "trkpt" | "rtept" | "wpt")
+    }) {
+        let Some(time) = child_text(node, "time") else {
+            continue; // Not usable without a time
+        };
+        result.push(TrackPoint {
+            time: parse_time(time)?,
+            lat: coord_attr(node, "lat")?,
+            lng: coord_attr(node, "lon")?,
+        });
+    }
+    Ok(result)
+}
+
+fn parse_kml(data: &str) -> Result<Vec<TrackPoint>, Error> {
+    let doc = Document::parse(data)?;
+    let mut result = Vec::new();
+    for node in doc.descendants().filter(|n| n.is_element()) {
+        if node.has_tag_name((NS_GX, "Track")) {
+            // A gx:Track has a list of when elements and a matching
+            // list of gx:coord elements.
+            let whens = node.children().filter(|n| is_kml(n, "when"));
+            let coords =
+                node.children().filter(|n| n.has_tag_name((NS_GX, "coord")));
+            for (when, coord) in whens.zip(coords) {
+                let (lng, lat) = kml_coord(coord.text().unwrap_or(""), ' ')?;
+                result.push(TrackPoint {
+                    time: parse_time(when.text().unwrap_or(""))?,
+                    lat,
+                    lng,
+                });
+            }
+        } else if is_kml(&node, "Placemark") {
+            // A timestamped point.
+            let when = node
+                .descendants()
+                .find(|n| is_kml(n, "TimeStamp"))
+                .and_then(|n| n.children().find(|n| is_kml(n, "when")))
+                .and_then(|n| n.text());
+            let coord = node
+                .descendants()
+                .find(|n| is_kml(n, "Point"))
+                .and_then(|n| n.children().find(|n| is_kml(n, "coordinates")))
+                .and_then(|n| n.text());
+            if let (Some(when), Some(coord)) = (when, coord) {
+                let (lng, lat) = kml_coord(coord, ',')?;
+                result.push(TrackPoint {
+                    time: parse_time(when)?,
+                    lat,
+                    lng,
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn is_kml(node: &Node, name: &str) -> bool {
+    let tag = node.tag_name();
+    tag.name() == name
+        && tag.namespace().is_none_or(|ns| ns == NS_KML || ns == NS_GX)
+}
+
+/// Parse a kml coordinate; longitude, latitude and optional altitude.
+fn kml_coord(s: &str, sep: char) -> Result<(f64, f64), Error> {
+    let mut parts = s.trim().split(sep).filter(|s| !s.is_empty());
+    let mut next = || {
+        parts
+            .next()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or(Error::BadCoordinate)
+    };
+    Ok((next()?, next()?))
+}
+
+/// Parse GeoJSON with timestamped points.
+///
+/// Supports Point features with a `time` (or `timestamp`) property,
+/// and LineString / MultiLineString features with a `coordTimes` or
+/// `times` property (as written by e.g. togeojson).
+fn parse_geojson(data: &str) -> Result<Vec<TrackPoint>, Error> {
+    let json: Value = serde_json::from_str(data)?;
+    let features = match json["type"].as_str() {
+        Some("FeatureCollection") => {
+            json["features"].as_array().cloned().unwrap_or_default()
+        }
+        Some("Feature") => vec![json],
+        _ => return Err(Error::UnknownFormat),
+    };
+    let mut result = Vec::new();
+    for feature in &features {
+        let props = &feature["properties"];
+        let geometry = &feature["geometry"];
+        let coords = &geometry["coordinates"];
+        match geometry["type"].as_str() {
+            Some("Point") => {
+                let time =
+                    props["time"].as_str().or(props["timestamp"].as_str());
+                if let Some(time) = time {
+                    result.push(json_point(coords, time)?);
+                }
+            }
+            Some("LineString") => {
+                let times = props["coordTimes"]
+                    .as_array()
+                    .or(props["times"].as_array());
+                if let (Some(coords), Some(times)) = (coords.as_array(), times)
+                {
+                    json_line(coords, times, &mut result)?;
+                }
+            }
+            Some("MultiLineString") => {
+                let times = props["coordTimes"]
+                    .as_array()
+                    .or(props["times"].as_array());
+                if let (Some(lines), Some(times)) = (coords.as_array(), times)
+                {
+                    for (coords, times) in lines.iter().zip(times) {
+                        if let (Some(coords), Some(times)) =
+                            (coords.as_array(), times.as_array())
+                        {
+                            json_line(coords, times, &mut result)?;
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(result)
+}
+
+fn json_line(
+    coords: &[Value],
+    times: &[Value],
+    result: &mut Vec<TrackPoint>,
+) -> Result<(), Error> {
+    for (coord, time) in coords.iter().zip(times) {
+        if let Some(time) = time.as_str() {
+            result.push(json_point(coord, time)?);
+        }
+    }
+    Ok(())
+}
+
+fn json_point(coord: &Value, time: &str) -> Result<TrackPoint, Error> {
+    Ok(TrackPoint {
+        time: parse_time(time)?,
+        lat: coord[1].as_f64().ok_or(Error::BadCoordinate)?,
+        lng: coord[0].as_f64().ok_or(Error::BadCoordinate)?,
+    })
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|n| n.tag_name().name() == name)
+        .and_then(|n| n.text())
+}
+
+fn coord_attr(node: Node, name: &str) -> Result<f64, Error> {
+    node.attribute(name)
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or(Error::BadCoordinate)
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, Error> {
+    DateTime::parse_from_rfc3339(s.trim())
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|_| Error::BadTime(s.into()))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Xml(roxmltree::Error),
+    Json(serde_json::Error),
+    UnknownFormat,
+    BadCoordinate,
+    BadTime(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(out, "{e}"),
+            Error::Xml(e) => write!(out, "Bad xml: {e}"),
+            Error::Json(e) => write!(out, "Bad json: {e}"),
+            Error::UnknownFormat => out.write_str("Unknown track format"),
+            Error::BadCoordinate => out.write_str("Bad coordinate"),
+            Error::BadTime(t) => write!(out, "Bad time {t:?}"),
+        }
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Self {
+        Error::Xml(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> DateTime<Utc> {
+        parse_time(s).unwrap()
+    }
+
+    #[test]
+    fn gpx_points() {
+        let points = parse_gpx(
+            r#"<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+ <trk><trkseg>
+  <trkpt lat="59.3" lon="18.0"><ele>12</ele><time>2019-05-03T12:00:00Z</time></trkpt>
+  <trkpt lat="59.4" lon="18.2"><time>2019-05-03T14:10:00+02:00</time></trkpt>
+  <trkpt lat="59.5" lon="18.4"></trkpt>
+ </trkseg></trk>
+</gpx>"#,
+        )
+        .unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].time, t("2019-05-03T12:10:00Z"));
+        assert_eq!((points[1].lat, points[1].lng), (59.4, 18.2));
+    }
+
+    #[test]
+    fn kml_track() {
+        let points = parse_kml(
+            r#"<kml xmlns="http://www.opengis.net/kml/2.2"
+ xmlns:gx="http://www.google.com/kml/ext/2.2"><Document><Placemark>
+ <gx:Track>
+  <when>2019-05-03T12:00:00Z</when><when>2019-05-03T12:01:00Z</when>
+  <gx:coord>18.0 59.3 12</gx:coord><gx:coord>18.1 59.31 14</gx:coord>
+ </gx:Track></Placemark>
+ <Placemark><TimeStamp><when>2019-05-03T13:00:00Z</when></TimeStamp>
+  <Point><coordinates>18.2,59.32,0</coordinates></Point></Placemark>
+</Document></kml>"#,
+        )
+        .unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!((points[1].lat, points[1].lng), (59.31, 18.1));
+        assert_eq!(points[2].time, t("2019-05-03T13:00:00Z"));
+    }
+
+    #[test]
+    fn geojson_line() {
+        let points = parse_geojson(
+            r#"{"type":"FeatureCollection","features":[{"type":"Feature",
+ "properties":{"coordTimes":["2019-05-03T12:00:00Z","2019-05-03T12:01:00Z"]},
+ "geometry":{"type":"LineString","coordinates":[[18.0,59.3],[18.1,59.31]]}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!((points[1].lat, points[1].lng), (59.31, 18.1));
+    }
+
+    #[test]
+    fn interpolate() {
+        let mut track = Track::default();
+        track.add(vec![
+            TrackPoint {
+                time: t("2019-05-03T12:00:00Z"),
+                lat: 59.0,
+                lng: 18.0,
+            },
+            TrackPoint {
+                time: t("2019-05-03T12:10:00Z"),
+                lat: 60.0,
+                lng: 19.0,
+            },
+        ]);
+        let gap = TimeDelta::minutes(10);
+        assert_eq!(
+            track.position_at(t("2019-05-03T12:05:00Z"), gap),
+            Some((59.5, 18.5)),
+        );
+        assert_eq!(
+            track.position_at(t("2019-05-03T12:15:00Z"), gap),
+            Some((60.0, 19.0)),
+        );
+        assert_eq!(track.position_at(t("2019-05-03T12:25:00Z"), gap), None);
+        let small_gap = TimeDelta::minutes(3);
+        assert_eq!(
+            track.position_at(t("2019-05-03T12:02:00Z"), small_gap),
+            Some((59.0, 18.0)),
+        );
+        assert_eq!(
+            track.position_at(t("2019-05-03T12:05:00Z"), small_gap),
+            None,
+        );
+    }
+}
@@ -0,0 +1,584 @@
+//! Read and write keywords, rating, people and caption as XMP.
+//!
+//! The data is read from a sidecar file (`name.ext.xmp` or
+//! `name.xmp`) if one exists, and from XMP and IPTC-IIM data
+//! embedded in jpeg files.  Data from the sidecar has precedence.
+//!
+//! Sidecars written by rphotos are named `name.ext.xmp`, either next
+//! to the photo or in a separate directory mirroring the photos.
+use crate::adm::result::Error;
+use crate::models::{Photo, PhotoDetails};
+use crate::templates;
+use diesel_async::AsyncPgConnection;
+use roxmltree::{Document, Node};
+use std::fs::{File, create_dir_all};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+const NS_RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_DC: &str = "http://purl.org/dc/elements/1.1/";
+const NS_XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const NS_LR: &str = "http://ns.adobe.com/lightroom/1.0/";
+const NS_DIGIKAM: &str = "http://www.digikam.org/ns/1.0/";
+const NS_MWG_RS: &str = "http://www.metadataworkinggroup.com/schemas/regions/";
+const NS_IPTC_EXT: &str = "http://iptc.org/std/Iptc4xmpExt/2008-02-29/";
+const NS_MPRI: &str = "http://ns.microsoft.com/photo/1.2/t/Region#";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct XmpData {
+    pub keywords: Vec<String>,
+    pub people: Vec<String>,
+    /// Star rating, -1 (rejected) to 5.  Zero means unrated.
+    pub rating: Option<i8>,
+    pub description: Option<String>,
+}
+
+impl XmpData {
+    /// Read all xmp / iptc data available for the image at `path`.
+    pub fn read_for(path: &Path) -> Result<Self, Error> {
+        let mut result = Self::default();
+        for sidecar in sidecars(path) {
+            if sidecar.is_file() {
+                debug!("Reading sidecar {sidecar:?}");
+                let xml = std::fs::read_to_string(&sidecar)
+                    .map_err(|e| Error::in_file(&e, &sidecar))?;
+                let data = Self::parse(&xml)
+                    .map_err(|e| Error::in_file(&e, &sidecar))?;
+                result.fill_from(data);
+                break;
+            }
+        }
+        if is_jpeg(path) {
+            let JpegMeta { xmp, iptc } =
+                read_jpeg_meta(path).map_err(|e| Error::in_file(&e, path))?;
+            if let Some(xmp) = xmp {
+                let xml = String::from_utf8_lossy(&xmp);
+                let data = Self::parse(xml.trim_end_matches('\0'))
+                    .map_err(|e| Error::in_file(&e, path))?;
+                result.fill_from(data);
+            }
+            if let Some(iptc) = iptc {
+                result.fill_from(parse_iptc(&iptc));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Parse an xmp packet or sidecar file.
+    pub fn parse(xml: &str) -> Result<Self, roxmltree::Error> {
+        let doc = Document::parse(xml)?;
+        let mut result = Self::default();
+        let mut subjects = Vec::new();
+        for node in doc.descendants().filter(Node::is_element) {
+            let tag = node.tag_name();
+            match (tag.namespace(), tag.name()) {
+                (Some(NS_DC), "subject") => subjects.extend(list(node)),
+                (Some(NS_DC), "description") => {
+                    result.description = alt_text(node);
+                }
+                (Some(NS_LR), "hierarchicalSubject") => {
+                    result.add_people(list(node), '|');
+                }
+                (Some(NS_DIGIKAM), "TagsList") => {
+                    result.add_people(list(node), '/');
+                }
+                (Some(NS_IPTC_EXT), "PersonInImage") => {
+                    result.add_people(list(node), '\0');
+                }
+                _ => (),
+            }
+            if let Some(rating) = prop(node, NS_XMP, "Rating") {
+                result.rating = rating
+                    .parse::<f32>()
+                    .ok()
+                    .map(|r| r.round().clamp(-1., 5.) as i8);
+            }
+            if let Some(desc) = node.attribute((NS_DC, "description")) {
+                result.description = non_empty(desc);
+            }
+            if let Some(name) = prop(node, NS_MWG_RS, "Name") {
+                let kind = prop(node, NS_MWG_RS, "Type");
+                if kind.is_none_or(|k| k == "Face") {
+                    result.add_people([name.to_string()], '\0');
+                }
+            }
+            if let Some(name) = prop(node, NS_MPRI, "PersonDisplayName") {
+                result.add_people([name.to_string()], '\0');
+            }
+        }
+        result.keywords = subjects
+            .into_iter()
+            .filter(|s| !result.people.contains(s))
+            .fold(Vec::new(), |mut v, s| {
+                if !v.contains(&s) {
+                    v.push(s);
+                }
+                v
+            });
+        Ok(result)
+    }
+
+    /// The rating converted to a grade (0 to 100) as used in rphotos.
+    pub fn grade(&self) -> Option<i16> {
+        match self.rating? {
+            0 => None,
+            r if r < 0 => Some(0),
+            r => Some(i16::from(r) * 20),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Set any data missing here from `other`.
+    fn fill_from(&mut self, other: Self) {
+        if self.keywords.is_empty() {
+            self.keywords = other.keywords;
+        }
+        if self.people.is_empty() {
+            self.people = other.people;
+        }
+        if self.rating.is_none() {
+            self.rating = other.rating;
+        }
+        if self.description.is_none() {
+            self.description = other.description;
+        }
+    }
+
+    /// Add people by name.
+    ///
+    /// For hierarchical tags (when `sep` is not nul), only tags below
+    /// "People" are people, and the last part of the tag is the name.
+    fn add_people<I>(&mut self, names: I, sep: char)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        for name in names {
+            let name = if sep == '\0' {
+                name
+            } else {
+                match name.split_once(sep) {
+                    Some((top, rest))
+                        if top.eq_ignore_ascii_case("people") =>
+                    {
+                        rest.rsplit(sep).next().unwrap_or(rest).to_string()
+                    }
+                    _ => continue,
+                }
+            };
+            if let Some(name) = non_empty(&name)
+                && !self.people.contains(&name)
+            {
+                self.people.push(name);
+            }
+        }
+    }
+}
+
+/// Command line options for writing xmp sidecars.
+#[derive(clap::Parser)]
+pub struct XmpOpt {
+    /// Directory for written xmp sidecars, mirroring the photo
+    /// directory.  If not given, sidecars are written next to the
+    /// photos.
+    #[clap(long, env = "RPHOTOS_XMP_DIR")]
+    xmp_dir: Option<PathBuf>,
+    /// Overwrite existing sidecars that was not written by rphotos.
+    #[clap(long)]
+    force_xmp: bool,
+}
+
+impl XmpOpt {
+    pub fn writer(&self, photos_dir: &Path) -> SidecarWriter {
+        SidecarWriter {
+            dir: self.xmp_dir.as_deref().unwrap_or(photos_dir).into(),
+            force: self.force_xmp,
+        }
+    }
+}
+
+/// Command line options for commands that may write xmp sidecars
+/// for the photos they change.
+#[derive(clap::Parser)]
+pub struct WriteXmpOpt {
+    /// Write xmp sidecars for the photos that are changed.
+    #[clap(long)]
+    write_xmp: bool,
+    /// Path to the root directory storing all actual photos, needed
+    /// for --write-xmp unless --xmp-dir is given.
+    #[clap(long, env = "RPHOTOS_DIR")]
+    photos_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    xmp: XmpOpt,
+}
+
+impl WriteXmpOpt {
+    /// The writer to use, if sidecars should be written.
+    pub fn writer(&self) -> Result<Option<SidecarWriter>, Error> {
+        if !self.write_xmp {
+            return Ok(None);
+        }
+        let dir = self
+            .xmp
+            .xmp_dir
+            .as_deref()
+            .or(self.photos_dir.as_deref())
+            .ok_or_else(|| {
+            Error::Other("--write-xmp needs --photos-dir or --xmp-dir".into())
+        })?;
+        Ok(Some(self.xmp.writer(dir)))
+    }
+}
+
+/// Writes the metadata for photos as xmp sidecars.
+#[derive(Clone)]
+pub struct SidecarWriter {
+    dir: PathBuf,
+    force: bool,
+}
+
+impl SidecarWriter {
+    pub fn path_for(&self, photo: &Photo) -> PathBuf {
+        self.dir.join(format!("{}.xmp", photo.path))
+    }
+
+    /// Write the sidecar for a photo after it is changed.
+    ///
+    /// Failures are logged, but not returned, since the change
+    /// itself is already done.
+    pub async fn update(&self, db: &mut AsyncPgConnection, photo_id: i32) {
+        let written = match PhotoDetails::load(photo_id, db).await {
+            Ok(photo) => self.write(&photo).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match written {
+            Ok(true) => debug!("Wrote xmp for #{}", photo_id),
+            Ok(false) => (),
+            Err(e) => warn!("Failed to write xmp for #{}: {}", photo_id, e),
+        }
+    }
+
+    /// Write the sidecar for a photo.
+    ///
+    /// Returns true if the sidecar was written, false if it was
+    /// unchanged or written by another tool.
+    pub fn write(&self, photo: &PhotoDetails) -> Result<bool, Error> {
+        let path = self.path_for(photo);
+        let mut data = Vec::new();
+        sidecar_xml(&mut data, photo)
+            .map_err(|e| Error::in_file(&e, &path))?;
+        match std::fs::read(&path) {
+            Ok(old) if old == data => return Ok(false),
+            Ok(old) if !self.force && !is_own_sidecar(&old) => {
+                warn!("Not overwriting foreign sidecar {path:?}");
+                return Ok(false);
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::in_file(&e, &path)),
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).map_err(|e| Error::in_file(&e, parent))?;
+        }
+        std::fs::write(&path, data).map_err(|e| Error::in_file(&e, &path))?;
+        Ok(true)
+    }
+}
+
+fn sidecar_xml(out: &mut Vec<u8>, photo: &PhotoDetails) -> io::Result<()> {
+    let rating = photo.grade.map(|g| match g {
+        0 => -1,
+        g => (g.clamp(1, 100) as f32 / 20.).round().max(1.) as i8,
+    });
+    let gps = photo
+        .pos
+        .as_ref()
+        .map(|pos| (gps_coord(pos.x, 'N', 'S'), gps_coord(pos.y, 'E', 'W')));
+    // Places are loaded most specific first.
+    let places = (!photo.places.is_empty()).then(|| {
+        let names = photo.places.iter().rev().map(|p| &*p.place_name);
+        names.collect::<Vec<_>>().join("|")
+    });
+    templates::xmp_sidecar_xml(out, photo, rating, gps, places)
+}
+
+/// Format a coordinate as an xmp GPSCoordinate, "DDD,MM.mmmmk".
+fn gps_coord(value: f64, pos: char, neg: char) -> String {
+    let dir = if value < 0. { neg } else { pos };
+    let value = value.abs();
+    let deg = value.trunc();
+    format!("{},{:.6}{}", deg, (value - deg) * 60., dir)
+}
+
+/// Check if a sidecar was written by rphotos.
+fn is_own_sidecar(data: &[u8]) -> bool {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|xml| Document::parse(xml).ok())
+        .is_some_and(|doc| {
+            doc.descendants()
+                .any(|n| prop(n, NS_XMP, "CreatorTool") == Some("rphotos"))
+        })
+}
+
+/// Possible sidecar files for an image, in order of precedence.
+pub fn sidecars(path: &Path) -> [PathBuf; 2] {
+    let mut full = path.as_os_str().to_owned();
+    full.push(".xmp");
+    [full.into(), path.with_extension("xmp")]
+}
+
+fn is_jpeg(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| {
+        e.eq_ignore_ascii_case("jpg") || e.eq_ignore_ascii_case("jpeg")
+    })
+}
+
+/// A property value, given either as an attribute or a child element.
+fn prop<'a>(node: Node<'a, '_>, ns: &str, name: &str) -> Option<&'a str> {
+    node.attribute((ns, name)).or_else(|| {
+        node.children()
+            .find(|c| c.has_tag_name((ns, name)))
+            .and_then(|c| c.text())
+            .map(str::trim)
+    })
+}
+
+/// The items of a rdf:Bag or rdf:Seq property.
+fn list(node: Node) -> Vec<String> {
+    node.descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "li")))
+        .filter_map(|n| non_empty(n.text()?))
+        .collect()
+}
+
+/// The default (or first) value of a rdf:Alt property.
+fn alt_text(node: Node) -> Option<String> {
+    let items = node
+        .descendants()
+        .filter(|n| n.has_tag_name((NS_RDF, "li")))
+        .collect::<Vec<_>>();
+    items
+        .iter()
+        .find(|n| {
+            n.attribute(("http://www.w3.org/XML/1998/namespace", "lang"))
+                == Some("x-default")
+        })
+        .or(items.first())
+        .and_then(|n| non_empty(n.text()?))
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+/// Raw metadata blocks embedded in a jpeg file.
+#[derive(Default)]
+struct JpegMeta {
+    xmp: Option<Vec<u8>>,
+    iptc: Option<Vec<u8>>,
+}
+
+/// Get the xmp packet and the iptc data from a jpeg file.
+///
+/// Only the headers are read; reading stops at the start of scan.
+fn read_jpeg_meta(path: &Path) -> io::Result<JpegMeta> {
+    const XMP_ID: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    const PS_ID: &[u8] = b"Photoshop 3.0\0";
+    let mut f = BufReader::new(File::open(path)?);
+    let mut buf = [0; 2];
+    f.read_exact(&mut buf)?;
+    if buf != [0xFF, 0xD8] {
+        return Ok(JpegMeta::default());
+    }
+    let mut result = JpegMeta::default();
+    loop {
+        f.read_exact(&mut buf)?;
+        if buf[0] != 0xFF {
+            break;
+        }
+        let marker = buf[1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        f.read_exact(&mut buf)?;
+        let len = usize::from(u16::from_be_bytes(buf)).saturating_sub(2);
+        let mut data = vec![0; len];
+        f.read_exact(&mut data)?;
+        if marker == 0xE1 && data.starts_with(XMP_ID) {
+            result.xmp = Some(data[XMP_ID.len()..].to_vec());
+        } else if marker == 0xED && data.starts_with(PS_ID) {
+            result.iptc = photoshop_iptc(&data[PS_ID.len()..]);
+        }
+    }
+    Ok(result)
+}
+
+/// Find the IPTC-IIM block among photoshop image resources.
+fn photoshop_iptc(mut data: &[u8]) -> Option<Vec<u8>> {
+    while data.len() > 12 && data.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        // Pascal string name, padded to even length.
+        let name_len = usize::from(data[6]);
+        let pos = 6 + ((name_len + 2) & !1);
+        let size = data.get(pos..pos + 4)?;
+        let size = u32::from_be_bytes(size.try_into().ok()?) as usize;
+        let start = pos + 4;
+        let block = data.get(start..start + size)?;
+        if id == 0x0404 {
+            return Some(block.to_vec());
+        }
+        data = data.get(start + ((size + 1) & !1)..)?;
+    }
+    None
+}
+
+/// Parse keywords (2:25) and caption (2:120) from IPTC-IIM data.
+fn parse_iptc(mut data: &[u8]) -> XmpData {
+    let mut result = XmpData::default();
+    while data.len() >= 5 && data[0] == 0x1C {
+        let (record, dataset) = (data[1], data[2]);
+        let len = usize::from(u16::from_be_bytes([data[3], data[4]]));
+        if len & 0x8000 != 0 {
+            break; // Extended datasets are not used for text.
+        }
+        let Some(value) = data.get(5..5 + len) else {
+            break;
+        };
+        let value = match std::str::from_utf8(value) {
+            Ok(s) => s.to_string(),
+            Err(_) => value.iter().map(|b| char::from(*b)).collect(),
+        };
+        match (record, dataset) {
+            (2, 25) => {
+                if let Some(kw) = non_empty(&value)
+                    && !result.keywords.contains(&kw)
+                {
+                    result.keywords.push(kw);
+                }
+            }
+            (2, 120) => result.description = non_empty(&value),
+            _ => (),
+        }
+        data = &data[5 + len..];
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lightroom_sidecar() {
+        let xmp = XmpData::parse(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmp:Rating="4">
+   <dc:description><rdf:Alt>
+    <rdf:li xml:lang="x-default">Midsummer at the lake</rdf:li>
+   </rdf:Alt></dc:description>
+   <dc:subject><rdf:Bag>
+    <rdf:li>lake</rdf:li><rdf:li>Anna</rdf:li><rdf:li>summer</rdf:li>
+   </rdf:Bag></dc:subject>
+   <lr:hierarchicalSubject><rdf:Bag>
+    <rdf:li>People|Family|Anna</rdf:li><rdf:li>Places|lake</rdf:li>
+   </rdf:Bag></lr:hierarchicalSubject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            xmp,
+            XmpData {
+                keywords: vec!["lake".into(), "summer".into()],
+                people: vec!["Anna".into()],
+                rating: Some(4),
+                description: Some("Midsummer at the lake".into()),
+            }
+        );
+        assert_eq!(xmp.grade(), Some(80));
+    }
+
+    #[test]
+    fn mwg_regions() {
+        let xmp = XmpData::parse(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:mwg-rs="http://www.metadataworkinggroup.com/schemas/regions/">
+   <xmp:Rating>-1</xmp:Rating>
+   <mwg-rs:Regions rdf:parseType="Resource">
+    <mwg-rs:RegionList><rdf:Bag>
+     <rdf:li><rdf:Description mwg-rs:Name="Bertil" mwg-rs:Type="Face"/>
+     </rdf:li>
+     <rdf:li><rdf:Description mwg-rs:Name="Dog" mwg-rs:Type="Pet"/>
+     </rdf:li>
+     <rdf:li rdf:parseType="Resource"><mwg-rs:Name>Cecilia</mwg-rs:Name>
+     </rdf:li>
+    </rdf:Bag></mwg-rs:RegionList>
+   </mwg-rs:Regions>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+        )
+        .unwrap();
+        assert_eq!(xmp.people, ["Bertil", "Cecilia"]);
+        assert_eq!(xmp.grade(), Some(0));
+    }
+
+    #[test]
+    fn iptc_keywords_and_caption() {
+        let mut data = Vec::new();
+        for (ds, value) in [(25, "lake"), (120, "A caption"), (25, "sun")] {
+            data.extend([0x1C, 2, ds, 0, value.len() as u8]);
+            data.extend(value.as_bytes());
+        }
+        let iptc = parse_iptc(&data);
+        assert_eq!(iptc.keywords, ["lake", "sun"]);
+        assert_eq!(iptc.description.as_deref(), Some("A caption"));
+    }
+
+    #[test]
+    fn gps_coords() {
+        assert_eq!(gps_coord(59.3085, 'N', 'S'), "59,18.510000N");
+        assert_eq!(gps_coord(-0.5, 'E', 'W'), "0,30.000000W");
+    }
+
+    #[test]
+    fn own_sidecar() {
+        assert!(is_own_sidecar(
+            br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF
+ xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description
+ xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreatorTool="rphotos"/>
+</rdf:RDF></x:xmpmeta>"#
+        ));
+        assert!(!is_own_sidecar(
+            br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF
+ xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description
+ xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreatorTool="digiKam"/>
+</rdf:RDF></x:xmpmeta>"#
+        ));
+    }
+
+    #[test]
+    fn sidecar_names() {
+        assert_eq!(
+            sidecars(Path::new("2019/IMG_17.CR2")),
+            [
+                PathBuf::from("2019/IMG_17.CR2.xmp"),
+                PathBuf::from("2019/IMG_17.xmp")
+            ],
+        );
+    }
+}